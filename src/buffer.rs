@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Fixed-capacity byte buffer
+
+use core::ops::{Deref, DerefMut};
+
+/// Byte vector backed by an initialized array, which can be resized without
+/// `unsafe` up to its capacity
+#[derive(Clone, Debug)]
+pub(crate) struct ArrayBuf<const N: usize> {
+    data: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> ArrayBuf<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            data: [0; N],
+            len: 0,
+        }
+    }
+
+    pub(crate) const fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Sets the length, which exposes the stale contents of the backing array
+    /// when growing
+    ///
+    /// # Panics
+    ///
+    /// When `len` exceeds the capacity
+    pub(crate) fn set_len(&mut self, len: usize) {
+        assert!(len <= N, "length exceeds capacity");
+        self.len = len;
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// # Panics
+    ///
+    /// When the buffer is full
+    pub(crate) fn push(&mut self, byte: u8) {
+        self.data[self.len] = byte;
+        self.len += 1;
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<u8> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.data[self.len])
+    }
+
+    /// # Panics
+    ///
+    /// When `bytes` does not fit the remaining capacity
+    pub(crate) fn extend_from_slice(&mut self, bytes: &[u8]) {
+        let end = self.len + bytes.len();
+        self.data[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<const N: usize> Default for ArrayBuf<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for ArrayBuf<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl<const N: usize> DerefMut for ArrayBuf<N> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+impl<const N: usize> PartialEq for ArrayBuf<N> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<const N: usize> PartialEq<&[u8]> for ArrayBuf<N> {
+    fn eq(&self, other: &&[u8]) -> bool {
+        **self == **other
+    }
+}
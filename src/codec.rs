@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Compression and encryption hooks
+
+use crate::proto::{ZTCRYPT, ZTLZW};
+use crate::{Error, Zrinit};
+
+/// Transport option of `ZFILE`, which is negotiated with the `ZRINIT`
+/// capabilities of the receiver
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    /// Compression (`ZTLZW`), negotiated with `Zrinit::CANLZW`
+    Compress,
+    /// Encryption (`ZTCRYPT`), negotiated with `Zrinit::CANCRY`
+    Encrypt,
+}
+
+impl Transport {
+    /// Returns the `ZRINIT` capability of the transport
+    pub(crate) const fn capability(self) -> Zrinit {
+        match self {
+            Transport::Compress => Zrinit::CANLZW,
+            Transport::Encrypt => Zrinit::CANCRY,
+        }
+    }
+
+    /// Returns the `ZF2` transport option
+    pub(crate) const fn option(self) -> u8 {
+        match self {
+            Transport::Compress => ZTLZW,
+            Transport::Encrypt => ZTCRYPT,
+        }
+    }
+}
+
+/// Codec, which transforms the file data of each subpacket in transit. The
+/// receiver advertises the capability of the codec in `ZRINIT`, and the sender
+/// uses its codec only when the receiver has the same capability, which is
+/// signaled to the receiver with the `ZF2` transport option of `ZFILE`.
+///
+/// The file offsets always refer to the decoded data. As the data is re-sent
+/// from an arbitrary offset after an error, each subpacket should be encoded
+/// independently of the previous ones.
+pub trait Codec {
+    /// Returns the transport option implemented by the codec
+    fn transport(&self) -> Transport;
+
+    /// Returns the maximum number of bytes the encoding adds to a subpacket.
+    /// The sender reads correspondingly less file data per subpacket.
+    fn overhead(&self) -> usize {
+        0
+    }
+
+    /// Encodes `len` bytes in the beginning of `buf` in place, and returns the
+    /// length of the encoded data
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the encoded data does not fit to `buf`
+    fn encode(&mut self, buf: &mut [u8], len: usize) -> Result<usize, Error>;
+
+    /// Decodes `len` bytes in the beginning of `buf` in place, and returns the
+    /// length of the decoded data
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the data cannot be decoded
+    fn decode(&mut self, buf: &mut [u8], len: usize) -> Result<usize, Error>;
+}
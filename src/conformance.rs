@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Conformance of the received headers
+
+use crate::{Encoding, Error, Read, XOFF, XON};
+
+/// Conformance of the received headers to the 1988 ZMODEM specification
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Conformance {
+    /// Accepts the deviations of lrzsz, the de facto reference
+    /// implementation:
+    ///
+    /// * The parity bit set in the characters of a `ZHEX` header
+    /// * XON and XOFF in the middle of a `ZHEX` header
+    /// * The parity bit set in the trailing CR and LF of a `ZHEX` header
+    /// * A number of `ZPAD` characters not matching the encoding
+    #[default]
+    Lenient,
+    /// Rejects the headers with any of the deviations accepted in the lenient
+    /// mode with `ZNAK`. The trailing CR and LF of a `ZHEX` header are
+    /// required.
+    Strict,
+}
+
+/// Reader for a header, which strips the parity bit, and skips XON and XOFF
+/// in the characters of a `ZHEX` header
+pub(crate) struct Lenient<'a, P> {
+    port: &'a mut P,
+    hex: Option<bool>,
+}
+
+impl<'a, P> Lenient<'a, P> {
+    pub(crate) fn new(port: &'a mut P) -> Self {
+        Self { port, hex: None }
+    }
+}
+
+impl<P> Read for Lenient<'_, P>
+where
+    P: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        for b in buf.iter_mut() {
+            *b = self.read_byte()?;
+        }
+        u32::try_from(buf.len()).or(Err(Error::Data))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        // The first byte is the encoding:
+        let Some(true) = self.hex else {
+            let b = self.port.read_byte()?;
+            self.hex.get_or_insert(b == Encoding::ZHEX as u8);
+            return Ok(b);
+        };
+        loop {
+            match self.port.read_byte()? & 0x7f {
+                XON | XOFF => (),
+                b => return Ok(b),
+            }
+        }
+    }
+}
+
+/// Reader, which strips the parity bit from every byte for a 7-bit link
+pub(crate) struct Parity<'a, P> {
+    port: &'a mut P,
+}
+
+impl<'a, P> Parity<'a, P> {
+    pub(crate) fn new(port: &'a mut P) -> Self {
+        Self { port }
+    }
+}
+
+impl<P> Read for Parity<'_, P>
+where
+    P: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let len = self.port.read(buf)?;
+        for b in buf.iter_mut().take(len as usize) {
+            *b &= 0x7f;
+        }
+        Ok(len)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        Ok(self.port.read_byte()? & 0x7f)
+    }
+}
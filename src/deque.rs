@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Port over a pair of byte queues
+
+use crate::{Error, Read, Write};
+use alloc::collections::VecDeque;
+
+/// Port, which reads from and writes to a pair of byte queues borrowed from
+/// the caller. This allows to drive `zmodem2::send` and `zmodem2::receive`
+/// from an event loop, which accumulates the received bytes to `rx`, and
+/// drains the output from `tx` after each step. A read from an empty `rx`
+/// fails similarly to a timed out read of a serial port, and thus the step
+/// can be repeated when more bytes have arrived.
+pub struct DequePort<'a> {
+    rx: &'a mut VecDeque<u8>,
+    tx: &'a mut VecDeque<u8>,
+}
+
+impl<'a> DequePort<'a> {
+    /// Creates a new instance
+    pub fn new(rx: &'a mut VecDeque<u8>, tx: &'a mut VecDeque<u8>) -> Self {
+        Self { rx, tx }
+    }
+}
+
+impl Read for DequePort<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let len = buf.len().min(self.rx.len());
+        for (b, byte) in buf.iter_mut().zip(self.rx.drain(..len)) {
+            *b = byte;
+        }
+        u32::try_from(len).or(Err(Error::Data))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        self.rx.pop_front().ok_or(Error::Read)
+    }
+}
+
+impl Write for DequePort<'_> {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.tx.extend(buf);
+        Ok(())
+    }
+
+    fn write_byte(&mut self, value: u8) -> Result<(), Error> {
+        self.tx.push_back(value);
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Filename policy for constrained filesystems
+
+use crate::{Error, Quota};
+use core::fmt::Write;
+use heapless::String;
+
+/// Maximum number of alternatives tried for a name, which already exists,
+/// unless bound by `Quotas::with_alternatives`
+pub(crate) const MAX_ALTERNATIVES: u32 = 99;
+
+/// Capacity of a mapped name
+const MAX_NAME_LEN: usize = 256;
+
+/// Characters allowed in a FAT short name in addition to letters and digits
+const FAT_SPECIAL: &str = "!#$%&'()-@^_`{}~";
+
+/// Policy for the names of the received files, which maps the name offered by
+/// the sender to a name valid on the local filesystem. The characters not
+/// allowed are replaced with `_`, and the directory components are removed.
+#[derive(Clone, Copy, Debug)]
+pub enum NamePolicy {
+    /// Upper-case 8.3 names for FAT without long filename support
+    Short,
+    /// Names up to `max_len` bytes with the characters accepted by `allowed`.
+    /// The length is clamped to 256 bytes.
+    Custom {
+        max_len: usize,
+        allowed: fn(char) -> bool,
+    },
+}
+
+impl NamePolicy {
+    /// Maps `name` to the policy
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the maximum length is zero
+    pub fn apply(&self, name: &str) -> Result<String<256>, Error> {
+        self.alternative(name, 0)
+    }
+
+    /// Maps `name` to the policy, and appends `~n` to the base name, when `n`
+    /// is non-zero. The base name is truncated to fit the suffix, similarly
+    /// to the short names generated by FAT.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the name cannot fit the suffix
+    pub fn alternative(&self, name: &str, n: u32) -> Result<String<256>, Error> {
+        let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+        let name = name.trim_start_matches('.');
+        let max_len = match *self {
+            Self::Short => 12,
+            Self::Custom { max_len, .. } => max_len.min(MAX_NAME_LEN),
+        };
+        // The extension is kept, unless it does not fit with the base name:
+        let (base, ext) = match (name.rsplit_once('.'), *self) {
+            (Some((_, ext)), Self::Custom { .. }) if ext.len() + 1 >= max_len => (name, ""),
+            (Some((base, ext)), _) if !base.is_empty() => (base, ext),
+            _ => (name, ""),
+        };
+        let mut suffix = String::<11>::new();
+        if n > 0 {
+            write!(suffix, "~{n}").or(Err(Error::Data))?;
+        }
+        let (base_len, ext_len) = match *self {
+            Self::Short => (8, 3),
+            Self::Custom { .. } => {
+                let dot = usize::from(!ext.is_empty());
+                (max_len.saturating_sub(ext.len() + dot), ext.len())
+            }
+        };
+        let base_len = base_len
+            .checked_sub(suffix.len())
+            .filter(|len| *len > 0)
+            .ok_or(Error::Data)?;
+        let mut out = String::new();
+        self.push(&mut out, base, base_len)?;
+        if out.is_empty() {
+            out.push('_').or(Err(Error::Data))?;
+        }
+        out.push_str(&suffix).or(Err(Error::Data))?;
+        if !ext.is_empty() {
+            out.push('.').or(Err(Error::Data))?;
+            self.push(&mut out, ext, ext_len)?;
+        }
+        Ok(out)
+    }
+
+    /// Appends the characters of `part` mapped to the policy to `out`, up to
+    /// `len` bytes
+    fn push(&self, out: &mut String<256>, part: &str, len: usize) -> Result<(), Error> {
+        let mut left = len;
+        for c in part.chars() {
+            let c = match *self {
+                Self::Short if c == ' ' || c == '.' => continue,
+                Self::Short if c.is_ascii_alphanumeric() || FAT_SPECIAL.contains(c) => {
+                    c.to_ascii_uppercase()
+                }
+                Self::Custom { allowed, .. } if allowed(c) => c,
+                _ => '_',
+            };
+            if c.len_utf8() > left {
+                break;
+            }
+            left -= c.len_utf8();
+            out.push(c).or(Err(Error::Data))?;
+        }
+        Ok(())
+    }
+
+    /// Returns `true` for the characters of the POSIX portable filename
+    /// character set, which can be used as `allowed` for `NamePolicy::Custom`
+    #[must_use]
+    pub fn portable(c: char) -> bool {
+        c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')
+    }
+}
+
+/// Maps `name` to `policy`, and picks the first alternative, which does not
+/// exist, or returns `None` if there is none. At most `quota` alternatives are
+/// tried in the hardened mode, and running out of them is an error.
+pub(crate) fn resolve(
+    policy: NamePolicy,
+    name: &str,
+    exists: &mut dyn FnMut(&str) -> bool,
+    quota: Option<u32>,
+) -> Result<Option<String<256>>, Error> {
+    for n in 0..=quota.unwrap_or(MAX_ALTERNATIVES) {
+        // A name, which cannot fit the suffix, has no more alternatives:
+        let Ok(alternative) = policy.alternative(name, n) else {
+            return Ok(None);
+        };
+        if !exists(&alternative) {
+            return Ok(Some(alternative));
+        }
+    }
+    match quota {
+        Some(_) => Err(Error::Quota(Quota::Alternatives)),
+        None => Ok(None),
+    }
+}
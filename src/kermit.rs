@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Minimal Kermit file transfer for the console ports, which do not offer
+//! ZMODEM. A single file is transferred with the basic protocol: each packet
+//! is acknowledged before the next one is sent, the packets are at most 94
+//! characters long with the type 1 block check, and the control characters
+//! are the only ones prefixed. The 8th bit is passed as it is, and thus the
+//! link must be 8-bit clean.
+
+use crate::buffer::ArrayBuf;
+use crate::{read_full, Error, Read, Write, MAX_GARBAGE};
+use heapless::String;
+
+/// Start of a packet
+const MARK: u8 = 0x01;
+/// Maximum packet length, which is the maximum of the basic protocol
+const MAXL: u8 = 94;
+/// Timeout in seconds requested from the peer
+const TIME: u8 = 5;
+/// Default end of line terminating a packet
+const EOL: u8 = b'\r';
+/// Default control prefix
+const QCTL: u8 = b'#';
+/// Number of times a packet is sent or waited for before giving up
+const MAX_RETRIES: u32 = 10;
+
+/// Packet data, which holds the longest data field
+type Data = ArrayBuf<{ MAXL as usize }>;
+
+/// Send-Init
+const SEND_INIT: u8 = b'S';
+/// File header
+const FILE: u8 = b'F';
+/// File data
+const DATA: u8 = b'D';
+/// End of file
+const EOF: u8 = b'Z';
+/// End of transaction
+const BREAK: u8 = b'B';
+/// Acknowledgement
+const ACK: u8 = b'Y';
+/// Negative acknowledgement
+const NAK: u8 = b'N';
+/// Fatal error
+const ERROR: u8 = b'E';
+
+/// Maps a number in `0..95` to a printable character
+const fn tochar(x: u8) -> u8 {
+    x + 32
+}
+
+/// Maps a printable character to a number in `0..95`
+const fn unchar(c: u8) -> u8 {
+    c.wrapping_sub(32)
+}
+
+/// Toggles a control character and its printable form
+const fn ctl(c: u8) -> u8 {
+    c ^ 64
+}
+
+/// Parameters negotiated with Send-Init and its acknowledgement
+#[derive(Clone, Copy)]
+pub(crate) struct Link {
+    /// Maximum packet length accepted by the peer
+    maxl: u8,
+    /// End of line expected by the peer
+    eol: u8,
+    /// Control prefix used by the peer
+    qctl: u8,
+}
+
+impl Link {
+    pub(crate) const fn new() -> Self {
+        Self {
+            maxl: MAXL,
+            eol: EOL,
+            qctl: QCTL,
+        }
+    }
+
+    /// Reads the parameters of the peer. The missing and the invalid ones
+    /// keep their defaults.
+    fn from_params(params: &[u8]) -> Self {
+        let mut link = Self::new();
+        if let Some(maxl) = params.first().map(|c| unchar(*c)) {
+            link.maxl = maxl.clamp(10, MAXL);
+        }
+        if let Some(eol) = params.get(4).map(|c| unchar(*c)).filter(|c| *c < 32) {
+            link.eol = eol;
+        }
+        if let Some(qctl) = params.get(5).filter(|c| c.is_ascii_graphic()) {
+            link.qctl = *qctl;
+        }
+        link
+    }
+
+    /// Returns the parameters sent by this side
+    fn params() -> Data {
+        let mut params = Data::new();
+        params.extend_from_slice(&[tochar(MAXL), tochar(TIME), tochar(0), ctl(0), tochar(EOL)]);
+        params.push(QCTL);
+        params
+    }
+
+    /// Returns the maximum length of the data field of a packet sent to the
+    /// peer
+    fn limit(self) -> usize {
+        // Excludes the sequence number, the type and the block check:
+        usize::from(self.maxl) - 3
+    }
+}
+
+/// Returns the type 1 block check of the characters from the length to the
+/// end of the data field
+fn check(bytes: &[u8]) -> u8 {
+    let sum: u32 = bytes.iter().map(|b| u32::from(*b)).sum();
+    tochar(((sum + ((sum & 0xc0) >> 6)) & 0x3f).to_le_bytes()[0])
+}
+
+pub(crate) fn write_packet<P>(
+    port: &mut P,
+    link: Link,
+    seq: u8,
+    kind: u8,
+    data: &[u8],
+) -> Result<(), Error>
+where
+    P: Write,
+{
+    let len = u8::try_from(data.len() + 3).or(Err(Error::Data))?;
+    let mut out = ArrayBuf::<{ MAXL as usize + 1 }>::new();
+    out.extend_from_slice(&[tochar(len), tochar(seq % 64), kind]);
+    out.extend_from_slice(data);
+    out.push(check(&out));
+    port.write_byte(MARK)?;
+    port.write_all(&out)?;
+    port.write_byte(link.eol)?;
+    port.flush()
+}
+
+/// Reads a packet to `data`, and returns its sequence number and type
+fn read_packet<P>(port: &mut P, data: &mut Data) -> Result<(u8, u8), Error>
+where
+    P: Read,
+{
+    for skipped in 0..=MAX_GARBAGE {
+        if port.read_byte()? == MARK {
+            break;
+        }
+        if skipped == MAX_GARBAGE {
+            return Err(Error::Data);
+        }
+    }
+    let len = port.read_byte()?;
+    if !(3..=MAXL).contains(&unchar(len)) {
+        return Err(Error::Data);
+    }
+    let mut out = ArrayBuf::<{ MAXL as usize + 1 }>::new();
+    out.set_len(usize::from(unchar(len)) + 1);
+    out[0] = len;
+    port.read_exact(&mut out[1..])?;
+    let (body, sum) = out.split_at(out.len() - 1);
+    if check(body) != sum[0] {
+        return Err(Error::Data);
+    }
+    data.clear();
+    data.extend_from_slice(&body[3..]);
+    Ok((unchar(body[1]), body[2]))
+}
+
+/// Encodes the bytes from `input` to `out` up to `limit` characters, and
+/// returns the number of bytes encoded
+fn encode(input: &[u8], out: &mut Data, limit: usize) -> usize {
+    for (i, b) in input.iter().enumerate() {
+        let c = b & 0x7f;
+        let control = c < 32 || c == 127;
+        let prefixed = control || c == QCTL;
+        if out.len() + 1 + usize::from(prefixed) > limit {
+            return i;
+        }
+        if prefixed {
+            out.push(QCTL);
+        }
+        out.push(if control { ctl(*b) } else { *b });
+    }
+    input.len()
+}
+
+/// Decodes the data field of a packet prefixed with `qctl`
+fn decode(data: &[u8], qctl: u8, out: &mut Data) -> Result<(), Error> {
+    let mut bytes = data.iter();
+    while let Some(b) = bytes.next() {
+        if *b != qctl {
+            out.push(*b);
+            continue;
+        }
+        let c = *bytes.next().ok_or(Error::Data)?;
+        out.push(if (63..=95).contains(&(c & 0x7f)) {
+            ctl(c)
+        } else {
+            c
+        });
+    }
+    Ok(())
+}
+
+/// Sends a packet until it has been acknowledged, and stores the data of the
+/// acknowledgement to `reply`
+fn exchange<P>(
+    port: &mut P,
+    link: Link,
+    seq: u8,
+    kind: u8,
+    data: &[u8],
+    reply: &mut Data,
+) -> Result<(), Error>
+where
+    P: Read + Write,
+{
+    for _ in 0..MAX_RETRIES {
+        write_packet(port, link, seq, kind, data)?;
+        match read_packet(port, reply) {
+            Ok((n, ACK)) if n == seq % 64 => return Ok(()),
+            // A NAK for the next packet acknowledges this one:
+            Ok((n, NAK)) if n == (seq + 1) % 64 => {
+                reply.clear();
+                return Ok(());
+            }
+            Ok((_, ERROR)) => return Err(Error::Data),
+            Ok(_) | Err(Error::Read | Error::Data) => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(Error::Read)
+}
+
+/// Sends `file` with the name `name` using the Kermit protocol. A read from
+/// the port is expected to time out, similarly to `zmodem2::send`.
+///
+/// # Errors
+///
+/// * `Err(Error::Read)` when the read I/O fails with the serial port, or the
+///   receiver stopped answering
+/// * `Err(Error::Write)` when the write I/O fails with the serial port
+/// * `Err(Error::Data)` when the receiver sent an error packet, or `name` does
+///   not fit in a packet of the receiver
+pub fn send<P, F>(port: &mut P, file: &mut F, name: &str) -> Result<(), Error>
+where
+    P: Read + Write,
+    F: Read,
+{
+    let mut reply = Data::new();
+    exchange(port, Link::new(), 0, SEND_INIT, &Link::params(), &mut reply)?;
+    let link = Link::from_params(&reply);
+    let mut data = Data::new();
+    if encode(name.as_bytes(), &mut data, link.limit()) != name.len() {
+        return Err(Error::Data);
+    }
+    exchange(port, link, 1, FILE, &data, &mut reply)?;
+    let mut seq = 2;
+    let mut input = Data::new();
+    loop {
+        let len = input.len();
+        input.set_len(input.capacity());
+        let count = read_full(file, &mut input[len..])?;
+        input.set_len(len + count as usize);
+        if input.is_empty() {
+            break;
+        }
+        data.clear();
+        let used = encode(&input, &mut data, link.limit());
+        input.copy_within(used.., 0);
+        input.truncate(input.len() - used);
+        exchange(port, link, seq, DATA, &data, &mut reply)?;
+        seq = (seq + 1) % 64;
+    }
+    exchange(port, link, seq, EOF, &[], &mut reply)?;
+    exchange(port, link, (seq + 1) % 64, BREAK, &[], &mut reply)
+}
+
+/// Receives a file to `file` using the Kermit protocol, and returns the name
+/// sent by the sender. A read from the port is expected to time out, which
+/// is answered with a NAK.
+///
+/// # Errors
+///
+/// * `Err(Error::Read)` when the read I/O fails with the serial port, or the
+///   sender stopped sending
+/// * `Err(Error::Write)` when the write I/O fails with the serial port or file
+/// * `Err(Error::Data)` when the sender sent an error packet, or a packet not
+///   supported
+pub fn receive<P, F>(port: &mut P, file: &mut F) -> Result<String<256>, Error>
+where
+    P: Read + Write,
+    F: Write,
+{
+    let mut link = Link::new();
+    let mut name = String::new();
+    let mut seq = 0;
+    let mut ack = Data::new();
+    let mut data = Data::new();
+    let mut retries = 0;
+    loop {
+        let (n, kind) = match read_packet(port, &mut data) {
+            Ok(packet) => packet,
+            Err(Error::Read | Error::Data) if retries < MAX_RETRIES => {
+                retries += 1;
+                write_packet(port, link, seq, NAK, &[])?;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        retries = 0;
+        // The acknowledgement of the previous packet was lost:
+        if seq > 0 && n == (seq + 63) % 64 {
+            write_packet(port, link, n, ACK, &ack)?;
+            continue;
+        }
+        if n != seq {
+            write_packet(port, link, seq, NAK, &[])?;
+            continue;
+        }
+        ack.clear();
+        let mut out = Data::new();
+        match kind {
+            SEND_INIT if seq == 0 => {
+                link = Link::from_params(&data);
+                ack = Link::params();
+            }
+            FILE => {
+                decode(&data, link.qctl, &mut out)?;
+                let out = core::str::from_utf8(&out).or(Err(Error::Data))?;
+                name.clear();
+                name.push_str(out).or(Err(Error::Data))?;
+            }
+            DATA => {
+                decode(&data, link.qctl, &mut out)?;
+                file.write_all(&out)?;
+            }
+            EOF => file.flush()?,
+            BREAK => {
+                write_packet(port, link, seq, ACK, &[])?;
+                return Ok(name);
+            }
+            _ => {
+                write_packet(port, link, seq, ERROR, b"Unsupported packet")?;
+                return Err(Error::Data);
+            }
+        }
+        write_packet(port, link, seq, ACK, &ack)?;
+        seq = (seq + 1) % 64;
+    }
+}
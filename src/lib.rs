@@ -15,16 +15,82 @@
 #![deny(clippy::all)]
 #![deny(clippy::pedantic)]
 #![cfg_attr(not(feature = "std"), no_std)]
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+mod buffer;
+mod codec;
+mod conformance;
+#[cfg(feature = "alloc")]
+mod deque;
+mod filename;
+pub mod kermit;
+#[cfg(feature = "littlefs2")]
+mod littlefs;
+mod newline;
+mod overlap;
+pub mod proto;
+mod quirks;
+mod quota;
+mod resume;
+mod rtt;
+mod runner;
+#[cfg(feature = "embedded-sdmmc")]
+mod sdmmc;
+mod session;
+mod sink;
+mod source;
 #[cfg(feature = "std")]
 mod std;
+mod summary;
+
+pub use crate::codec::{Codec, Transport};
+pub use crate::conformance::Conformance;
+#[cfg(feature = "alloc")]
+pub use crate::deque::DequePort;
+pub use crate::filename::NamePolicy;
+#[cfg(feature = "littlefs2")]
+pub use crate::littlefs::LittlefsFile;
+pub use crate::newline::Newline;
+pub use crate::overlap::{DeferredBuf, DeferredWrite, DoubleBuffer};
+pub use crate::quirks::Quirks;
+pub use crate::quota::{Quota, Quotas};
+pub use crate::resume::{ResumeRecord, ResumeStore};
+pub use crate::rtt::RttPort;
+pub use crate::runner::{run_receive, run_send, Hooks};
+#[cfg(feature = "embedded-sdmmc")]
+pub use crate::sdmmc::SdmmcFile;
+pub use crate::session::{Event, Session};
+pub use crate::sink::Sink;
+pub use crate::source::{ChunkSource, MappedSource, Source};
+#[cfg(feature = "futures")]
+pub use crate::std::Blocking;
+#[cfg(feature = "nusb")]
+pub use crate::std::UsbPort;
+#[cfg(all(unix, feature = "pty"))]
+pub use crate::std::{openpty, set_raw, PtyMaster, PtyPair};
+#[cfg(feature = "std")]
+pub use crate::std::{receive_dir, send_dir, BufferedPort, NameRules, Pipelined, TarSink};
+pub use crate::summary::{BatchProgress, FileStatus, FileSummary, LinkEvent, TransferSummary};
 
-use bitflags::bitflags;
-use core::{convert::TryFrom, str::FromStr};
+use crate::buffer::ArrayBuf;
+use crate::conformance::{Lenient, Parity};
+use crate::filename::resolve;
+use crate::newline::Converter;
+use crate::proto::{ABORT, XOFF, XON, ZCACK1, ZCNL, ZDLE, ZESC8, ZESCAPE_BIT, ZPAD};
+use crate::quirks::Tolerant;
+use crate::quota::Bounded;
+use crate::resume::update_crc;
+use crate::session::FileMark;
+use crate::source::Plain;
+use core::{
+    convert::TryFrom,
+    ops::{BitAnd, BitOr, BitOrAssign},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
 use crc::{Crc, CRC_16_XMODEM, CRC_32_ISO_HDLC};
-use heapless::String;
-use strum::IntoEnumIterator;
-use strum_macros::EnumIter;
-use tinyvec::{array_vec, ArrayVec};
+use heapless::{Deque, String};
 
 /// Size of the unescaped subpacket payload. The size was picked based on
 /// maximum subpacket size in the original 1988 ZMODEM specification.
@@ -36,6 +102,12 @@ const HEADER_SIZE: usize = 32;
 /// The number of subpackets to stream
 const SUBPACKET_PER_ACK: usize = 10;
 
+/// The maximum number of bytes skipped while looking for a header
+const MAX_GARBAGE: usize = 2048;
+
+/// Maximum number of `ZFILE` retransmissions without an answer
+const MAX_ZFILE_RETRIES: u32 = 10;
+
 /// CRC algorithm for `ZBIN` or `ZHEX` encoded transmissions.
 const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
 
@@ -82,21 +154,20 @@ const UNZDLE_TABLE: [u8; 0x100] = [
     0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff,
 ];
 
-const ZPAD: u8 = b'*';
-const ZDLE: u8 = 0x18;
-const XON: u8 = 0x11;
 const ZACK_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZACK, &[0; 4]);
-const ZDATA_HEADER: Header = Header::new(Encoding::ZBIN32, Frame::ZDATA, &[0; 4]);
-const ZEOF_HEADER: Header = Header::new(Encoding::ZBIN32, Frame::ZEOF, &[0; 4]);
+const ZCOMPL_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZCOMPL, &[0; 4]);
 const ZFIN_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZFIN, &[0; 4]);
 const ZNAK_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZNAK, &[0; 4]);
 const ZRPOS_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4]);
+const ZSKIP_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZSKIP, &[0; 4]);
+const ZRINIT_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZRINIT, &[0; 4]);
 const ZRQINIT_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZRQINIT, &[0; 4]);
 
 /// Staging and temporal storage for incoming and outgoing frames
-type Buffer = ArrayVec<[u8; BUFFER_SIZE]>;
+type Buffer = ArrayBuf<BUFFER_SIZE>;
 
-/// Error codes for `zmodem2::send` and `zmodem2::receive`
+/// Error codes for `zmodem2::send` and `zmodem2::receive`. The location of
+/// the most recent error is available from `State::last_error_context`.
 #[derive(Debug, PartialEq)]
 pub enum Error {
     /// The received data failed validation
@@ -105,6 +176,100 @@ pub enum Error {
     Read,
     /// I/O error during write
     Write,
+    /// The session was cancelled with the token set by
+    /// `State::set_cancel_token`
+    Cancelled,
+    /// The peer input exceeded a quota set by `State::set_quotas`
+    Quota(Quota),
+    /// The peer has not answered after the retries, and the session has been
+    /// finished
+    Unanswered,
+}
+
+/// Location of the most recent error returned by `zmodem2::send` or
+/// `zmodem2::receive`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorContext {
+    stage: Stage,
+    frame: Option<Frame>,
+    offset: u32,
+}
+
+impl ErrorContext {
+    /// Returns the stage of the transfer, when the error happened
+    #[must_use]
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// Returns the frame being processed, or `None` if the error happened
+    /// before a header had been received
+    #[must_use]
+    pub fn frame(&self) -> Option<Frame> {
+        self.frame
+    }
+
+    /// Returns the file offset, when the error happened
+    #[must_use]
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
+
+/// Reason for answering the peer with `ZNAK`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NakReason {
+    /// The header was malformed, or its CRC did not match
+    Header,
+    /// The subpacket after the header was malformed, or its CRC did not match
+    Subpacket,
+}
+
+/// Record of the most recent `ZNAK` sent to the peer, which tells why the
+/// peer was asked to retransmit
+#[derive(Clone, Debug, PartialEq)]
+pub struct NakContext {
+    reason: NakReason,
+    frame: Option<Frame>,
+    encoding: Option<Encoding>,
+    bytes: ArrayBuf<HEADER_SIZE>,
+    offset: u32,
+}
+
+impl NakContext {
+    /// Returns the reason for the rejection
+    #[must_use]
+    pub fn reason(&self) -> NakReason {
+        self.reason
+    }
+
+    /// Returns the type of the frame, of which subpacket was rejected, or
+    /// `None` for a rejected header
+    #[must_use]
+    pub fn frame(&self) -> Option<Frame> {
+        self.frame
+    }
+
+    /// Returns the encoding of the rejected header or subpacket, or `None`
+    /// if the encoding byte was not valid
+    #[must_use]
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+
+    /// Returns the raw bytes of a rejected header following `ZDLE`, which
+    /// are truncated to the maximum size of a header. The bytes of a rejected
+    /// subpacket are not recorded.
+    #[must_use]
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the file offset, when the rejection happened
+    #[must_use]
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
 }
 
 /// Write I/O operations
@@ -128,6 +293,20 @@ pub trait Write {
     fn write_byte(&mut self, value: u8) -> Result<(), Error> {
         self.write_all(&[value])
     }
+
+    /// Flushes buffered data to the underlying storage. When used as a file
+    /// sink, this is called after `ZEOF` has been accepted, and before the
+    /// session is finished with `ZFIN`, which makes it a well-defined point
+    /// for e.g. synchronizing the file to the disk.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Read)` when the read I/O fails with the serial port
+    /// * `Err(Error::Write)` when the write I/O fails with the serial port
+    /// * `Err(Error::Data)` when corrupted data has been detected
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 /// Read I/O operations
@@ -148,7 +327,43 @@ pub trait Read {
     /// * `Err(Error::Read)` when the read I/O fails with the serial port
     /// * `Err(Error::Write)` when the write I/O fails with the serial port
     /// * `Err(Error::Data)` when corrupted data has been detected
-    fn read_byte(&mut self) -> Result<u8, Error>;
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut buf = [0; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    /// Reads exactly enough bytes to fill the buffer. The default calls
+    /// `Read::read` until the buffer is full.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Read)` when the read I/O fails with the serial port, or
+    ///   the end of the data is reached before the buffer is full
+    /// * `Err(Error::Write)` when the write I/O fails with the serial port
+    /// * `Err(Error::Data)` when corrupted data has been detected
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        match read_full(self, buf)? {
+            len if len as usize == buf.len() => Ok(()),
+            _ => Err(Error::Read),
+        }
+    }
+}
+
+/// Calls `Read::read` until `buf` is full, or the end of the data is reached,
+/// and returns the number of bytes read
+fn read_full<R>(reader: &mut R, buf: &mut [u8]) -> Result<u32, Error>
+where
+    R: Read + ?Sized,
+{
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..])? {
+            0 => break,
+            count => len += count as usize,
+        }
+    }
+    u32::try_from(len).or(Err(Error::Data))
 }
 
 /// Seek I/O operations
@@ -163,6 +378,28 @@ pub trait Seek {
     fn seek(&mut self, offset: u32) -> Result<(), Error>;
 }
 
+/// Source of subpackets, which have been escaped ahead of writing them, e.g.
+/// on another thread
+pub trait EscapedSource {
+    /// Returns the subpacket of at most `len` file bytes at `offset` as the
+    /// number of file bytes, the data escaped with `escape` in addition to
+    /// the bytes always escaped, and the CRC of the data before escaping,
+    /// which is CRC-32 for `Encoding::ZBIN32`, and CRC-16 otherwise. Zero bytes
+    /// are returned at the end of file.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Read)` when the file cannot be read
+    /// * `Err(Error::Data)` when the offset is invalid
+    fn next_escaped(
+        &mut self,
+        offset: u32,
+        len: usize,
+        encoding: Encoding,
+        escape: &EscapeSet,
+    ) -> Result<(u32, &[u8], u32), Error>;
+}
+
 /// Data structure for holding a ZMODEM protocol header, which begins a frame,
 /// and is followed optionally by a variable number of subpackets.
 #[repr(C)]
@@ -196,7 +433,8 @@ impl Header {
         self.frame
     }
 
-    /// Returns count for the frame types using this field
+    /// Returns count for the frame types using this field, i.e. the file
+    /// offset for `ZRPOS`, `ZDATA`, `ZEOF` and `ZACK`
     #[must_use]
     pub const fn count(&self) -> u32 {
         u32::from_le_bytes(self.flags)
@@ -213,7 +451,22 @@ impl Header {
     where
         P: Write,
     {
-        let mut out = array_vec!([u8; HEADER_SIZE]);
+        self.write_with(port, &EscapeSet::new())
+    }
+
+    /// Encodes and writes the header to the serial port, escaping also the
+    /// bytes in `escape`
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Read)` when the read I/O fails with the serial port
+    /// * `Err(Error::Write)` when the write I/O fails with the serial port
+    /// * `Err(Error::Data)` when corrupted data has been detected
+    pub fn write_with<P>(&self, port: &mut P, escape: &EscapeSet) -> Result<(), Error>
+    where
+        P: Write,
+    {
+        let mut out = ArrayBuf::<HEADER_SIZE>::new();
         port.write_byte(ZPAD)?;
         if self.encoding == Encoding::ZHEX {
             port.write_byte(ZPAD)?;
@@ -234,11 +487,11 @@ impl Header {
                 return Err(Error::Data);
             }
             let hex = &mut hexbuf[..len];
-            hex::encode_to_slice(&out, hex).map_err(|_| Error::Data)?;
+            encode_hex(&out, hex);
             out.truncate(0);
             out.extend_from_slice(hex);
         }
-        write_slice_escaped(port, &out)?;
+        write_slice_escaped(port, &out, escape)?;
         if self.encoding == Encoding::ZHEX {
             // Add trailing CRLF for ZHEX transfer:
             port.write_byte(b'\r')?;
@@ -259,26 +512,63 @@ impl Header {
     /// * `Err(Error::Write)` when the write I/O fails with the serial port
     /// * `Err(Error::Data)` when corrupted data has been detected
     pub fn read<P>(port: &mut P) -> Result<Header, Error>
+    where
+        P: Read,
+    {
+        Self::read_with(port, false)
+    }
+
+    /// Reads and decodes a header. When `crc_width` is set, a binary header
+    /// is accepted also with the CRC of the other binary encoding, and the
+    /// returned header has the encoding matching the CRC.
+    fn read_with<P>(port: &mut P, crc_width: bool) -> Result<Header, Error>
     where
         P: Read,
     {
         let encoding = Encoding::try_from(port.read_byte()?)?;
-        let mut out_hex = array_vec!([u8; HEADER_SIZE]);
+        if crc_width && encoding != Encoding::ZHEX {
+            return Self::read_either_crc(port);
+        }
+        let mut out_hex = ArrayBuf::<HEADER_SIZE>::new();
         for _ in 0..Header::unescaped_size(encoding) - 1 {
             out_hex.push(read_byte_unescaped(port)?);
         }
-        let mut out = array_vec!([u8; HEADER_SIZE]);
+        let mut out = ArrayBuf::<HEADER_SIZE>::new();
         out.set_len(out_hex.len() / 2);
         if encoding == Encoding::ZHEX {
-            hex::decode_to_slice(out_hex, &mut out).map_err(|_| Error::Data)?;
+            decode_hex(&out_hex, &mut out)?;
         } else {
             out = out_hex;
         }
-        check_crc(&out[..5], &out[5..], encoding)?;
-        let frame = Frame::try_from(out[0])?;
-        let mut header = Header::new(encoding, frame, &[0; 4]);
-        header.flags.copy_from_slice(&out[1..=4]);
-        Ok(header)
+        let (data, crc) = (out.get(..5), out.get(5..));
+        let (Some(data), Some(crc)) = (data, crc) else {
+            return Err(Error::Data);
+        };
+        check_crc(data, crc, encoding)?;
+        let [frame, flags @ ..] = <[u8; 5]>::try_from(data).or(Err(Error::Data))?;
+        Ok(Header::new(encoding, Frame::try_from(frame)?, &flags))
+    }
+
+    /// Reads the rest of a binary header with either CRC-16 or CRC-32. The
+    /// shorter CRC-16 is tried first, as the bytes following the header
+    /// cannot be put back.
+    fn read_either_crc<P>(port: &mut P) -> Result<Header, Error>
+    where
+        P: Read,
+    {
+        let mut out = ArrayBuf::<HEADER_SIZE>::new();
+        for _ in 0..Header::unescaped_size(Encoding::ZBIN) - 1 {
+            out.push(read_byte_unescaped(port)?);
+        }
+        let mut encoding = Encoding::ZBIN;
+        if check_crc(&out[..5], &out[5..], encoding).is_err() {
+            out.push(read_byte_unescaped(port)?);
+            out.push(read_byte_unescaped(port)?);
+            encoding = Encoding::ZBIN32;
+            check_crc(&out[..5], &out[5..], encoding)?;
+        }
+        let [frame, flags @ ..] = <[u8; 5]>::try_from(&out[..5]).or(Err(Error::Data))?;
+        Ok(Header::new(encoding, Frame::try_from(frame)?, &flags))
     }
 
     /// Returns a new instance with the flags substitude with a count
@@ -288,6 +578,37 @@ impl Header {
         Header::new(self.encoding, self.frame, &count.to_le_bytes())
     }
 
+    /// Returns the flag bytes `ZF0`, `ZF1`, `ZF2` and `ZF3` in this order for
+    /// the frame types using this field, such as the conversion options of
+    /// `ZFILE`. The bytes are stored in the header in the reverse order.
+    #[must_use]
+    pub const fn zf(&self) -> [u8; 4] {
+        let [zf3, zf2, zf1, zf0] = self.flags;
+        [zf0, zf1, zf2, zf3]
+    }
+
+    /// Returns a new instance with the flags substituted with the flag bytes
+    /// `ZF0`, `ZF1`, `ZF2` and `ZF3` given in this order
+    #[must_use]
+    pub const fn with_zf(&self, zf: &[u8; 4]) -> Self {
+        let [zf0, zf1, zf2, zf3] = *zf;
+        Header::new(self.encoding, self.frame, &[zf3, zf2, zf1, zf0])
+    }
+
+    /// Returns the capabilities advertised in `ZRINIT`. Unknown bits are
+    /// ignored.
+    #[must_use]
+    pub const fn zrinit(&self) -> Zrinit {
+        Zrinit::from_bits_truncate(self.zf()[0])
+    }
+
+    /// Returns a new instance with the flags substituted with the
+    /// capabilities for `ZRINIT`
+    #[must_use]
+    pub const fn with_zrinit(&self, zrinit: Zrinit) -> Self {
+        self.with_zf(&[zrinit.bits(), 0, 0, 0])
+    }
+
     /// Returns the serialized size of the header before escaping
     const fn unescaped_size(encoding: Encoding) -> usize {
         match encoding {
@@ -303,7 +624,7 @@ impl Header {
 /// The ZMODEM protocol frame encoding
 #[repr(u8)]
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy, EnumIter, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Encoding {
     ZBIN = 0x41,
     ZHEX = 0x42,
@@ -314,15 +635,18 @@ impl TryFrom<u8> for Encoding {
     type Error = Error;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        Encoding::iter()
-            .find(|e| value == *e as u8)
-            .ok_or(Error::Data)
+        match value {
+            0x41 => Ok(Encoding::ZBIN),
+            0x42 => Ok(Encoding::ZHEX),
+            0x43 => Ok(Encoding::ZBIN32),
+            _ => Err(Error::Data),
+        }
     }
 }
 
 #[repr(u8)]
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy, EnumIter, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 /// Frame types
 pub enum Frame {
     /// Request receive init
@@ -367,40 +691,210 @@ pub enum Frame {
     ZSTDERR = 19,
 }
 
+/// Frame types indexed by their value
+const FRAMES: [Frame; 20] = [
+    Frame::ZRQINIT,
+    Frame::ZRINIT,
+    Frame::ZSINIT,
+    Frame::ZACK,
+    Frame::ZFILE,
+    Frame::ZSKIP,
+    Frame::ZNAK,
+    Frame::ZABORT,
+    Frame::ZFIN,
+    Frame::ZRPOS,
+    Frame::ZDATA,
+    Frame::ZEOF,
+    Frame::ZFERR,
+    Frame::ZCRC,
+    Frame::ZCHALLENGE,
+    Frame::ZCOMPL,
+    Frame::ZCAN,
+    Frame::ZFREECNT,
+    Frame::ZCOMMAND,
+    Frame::ZSTDERR,
+];
+
 impl TryFrom<u8> for Frame {
     type Error = Error;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        Frame::iter().find(|t| value == *t as u8).ok_or(Error::Data)
+        FRAMES.get(value as usize).copied().ok_or(Error::Data)
+    }
+}
+
+/// `ZRINIT` flags
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Zrinit(u8);
+
+impl Zrinit {
+    /// Can send and receive in full-duplex
+    pub const CANFDX: Self = Self(0x01);
+    /// Can receive data in parallel with disk I/O
+    pub const CANOVIO: Self = Self(0x02);
+    /// Can send a break signal
+    pub const CANBRK: Self = Self(0x04);
+    /// Can decrypt
+    pub const CANCRY: Self = Self(0x08);
+    /// Can uncompress
+    pub const CANLZW: Self = Self(0x10);
+    /// Can use 32-bit frame check
+    pub const CANFC32: Self = Self(0x20);
+    /// Expects control character to be escaped
+    pub const ESCCTL: Self = Self(0x40);
+    /// Expects 8th bit to be escaped
+    pub const ESC8: Self = Self(0x80);
+
+    /// Returns an instance with no flags set
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the raw value of the flags
+    #[must_use]
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    /// Returns an instance for the raw value of the flags. As all of the bits
+    /// are defined, none are dropped.
+    #[must_use]
+    pub const fn from_bits_truncate(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// Returns `true` if all of the flags in `other` are set
+    #[must_use]
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns `true` if any of the flags in `other` is set
+    #[must_use]
+    pub const fn intersects(&self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// Sets the flags in `other`
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    /// Clears the flags in `other`
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for Zrinit {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Zrinit {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitAnd for Zrinit {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Set of bytes escaped with `ZDLE` in addition to the ones always escaped,
+/// e.g. for a link which drops the flow control characters only in one
+/// direction, or a multiplexer which reserves a command byte. Only the
+/// control characters `0x00..=0x1f` and `0x80..=0x9f` can be escaped, and
+/// all of the bytes with the 8th bit set with `EscapeSet::insert_high`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EscapeSet {
+    bytes: [u8; 32],
+    high: bool,
+}
+
+impl EscapeSet {
+    /// All of the control characters
+    pub const CONTROL: Self = Self {
+        bytes: [
+            0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ],
+        high: false,
+    };
+
+    /// Returns an empty instance
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; 32],
+            high: false,
+        }
+    }
+
+    /// Returns `true` if no additional bytes are escaped
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        !self.high && self.bytes.iter().all(|bits| *bits == 0)
+    }
+
+    /// Returns `true` if `byte` is in the set
+    #[must_use]
+    pub const fn contains(&self, byte: u8) -> bool {
+        (self.high && byte & 0x80 != 0) || self.bytes[(byte >> 3) as usize] & (1 << (byte & 7)) != 0
+    }
+
+    /// Adds all of the bytes with the 8th bit set for a 7-bit link, which
+    /// are escaped with `proto::ZESC8`
+    pub fn insert_high(&mut self) {
+        self.high = true;
+    }
+
+    /// Adds `byte` to the set
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when `byte` is not a control character
+    pub fn insert(&mut self, byte: u8) -> Result<(), Error> {
+        if byte & 0x60 != 0 {
+            return Err(Error::Data);
+        }
+        self.bytes[(byte >> 3) as usize] |= 1 << (byte & 7);
+        Ok(())
+    }
+
+    /// Removes `byte` from the set
+    pub fn remove(&mut self, byte: u8) {
+        self.bytes[(byte >> 3) as usize] &= !(1 << (byte & 7));
     }
 }
 
-bitflags! {
-   /// `ZRINIT` flags
-   struct Zrinit: u8 {
-        /// Can send and receive in full-duplex
-        const CANFDX = 0x01;
-        /// Can receive data in parallel with disk I/O
-        const CANOVIO = 0x02;
-        /// Can send a break signal
-        const CANBRK = 0x04;
-        /// Can decrypt
-        const CANCRY = 0x08;
-        /// Can uncompress
-        const CANLZW = 0x10;
-        /// Can use 32-bit frame check
-        const CANFC32 = 0x20;
-        /// Expects control character to be escaped
-        const ESCCTL = 0x40;
-        /// Expects 8th bit to be escaped
-        const ESC8 = 0x80;
+impl BitOr for EscapeSet {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        let mut bytes = self.bytes;
+        for (bits, other) in bytes.iter_mut().zip(other.bytes) {
+            *bits |= other;
+        }
+        Self {
+            bytes,
+            high: self.high || other.high,
+        }
     }
 }
 
 /// The ZMODEM protocol subpacket type
 #[repr(u8)]
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy, EnumIter, PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum Packet {
     ZCRCE = 0x68,
     ZCRCG = 0x69,
@@ -412,37 +906,273 @@ impl TryFrom<u8> for Packet {
     type Error = Error;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        Packet::iter()
-            .find(|e| value == *e as u8)
-            .ok_or(Error::Data)
+        match value {
+            0x68 => Ok(Packet::ZCRCE),
+            0x69 => Ok(Packet::ZCRCG),
+            0x6a => Ok(Packet::ZCRCQ),
+            0x6b => Ok(Packet::ZCRCW),
+            _ => Err(Error::Data),
+        }
+    }
+}
+
+/// File information transmitted in the `ZFILE` subpacket
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileInfo {
+    name: String<256>,
+    size: u32,
+    mtime: u32,
+    mode: u32,
+    files_remaining: Option<u32>,
+    bytes_remaining: Option<u64>,
+}
+
+impl FileInfo {
+    const fn new() -> Self {
+        Self {
+            name: String::new(),
+            size: 0,
+            mtime: 0,
+            mode: 0,
+            files_remaining: None,
+            bytes_remaining: None,
+        }
+    }
+
+    /// Returns the file name
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the file size in bytes
+    #[must_use]
+    pub fn size(&self) -> u32 {
+        self.size
     }
+
+    /// Returns the modification time in seconds since the Unix epoch, or zero
+    /// when not sent
+    #[must_use]
+    pub fn mtime(&self) -> u32 {
+        self.mtime
+    }
+
+    /// Returns the Unix file mode, or zero when not sent
+    #[must_use]
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Returns the number of files remaining in the batch including this
+    /// file, when sent
+    #[must_use]
+    pub fn files_remaining(&self) -> Option<u32> {
+        self.files_remaining
+    }
+
+    /// Returns the number of bytes remaining in the batch including this
+    /// file, when sent
+    #[must_use]
+    pub fn bytes_remaining(&self) -> Option<u64> {
+        self.bytes_remaining
+    }
+}
+
+/// Decision of the application on a file offered by the sender
+#[allow(clippy::large_enum_variant)]
+pub enum Decision {
+    /// Accept the file, and request the sender to start from the given offset
+    Accept(u32),
+    /// Skip the file with `ZSKIP`
+    Skip,
+    /// Accept the file from the beginning, and store it with a new name
+    Rename(String<256>),
+}
+
+/// Checkpoints of the acknowledged file offset
+#[derive(Clone, Copy)]
+struct Checkpoint {
+    interval: u32,
+    /// Offset most recently acknowledged by the receiver to the sender
+    acknowledged: u32,
+    /// Checkpoint reached by the most recent call
+    reached: Option<u32>,
 }
 
+/// Check for the existence of a file in the local filesystem
+type NameExists<'a> = &'a mut dyn FnMut(&str) -> bool;
+
+/// Handler for the commands sent with `ZCOMMAND`
+type CommandHandler<'a> = &'a mut dyn FnMut(&[u8]) -> u32;
+
 /// Send or receive transmission state
-pub struct State {
+#[allow(clippy::struct_excessive_bools)]
+pub struct State<'a> {
     stage: Stage,
     count: u32,
-    file_name: String<256>,
-    file_size: u32,
+    file: FileInfo,
+    next_file: Option<FileInfo>,
     buf: Buffer,
+    checkpoint: Option<Checkpoint>,
+    pre_accept: Option<&'a mut dyn FnMut(&FileInfo) -> Decision>,
+    summary: TransferSummary,
+    file_summaries: Option<&'a mut [FileSummary]>,
+    file_summaries_len: usize,
+    file_entry: Option<usize>,
+    file_open: bool,
+    batch: Option<BatchProgress>,
+    clock: Option<&'a dyn Fn() -> u32>,
+    started: Option<u32>,
+    frame: Option<Frame>,
+    last_error: Option<ErrorContext>,
+    last_nak: Option<NakContext>,
+    link_event: Option<LinkEvent>,
+    link_events_in_row: u32,
+    role: Option<Role>,
+    peer_zrinit: Option<Zrinit>,
+    codec: Option<&'a mut dyn Codec>,
+    codec_active: bool,
+    newline: Option<Newline>,
+    converter: Option<Converter>,
+    dry_run: Option<&'a mut [FileInfo]>,
+    dry_run_len: usize,
+    coalesce: Option<&'a mut [u8]>,
+    coalesce_len: usize,
+    burst: Burst,
+    zdata: Option<Encoding>,
+    overlap: u32,
+    repositioned: Option<u32>,
+    budget: usize,
+    alignment: u32,
+    resume: Option<&'a mut dyn ResumeStore>,
+    resume_interval: u32,
+    resume_crc: Option<u32>,
+    command: Option<CommandHandler<'a>>,
+    conformance: Conformance,
+    quirks: Quirks,
+    zfile_retries: Option<u32>,
+    name_policy: Option<(NamePolicy, NameExists<'a>)>,
+    cancel: Option<&'a AtomicBool>,
+    crc16: bool,
+    announce: Announce,
+    announcements: u32,
+    escape: EscapeSet,
+    /// Escapes requested by the receiver in the current session
+    peer_escape: EscapeSet,
+    zesc8: bool,
+    parity: bool,
+    crc_width: bool,
+    drain: bool,
+    quotas: Option<Quotas>,
+    zdata_subpackets: u32,
+    file_marks: Option<Deque<FileMark, 4>>,
+}
+
+/// Progress of the `ZDATA` burst written by the sender
+#[derive(Clone, Copy, PartialEq)]
+enum Burst {
+    /// No burst has been written
+    Idle,
+    /// `ZEOF` was written at the end of the file
+    Eof,
+    /// The burst was finished with `ZCRCW` at `end`
+    Complete { end: u32 },
+    /// The step budget ran out after `sent` subpackets, and the burst is
+    /// continued from `offset` in the next call
+    Paused { offset: u32, sent: usize },
+}
+
+/// Side of the session driven by a `State`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Sends the files with `zmodem2::send`
+    Sender,
+    /// Receives the files with `zmodem2::receive`
+    Receiver,
 }
 
-impl Default for State {
+impl Default for State<'_> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl State {
+impl<'a> State<'a> {
     /// Create a new transmission context
     #[must_use]
     pub const fn new() -> Self {
         Self {
             stage: Stage::Waiting,
             count: 0,
-            file_name: String::new(),
-            file_size: 0,
-            buf: Buffer::from_array_empty([0; BUFFER_SIZE]),
+            file: FileInfo::new(),
+            next_file: None,
+            buf: Buffer::new(),
+            checkpoint: None,
+            pre_accept: None,
+            summary: TransferSummary {
+                files_transferred: 0,
+                files_skipped: 0,
+                bytes: 0,
+                retries: 0,
+                timeouts: 0,
+                garbage: 0,
+                bad_headers: 0,
+                crc_width_mismatches: 0,
+                suppressed_repositions: 0,
+                subpacket_bytes: 0,
+                escapes: 0,
+                duration: None,
+            },
+            file_summaries: None,
+            file_summaries_len: 0,
+            file_entry: None,
+            file_open: false,
+            batch: None,
+            clock: None,
+            started: None,
+            frame: None,
+            last_error: None,
+            last_nak: None,
+            link_event: None,
+            link_events_in_row: 0,
+            role: None,
+            peer_zrinit: None,
+            codec: None,
+            codec_active: false,
+            newline: None,
+            converter: None,
+            dry_run: None,
+            dry_run_len: 0,
+            coalesce: None,
+            coalesce_len: 0,
+            burst: Burst::Idle,
+            zdata: None,
+            overlap: 0,
+            repositioned: None,
+            budget: 0,
+            alignment: 0,
+            resume: None,
+            resume_interval: 0,
+            resume_crc: None,
+            command: None,
+            conformance: Conformance::Lenient,
+            quirks: Quirks::empty(),
+            zfile_retries: None,
+            name_policy: None,
+            cancel: None,
+            crc16: false,
+            announce: Announce::Repeat,
+            announcements: 0,
+            escape: EscapeSet::new(),
+            peer_escape: EscapeSet::new(),
+            zesc8: false,
+            parity: false,
+            crc_width: false,
+            drain: false,
+            quotas: None,
+            zdata_subpackets: 0,
+            file_marks: None,
         }
     }
 
@@ -454,534 +1184,5671 @@ impl State {
     /// * `Err(Error::Write)` when the write I/O fails with the serial port
     /// * `Err(Error::Data)` when corrupted data has been detected
     pub fn new_file(file_name: &str, file_size: u32) -> Result<Self, Error> {
-        let file_name = String::from_str(file_name).or(Err(Error::Data))?;
-        Ok(Self {
-            stage: Stage::Waiting,
-            count: 0,
-            file_name,
-            file_size,
-            buf: Buffer::from_array_empty([0; BUFFER_SIZE]),
-        })
+        let mut state = Self::new();
+        state.set_file(file_name, file_size)?;
+        Ok(state)
     }
 
-    #[must_use]
-    pub fn stage(&self) -> Stage {
-        self.stage
+    /// Sets the name and size of the file to be sent, which allows to reuse
+    /// an existing instance e.g. from a pool. The file can be changed until
+    /// the sender has offered it with `ZFILE`.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the file name is too long, or the file has
+    ///   been already offered
+    pub fn set_file(&mut self, file_name: &str, file_size: u32) -> Result<(), Error> {
+        if self.stage != Stage::Waiting || self.file_open {
+            return Err(Error::Data);
+        }
+        self.file.name = String::from_str(file_name).or(Err(Error::Data))?;
+        self.file.size = file_size;
+        Ok(())
     }
 
-    #[must_use]
-    pub fn count(&self) -> u32 {
-        self.count
+    /// Prepares the context for a new session on the same port. The
+    /// callbacks, storage, codec, newline convention and clock are kept, and
+    /// everything else is reset as in `State::new`.
+    pub fn reset(&mut self) {
+        let file_summaries = self.file_summaries.take();
+        let dry_run = self.dry_run.take();
+        let coalesce = self.coalesce.take();
+        *self = Self {
+            checkpoint: self.checkpoint.map(|checkpoint| Checkpoint {
+                acknowledged: 0,
+                reached: None,
+                ..checkpoint
+            }),
+            pre_accept: self.pre_accept.take(),
+            clock: self.clock.take(),
+            codec: self.codec.take(),
+            newline: self.newline.take(),
+            resume: self.resume.take(),
+            resume_interval: self.resume_interval,
+            command: self.command.take(),
+            conformance: self.conformance,
+            quirks: self.quirks,
+            budget: self.budget,
+            alignment: self.alignment,
+            name_policy: self.name_policy.take(),
+            cancel: self.cancel,
+            crc16: self.crc16,
+            announce: self.announce,
+            escape: self.escape,
+            zesc8: self.zesc8,
+            parity: self.parity,
+            crc_width: self.crc_width,
+            drain: self.drain,
+            quotas: self.quotas,
+            file_marks: self.file_marks.take().map(|_| Deque::new()),
+            ..Self::new()
+        };
+        if let Some(storage) = file_summaries {
+            self.set_file_summaries(storage);
+        }
+        if let Some(storage) = dry_run {
+            self.set_dry_run(storage);
+        }
+        if let Some(storage) = coalesce {
+            self.set_coalesced_writes(storage);
+        }
     }
 
-    #[must_use]
-    pub fn file_name(&self) -> &str {
-        &self.file_name
+    /// Prepares the context for a new session on the same port, which sends
+    /// a file with the given name and size. The configuration is kept as in
+    /// `State::reset`.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the file name is too long
+    pub fn reset_file(&mut self, file_name: &str, file_size: u32) -> Result<(), Error> {
+        let name = String::from_str(file_name).or(Err(Error::Data))?;
+        self.reset();
+        self.file.name = name;
+        self.file.size = file_size;
+        Ok(())
+    }
+
+    /// Sets the next file to be sent in the batch. The sender offers the file
+    /// after the current file has been transferred or skipped by the
+    /// receiver, and the file information is then available through
+    /// `State::file_info`. Until set again, the session is finished after
+    /// that file.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the file name is too long
+    pub fn set_next_file(&mut self, file_name: &str, file_size: u32) -> Result<(), Error> {
+        let mut file = FileInfo::new();
+        file.name = String::from_str(file_name).or(Err(Error::Data))?;
+        file.size = file_size;
+        self.next_file = Some(file);
+        Ok(())
     }
 
+    /// Returns `true` when a next file has been set, and not yet offered
     #[must_use]
-    pub fn file_size(&self) -> u32 {
-        self.file_size
+    pub fn has_next_file(&self) -> bool {
+        self.next_file.is_some()
     }
-}
 
-#[derive(Clone, Copy, PartialEq)]
-pub enum Stage {
-    Waiting,
-    Ready,
-    InProgress,
-    Done,
-}
+    /// Sets a checkpoint every `interval` acknowledged bytes, which is
+    /// returned by `State::checkpoint`.
+    ///
+    /// The receiver reaches a checkpoint, when `interval` bytes more have
+    /// been written to the file, and the file has been flushed, and thus the
+    /// offset can be persisted, and later passed to `State::set_count` in
+    /// order to resume the transfer after e.g. a power loss. The sender
+    /// reaches a checkpoint, when the offset acknowledged by the receiver with
+    /// `ZACK`, `ZRPOS` or the final `ZRINIT` has advanced by `interval` bytes.
+    pub fn set_checkpoint(&mut self, interval: u32) {
+        self.checkpoint = Some(Checkpoint {
+            interval,
+            acknowledged: 0,
+            reached: None,
+        });
+    }
 
-/// Sends a file using the ZMODEM file transfer protocol.
+    /// Returns the offset of the checkpoint reached by the most recent call
+    /// to `zmodem2::send` or `zmodem2::receive`, or `None` if no checkpoint
+    /// was reached, or no interval has been set with `State::set_checkpoint`
+    #[must_use]
+    pub fn checkpoint(&self) -> Option<u32> {
+        self.checkpoint.and_then(|checkpoint| checkpoint.reached)
+    }
+
+    /// Limits the number of subpackets processed by a single call to
+    /// `zmodem2::send` or `zmodem2::receive`, which bounds the time spent in
+    /// a call e.g. in a superloop. A `ZDATA` frame exceeding the budget is
+    /// continued in the next call. Zero, the default, means no limit.
+    pub fn set_step_budget(&mut self, subpackets: usize) {
+        self.budget = subpackets;
+    }
+
+    /// Aligns the position, from which the sender restarts after `ZRPOS`, down
+    /// to a multiple of `bytes`, and thus the overlap with the data already
+    /// received is sent again. This simplifies the sinks, which can only write
+    /// whole blocks. The receiver discards the duplicated data. Zero, the
+    /// default, means no alignment.
+    pub fn set_reposition_alignment(&mut self, bytes: u32) {
+        self.alignment = bytes;
+    }
+
+    /// Sets the conformance required from the headers sent by the peer. The
+    /// default is `Conformance::Lenient`.
+    pub fn set_conformance(&mut self, conformance: Conformance) {
+        self.conformance = conformance;
+    }
+
+    /// Sets the deviations tolerated from the peer, e.g. `Quirks::TERA_TERM`
+    /// for the ZMODEM stack built in the terminal program. The default is
+    /// `Quirks::empty()`, which expects lrzsz.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Sets the behavior of the sender, while it waits for `ZRINIT`. The
+    /// default is `Announce::Repeat`, which waits indefinitely.
+    pub fn set_announce(&mut self, announce: Announce) {
+        self.announce = announce;
+    }
+
+    /// Returns the number of `ZRQINIT` announcements sent by the sender
+    #[must_use]
+    pub fn announcements(&self) -> u32 {
+        self.announcements
+    }
+
+    /// Sets the bytes escaped with `ZDLE` in addition to the ones always
+    /// escaped. This applies to the bytes written by either side, and the
+    /// default is an empty set. A file source, which escapes the subpackets
+    /// ahead, is bypassed when the set is not empty.
+    pub fn set_escape(&mut self, escape: EscapeSet) {
+        self.escape = escape;
+    }
+
+    /// Tolerates a 7-bit link with parity, e.g. 7E1, by stripping the parity
+    /// bit from the bytes of the received headers, including the control
+    /// characters marked with it. The receiver advertises `Zrinit::ESC8`
+    /// for asking the sender to escape the 8th bit, as the sender of this
+    /// crate does with `proto::ZESC8`, when enabled with
+    /// `State::set_zesc8`. The default is `false`.
+    pub fn set_parity_tolerant(&mut self, tolerant: bool) {
+        self.parity = tolerant;
+    }
+
+    /// Escapes the 8th bit with `proto::ZESC8` for a receiver advertising
+    /// `Zrinit::ESC8`. The escape is not defined by the ZMODEM specification,
+    /// and is understood only by the receiver of this crate, and thus this
+    /// should be enabled only, when the peer is known to run this crate. The
+    /// default is `false`.
+    pub fn set_zesc8(&mut self, enabled: bool) {
+        self.zesc8 = enabled;
+    }
+
+    /// Accepts a binary header also with the CRC of the other binary
+    /// encoding, as sent by some peers after renegotiating the encoding. The
+    /// subpackets following the header are expected to use the same CRC. The
+    /// accepted headers are counted by
+    /// `TransferSummary::crc_width_mismatches`. The default is `false`.
+    pub fn set_crc_width_tolerant(&mut self, tolerant: bool) {
+        self.crc_width = tolerant;
+    }
+
+    /// Enables the hardened mode, in which the work derived from the peer
+    /// input is bounded by `quotas`, e.g. for an unattended device exposing
+    /// ZMODEM on an open console port. A step exceeding a quota fails with
+    /// `Err(Error::Quota)` naming the quota. The duration is measured with
+    /// the clock, which must be set first with `State::set_clock`.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the duration is bound, and no clock is set
+    pub fn set_quotas(&mut self, quotas: Quotas) -> Result<(), Error> {
+        if quotas.duration.is_some() && self.clock.is_none() {
+            return Err(Error::Data);
+        }
+        self.quotas = Some(quotas);
+        Ok(())
+    }
+
+    /// Drains the port after the closing handshake, before the session is
+    /// reported as done. The output is flushed, and the residual input is
+    /// consumed until a read times out, so that the port can be used e.g. by
+    /// an interactive shell without the leftover protocol bytes spilling into
+    /// it. A session following immediately on the same port might lose its
+    /// first header, and thus the default is `false`.
+    pub fn set_drain(&mut self, drain: bool) {
+        self.drain = drain;
+    }
+
+    /// Stops the receiver from advertising `Zrinit::CANFC32`, which asks the
+    /// sender to protect the data subpackets with CRC-16 instead of CRC-32.
+    /// This trades integrity margin for CPU time e.g. on an MCU without a
+    /// CRC unit. The default is `false`.
+    pub fn set_prefer_crc16(&mut self, prefer: bool) {
+        self.crc16 = prefer;
+    }
+
+    /// Sets a policy for the names of the received files, e.g. for storing
+    /// them to FAT. The receiver does not replace an existing file, for which
+    /// `exists` returns `true`, but picks the first alternative name not
+    /// existing, or skips the file if there is none. The file being resumed
+    /// from the resume store is an exception.
+    pub fn set_name_policy(&mut self, policy: NamePolicy, exists: NameExists<'a>) {
+        self.name_policy = Some((policy, exists));
+    }
+
+    /// Sets a token for cancelling the session e.g. from another thread or an
+    /// interrupt handler. The token is checked in the beginning of each call
+    /// to `zmodem2::send` or `zmodem2::receive`, and between the received
+    /// subpackets. When the token is set, the abort sequence is sent to the
+    /// peer, the session is finished, and `Err(Error::Cancelled)` is returned.
+    pub fn set_cancel_token(&mut self, token: &'a AtomicBool) {
+        self.cancel = Some(token);
+    }
+
+    /// Sets a handler for the commands sent with `ZCOMMAND`, which returns the
+    /// status sent back with `ZCOMPL`. The commands are ignored, unless a
+    /// handler has been set.
+    pub fn set_command_handler(&mut self, handler: &'a mut dyn FnMut(&[u8]) -> u32) {
+        self.command = Some(handler);
+    }
+
+    /// Sets a persistent store for the resume record of the file being
+    /// received. The receiver flushes the file, and saves the record after
+    /// accepting a file, every time when `interval` bytes more have been
+    /// written, and at the end of the file. When a file with the same name and
+    /// size is offered again, e.g. after a reboot, the transfer is resumed
+    /// from the stored offset, unless a pre-accept callback has been set. The
+    /// file must then contain the data up to `State::count`. The files
+    /// converted with `State::set_newline` are not recorded.
+    pub fn set_resume_store(&mut self, interval: u32, store: &'a mut dyn ResumeStore) {
+        self.resume = Some(store);
+        self.resume_interval = interval;
+    }
+
+    /// Sets a callback, which the receiver invokes with the information of
+    /// each file offered by the sender before answering `ZFILE`. The returned
+    /// `Decision` determines, whether the file is accepted, renamed or
+    /// skipped.
+    pub fn set_pre_accept(&mut self, callback: &'a mut dyn FnMut(&FileInfo) -> Decision) {
+        self.pre_accept = Some(callback);
+    }
+
+    /// Sets a codec for compressing or encrypting the file data. The receiver
+    /// advertises the capability of the codec, and the sender uses the codec
+    /// for the files sent to a receiver with the same capability.
+    pub fn set_codec(&mut self, codec: &'a mut dyn Codec) {
+        self.codec = Some(codec);
+    }
+
+    /// Sets the local newline convention, to which the receiver converts the
+    /// newlines of the files sent with the `ZCNL` conversion option. The file
+    /// offsets refer to the data before the conversion, and thus the size of a
+    /// converted file can differ from the size given in `ZFILE`.
+    pub fn set_newline(&mut self, newline: Newline) {
+        self.newline = Some(newline);
+    }
+
+    /// Enables the dry-run mode, in which the receiver negotiates the session,
+    /// and records the information of each offered file to `storage`, but
+    /// skips all of the files with `ZSKIP`. The files, which do not fit to the
+    /// storage, are skipped without being recorded.
+    pub fn set_dry_run(&mut self, storage: &'a mut [FileInfo]) {
+        self.dry_run = Some(storage);
+        self.dry_run_len = 0;
+    }
+
+    /// Returns the information of the files offered in the dry-run mode
+    #[must_use]
+    pub fn offered_files(&self) -> &[FileInfo] {
+        match &self.dry_run {
+            Some(storage) => &storage[..self.dry_run_len],
+            None => &[],
+        }
+    }
+
+    /// Sets the storage, in which the receiver accumulates the data of the
+    /// subpackets before writing it to the file, so that the sinks with a high
+    /// overhead per write see fewer and larger writes. The data is written,
+    /// when the storage is full, at the end of each frame, before the resume
+    /// and checkpoint flushes, and at the end of the file.
+    pub fn set_coalesced_writes(&mut self, storage: &'a mut [u8]) {
+        self.coalesce = Some(storage);
+        self.coalesce_len = 0;
+    }
+
+    /// Sets the storage for the summaries of the individual files, which are
+    /// recorded in the order of the files in the session. The files, which do
+    /// not fit to the storage, are only included to `State::summary`.
+    pub fn set_file_summaries(&mut self, storage: &'a mut [FileSummary]) {
+        self.file_summaries = Some(storage);
+        self.file_summaries_len = 0;
+        self.file_entry = None;
+    }
+
+    /// Sets a monotonic clock returning milliseconds, which is used to measure
+    /// the duration of the session.
+    pub fn set_clock(&mut self, clock: &'a dyn Fn() -> u32) {
+        self.clock = Some(clock);
+    }
+
+    /// Returns the summary of the session. After the session has reached
+    /// `Stage::Done`, or failed with an error, the summary is final.
+    #[must_use]
+    pub fn summary(&self) -> TransferSummary {
+        let mut summary = self.summary;
+        if self.file_open {
+            summary.bytes += u64::from(self.count);
+        }
+        if summary.duration.is_none() {
+            if let (Some(clock), Some(started)) = (self.clock, self.started) {
+                summary.duration = Some(clock().wrapping_sub(started));
+            }
+        }
+        summary
+    }
+
+    /// Returns the location of the most recent error returned by
+    /// `zmodem2::send` or `zmodem2::receive`
+    #[must_use]
+    pub fn last_error_context(&self) -> Option<ErrorContext> {
+        self.last_error
+    }
+
+    /// Returns the condition of the link tolerated by the most recent call to
+    /// `zmodem2::send` or `zmodem2::receive`, or `None` if a header was
+    /// received, or the call did not read a header
+    #[must_use]
+    pub fn link_event(&self) -> Option<LinkEvent> {
+        self.link_event
+    }
+
+    /// Returns the number of consecutive calls, which have tolerated a link
+    /// condition without receiving a header. This allows the application to
+    /// give up on an unhealthy link with a policy of its own.
+    #[must_use]
+    pub fn link_events_in_row(&self) -> u32 {
+        self.link_events_in_row
+    }
+
+    /// Returns the record of the most recent `ZNAK` sent to the peer
+    #[must_use]
+    pub fn last_nak(&self) -> Option<&NakContext> {
+        self.last_nak.as_ref()
+    }
+
+    /// Returns the capabilities advertised by the receiver in `ZRINIT`, or
+    /// `None` if the sender has not yet received one
+    #[must_use]
+    pub fn peer_capabilities(&self) -> Option<Zrinit> {
+        self.peer_zrinit
+    }
+
+    /// Returns the frames, which the state machine is currently waiting for
+    /// from the peer. An empty slice is returned before the first call to
+    /// `zmodem2::send` or `zmodem2::receive`, and after the session has
+    /// finished.
+    #[must_use]
+    pub fn expected_frames(&self) -> &'static [Frame] {
+        match (self.role, self.stage) {
+            (None, _) | (_, Stage::Done) => &[],
+            (Some(Role::Sender), Stage::Waiting) => &[Frame::ZRINIT],
+            (Some(Role::Sender), Stage::Ready) => &[Frame::ZRPOS, Frame::ZSKIP],
+            (Some(Role::Sender), Stage::InProgress) => &[Frame::ZRPOS, Frame::ZACK, Frame::ZRINIT],
+            (Some(Role::Receiver), Stage::Waiting) => &[Frame::ZRQINIT, Frame::ZFILE, Frame::ZFIN],
+            (Some(Role::Receiver), Stage::Ready) => &[Frame::ZDATA, Frame::ZEOF],
+            (Some(Role::Receiver), Stage::InProgress) => &[Frame::ZDATA, Frame::ZEOF, Frame::ZFIN],
+        }
+    }
+
+    /// Returns the summaries of the individual files
+    #[must_use]
+    pub fn file_summaries(&self) -> &[FileSummary] {
+        match &self.file_summaries {
+            Some(storage) => &storage[..self.file_summaries_len],
+            None => &[],
+        }
+    }
+
+    /// Sets the file offset. When set before the transfer begins, the
+    /// receiver requests the sender to resume from this offset with `ZRPOS`.
+    pub fn set_count(&mut self, count: u32) {
+        self.count = count;
+    }
+
+    #[must_use]
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    #[must_use]
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    #[must_use]
+    pub fn file_name(&self) -> &str {
+        &self.file.name
+    }
+
+    #[must_use]
+    pub fn file_size(&self) -> u32 {
+        self.file.size
+    }
+
+    /// Returns the information of the file being transferred
+    #[must_use]
+    pub fn file_info(&self) -> &FileInfo {
+        &self.file
+    }
+}
+
+impl State<'_> {
+    /// Begins a call to `zmodem2::send` or `zmodem2::receive` in `role`,
+    /// and starts measuring the duration of the session
+    fn start(&mut self, role: Role) {
+        self.role = Some(role);
+        self.link_event = None;
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.reached = None;
+        }
+        if self.started.is_none() {
+            self.started = self.clock.map(|clock| clock());
+        }
+    }
+
+    /// Moves to `Stage::Done`, and stops measuring the duration
+    fn finish(&mut self) {
+        self.stage = Stage::Done;
+        self.summary.duration = self.summary().duration;
+    }
+
+    /// Returns `true` when the session has been cancelled with the token
+    fn cancelled(&self) -> bool {
+        self.stage != Stage::Done
+            && self
+                .cancel
+                .is_some_and(|token| token.load(Ordering::Relaxed))
+    }
+
+    /// Advances the batch progress to a newly offered file
+    fn offer_batch(&mut self, file: &FileInfo) {
+        let (number, bytes) = self
+            .batch
+            .map_or((1, 0), |batch| (batch.file + 1, batch.bytes));
+        self.batch = Some(BatchProgress {
+            file: number,
+            files: file.files_remaining.map(|files| number - 1 + files),
+            bytes,
+            total: file.bytes_remaining.map(|total| bytes + total),
+        });
+    }
+
+    /// Returns the progress of the batch of files received in the session,
+    /// or `None` before the first file has been offered
+    #[must_use]
+    pub fn batch_progress(&self) -> Option<BatchProgress> {
+        let mut batch = self.batch?;
+        if self.file_open {
+            batch.bytes += u64::from(self.count);
+        }
+        Some(batch)
+    }
+
+    /// Records the beginning of the current file
+    fn begin_file(&mut self) {
+        self.file_open = true;
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            checkpoint.acknowledged = 0;
+        }
+        self.mark_file(FileMark::Begin);
+        if let Some(storage) = self.file_summaries.as_mut() {
+            if let Some(entry) = storage.get_mut(self.file_summaries_len) {
+                *entry = FileSummary {
+                    name: self.file.name.clone(),
+                    size: self.file.size,
+                    count: self.count,
+                    status: FileStatus::Incomplete,
+                };
+                self.file_entry = Some(self.file_summaries_len);
+                self.file_summaries_len += 1;
+            }
+        }
+    }
+
+    /// Records the end of the current file
+    fn end_file(&mut self, status: FileStatus) {
+        if !self.file_open {
+            return;
+        }
+        self.file_open = false;
+        self.mark_file(FileMark::End(status));
+        match status {
+            FileStatus::Transferred => self.summary.files_transferred += 1,
+            FileStatus::Skipped => self.summary.files_skipped += 1,
+            FileStatus::Incomplete => (),
+        }
+        self.summary.bytes += u64::from(self.count);
+        if let Some(batch) = self.batch.as_mut() {
+            batch.bytes += u64::from(self.file.size);
+        }
+        if let Some(entry) = self.current_file_summary() {
+            entry.status = status;
+        }
+        self.file_entry = None;
+    }
+
+    /// Records the beginning or end of a file for `Session`. The oldest mark
+    /// is dropped, if the marks have not been collected.
+    fn mark_file(&mut self, mark: FileMark) {
+        if let Some(marks) = self.file_marks.as_mut() {
+            if marks.is_full() {
+                marks.pop_front();
+            }
+            marks.push_back(mark).ok();
+        }
+    }
+
+    /// Updates the file offset of the current file
+    fn advance(&mut self, count: u32) {
+        self.count = count;
+        if let Some(entry) = self.current_file_summary() {
+            entry.count = count;
+        }
+    }
+
+    /// Returns the offset, from which the sender continues after `ZRPOS` or
+    /// `ZACK`. `ZRPOS` is aligned as set by `State::set_reposition_alignment`.
+    fn restart_offset(&self, header: &Header) -> u32 {
+        let offset = header.count();
+        match header.frame() {
+            Frame::ZRPOS => offset - offset.checked_rem(self.alignment).unwrap_or(0),
+            _ => offset,
+        }
+    }
+
+    /// Returns `true` when a checkpoint lies in `(from, to]`
+    fn crosses_checkpoint(&self, from: u32, to: u32) -> bool {
+        self.checkpoint.is_some_and(|checkpoint| {
+            from.checked_div(checkpoint.interval) != to.checked_div(checkpoint.interval)
+        })
+    }
+
+    /// Records `offset` as acknowledged, which reaches a checkpoint, when it
+    /// has advanced past one
+    fn acknowledge(&mut self, offset: u32) {
+        let Some(acknowledged) = self.checkpoint.map(|checkpoint| checkpoint.acknowledged) else {
+            return;
+        };
+        let crossed = offset > acknowledged && self.crosses_checkpoint(acknowledged, offset);
+        if let Some(checkpoint) = self.checkpoint.as_mut() {
+            if crossed {
+                checkpoint.reached = Some(offset);
+            }
+            checkpoint.acknowledged = offset;
+        }
+    }
+
+    /// Saves the resume record of the current file, if a store has been set
+    fn save_resume(&mut self) -> Result<(), Error> {
+        if self.converter.is_some() {
+            return Ok(());
+        }
+        let Some(store) = self.resume.as_mut() else {
+            return Ok(());
+        };
+        store.save(&ResumeRecord {
+            name: self.file.name.clone(),
+            size: self.file.size,
+            offset: self.count,
+            crc: self.resume_crc,
+        })
+    }
+
+    /// Enables the codec for the current file, when the receiver has the
+    /// capability for it, and returns the `ZF2` transport option for `ZFILE`
+    fn negotiate_transport(&mut self) -> u8 {
+        let transport = self.codec.as_ref().map(|codec| codec.transport());
+        match (transport, self.peer_zrinit) {
+            (Some(transport), Some(zrinit)) if zrinit.contains(transport.capability()) => {
+                self.codec_active = true;
+                transport.option()
+            }
+            _ => {
+                self.codec_active = false;
+                0
+            }
+        }
+    }
+
+    /// Records the capabilities of the receiver. The control characters are
+    /// escaped for a receiver advertising `Zrinit::ESCCTL`. The 8th bit is
+    /// escaped for a receiver on a 7-bit link, which advertises
+    /// `Zrinit::ESC8`, when enabled with `State::set_zesc8`.
+    fn set_peer_zrinit(&mut self, zrinit: Zrinit) {
+        if zrinit.contains(Zrinit::ESCCTL) {
+            self.peer_escape = self.peer_escape | EscapeSet::CONTROL;
+        }
+        if zrinit.contains(Zrinit::ESC8) && self.zesc8 {
+            self.peer_escape.insert_high();
+        }
+        self.peer_zrinit = Some(zrinit);
+    }
+
+    /// Returns the bytes escaped in addition to the ones always escaped, which
+    /// are the ones set by the user, and the ones requested by the receiver
+    fn escape(&self) -> EscapeSet {
+        self.escape | self.peer_escape
+    }
+
+    /// Returns the encoding of the binary headers and the subpackets sent to
+    /// the receiver, which is `Encoding::ZBIN` with CRC-16, unless the
+    /// receiver advertises `Zrinit::CANFC32`
+    fn data_encoding(&self) -> Encoding {
+        match self.peer_zrinit {
+            Some(zrinit) if !zrinit.contains(Zrinit::CANFC32) => Encoding::ZBIN,
+            _ => Encoding::ZBIN32,
+        }
+    }
+
+    /// Returns the number of subpackets streamed before `ZACK` is expected.
+    /// A receiver without full duplex or overlapped I/O would be overrun by
+    /// a stream, and thus is sent one subpacket at a time with `ZCRCW`.
+    fn subpackets_per_ack(&self) -> usize {
+        if self.quirks.contains(Quirks::ZCRCW_ONLY) {
+            return 1;
+        }
+        match self.peer_zrinit {
+            Some(zrinit) if !zrinit.contains(Zrinit::CANFDX | Zrinit::CANOVIO) => 1,
+            _ => SUBPACKET_PER_ACK,
+        }
+    }
+
+    /// Returns the codec, if it is enabled for the current file
+    fn active_codec(&mut self) -> Option<&mut dyn Codec> {
+        match self.codec.as_mut() {
+            Some(codec) if self.codec_active => Some(&mut **codec),
+            _ => None,
+        }
+    }
+
+    /// Records a link condition tolerated by the current step
+    fn record_link_event(&mut self, event: LinkEvent) {
+        match event {
+            LinkEvent::Timeout => {
+                // The line has drained, and the sender has not restarted:
+                self.repositioned = None;
+                self.summary.timeouts += 1;
+            }
+            LinkEvent::Garbage => self.summary.garbage += 1,
+            LinkEvent::BadHeader => self.summary.bad_headers += 1,
+        }
+        self.link_event = Some(event);
+        self.link_events_in_row += 1;
+    }
+
+    /// Records the rejection answered with `ZNAK`, and counts it as a retry
+    fn reject(&mut self, reason: NakReason, header: Option<&Header>, bytes: &[u8]) {
+        let mut recorded = ArrayBuf::new();
+        recorded.extend_from_slice(&bytes[..bytes.len().min(recorded.capacity())]);
+        let encoding = match header {
+            Some(header) => Some(header.encoding()),
+            None => bytes.first().and_then(|b| Encoding::try_from(*b).ok()),
+        };
+        self.last_nak = Some(NakContext {
+            reason,
+            frame: header.map(Header::frame),
+            encoding,
+            bytes: recorded,
+            offset: self.count,
+        });
+        self.summary.retries += 1;
+    }
+
+    /// Records the location of an error returned from a transfer step
+    fn record_error<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if result.is_err() {
+            self.last_error = Some(ErrorContext {
+                stage: self.stage,
+                frame: self.frame,
+                offset: self.count,
+            });
+        }
+        result
+    }
+
+    /// Returns the summary of the current file, if it fits to the storage
+    fn current_file_summary(&mut self) -> Option<&mut FileSummary> {
+        let index = self.file_entry?;
+        self.file_summaries.as_mut()?.get_mut(index)
+    }
+}
+
+/// Behavior of the sender, while it waits for the receiver to answer the
+/// session announcement `ZRQINIT` with `ZRINIT`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Announce {
+    /// Repeat `ZRQINIT` after each timeout
+    Repeat,
+    /// Do not send `ZRQINIT`, and wait for the receiver to send `ZRINIT`
+    Passive,
+    /// Repeat `ZRQINIT` after each timeout, and give up the session with
+    /// `Err(Error::Unanswered)` after the given number of announcements
+    Limited(u32),
+}
+
+/// Stage of the transfer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    /// Waiting for the session or the next file to begin
+    Waiting,
+    /// A file has been offered and accepted
+    Ready,
+    /// The file data is being transferred
+    InProgress,
+    /// The session has finished
+    Done,
+}
+
+/// Sends a file using the ZMODEM file transfer protocol. The port is flushed
+/// at the end of each call.
 ///
 /// # Errors
 ///
 /// * `Err(Error::Read)` when the read I/O fails with the serial port
 /// * `Err(Error::Write)` when the write I/O fails with the serial port
 /// * `Err(Error::Data)` when corrupted data has been detected
-pub fn send<P, F>(port: &mut P, file: &mut F, state: &mut State) -> Result<(), Error>
+/// * `Err(Error::Cancelled)` when the session has been cancelled
+/// * `Err(Error::Unanswered)` when `ZFILE` has not been answered after the
+///   retries, or `ZRINIT` has not been received after the announcements
+///   allowed by `Announce::Limited`
+pub fn send<P, F>(port: &mut P, file: &mut F, state: &mut State<'_>) -> Result<(), Error>
 where
     P: Read + Write,
     F: Read + Seek,
 {
-    if state.stage == Stage::Waiting {
-        ZRQINIT_HEADER.write(port)?;
+    send_source(port, &mut Plain(file), state)
+}
+
+/// Sends a file using the ZMODEM file transfer protocol similarly to
+/// `zmodem2::send`, but takes the data from `source` in the fastest form it
+/// provides, e.g. directly from the memory of a `MappedSource`.
+///
+/// # Errors
+///
+/// * `Err(Error::Read)` when the read I/O fails with the serial port
+/// * `Err(Error::Write)` when the write I/O fails with the serial port
+/// * `Err(Error::Data)` when corrupted data has been detected
+/// * `Err(Error::Cancelled)` when the session has been cancelled
+/// * `Err(Error::Unanswered)` when `ZFILE` has not been answered after the
+///   retries, or `ZRINIT` has not been received after the announcements
+///   allowed by `Announce::Limited`
+pub fn send_source<P, S>(port: &mut P, source: &mut S, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Read + Write,
+    S: Source,
+{
+    let result = send_step(port, source, state);
+    let flushed = port.flush();
+    state.record_error(result.and(flushed))
+}
+
+fn send_step<P, F>(port: &mut P, file: &mut F, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Read + Write,
+    F: Source,
+{
+    state.start(Role::Sender);
+    if state.cancelled() {
+        return write_abort(port, state);
     }
-    if read_zpad(port).is_err() {
-        return Ok(());
+    // Continue the burst paused by the step budget:
+    if let Burst::Paused { offset, sent } = state.burst {
+        return write_zdata(port, state, file, offset, sent);
     }
-    let frame = match Header::read(port) {
-        Err(_) => {
-            ZNAK_HEADER.write(port)?;
-            return Ok(());
-        }
-        Ok(frame) => frame,
+    // Repeat `ZRQINIT`, unless the previous call received a header:
+    let timeout = state.frame.take().is_none();
+    if state.stage == Stage::Waiting && timeout {
+        announce(port, state)?;
+    }
+    if timeout {
+        repeat_zfile(port, state)?;
+    }
+    let Some(frame) = read_frame(port, state)? else {
+        return Ok(());
     };
+    state.frame = Some(frame.frame());
+    if frame.frame() == Frame::ZRINIT {
+        state.set_peer_zrinit(frame.zrinit());
+    }
+    if matches!(frame.frame(), Frame::ZRPOS | Frame::ZSKIP | Frame::ZCRC) {
+        state.zfile_retries = None;
+    }
     match frame.frame() {
         Frame::ZRINIT => match state.stage {
             Stage::Waiting => {
-                write_zfile(port, &mut state.buf, &state.file_name, state.file_size)?;
+                let transport = state.negotiate_transport();
+                write_zfile(port, state, transport)?;
+                state.begin_file();
                 state.stage = Stage::Ready;
+                state.zfile_retries = Some(0);
             }
-            Stage::InProgress => ZFIN_HEADER.write(port)?,
-            Stage::Ready | Stage::Done => (),
+            Stage::InProgress if state.burst == Burst::Eof => {
+                state.acknowledge(state.file.size);
+                state.end_file(FileStatus::Transferred);
+                write_next_file(port, state)?;
+            }
+            // A repeated `ZRINIT` before `ZEOF` after a timeout means that
+            // the receiver has timed out waiting for the data. `ZFILE` is
+            // re-sent, and the receiver answers it with its current `ZRPOS`.
+            // Otherwise, it is a stale duplicate. In `Stage::Ready`, `ZFILE`
+            // has been already repeated after the timeout.
+            Stage::InProgress if timeout => {
+                let transport = state.negotiate_transport();
+                write_zfile(port, state, transport)?;
+            }
+            Stage::Ready | Stage::InProgress | Stage::Done => (),
         },
         Frame::ZRPOS | Frame::ZACK => match state.stage {
-            Stage::Waiting => ZRQINIT_HEADER.write(port)?,
+            Stage::Waiting => ZRQINIT_HEADER.write_with(port, &state.escape())?,
             Stage::Ready | Stage::InProgress => {
-                write_zdata(port, &mut state.buf, file, frame.count())?;
+                state.acknowledge(frame.count());
+                let mut offset = state.restart_offset(&frame);
+                if frame.frame() == Frame::ZRPOS && state.stage == Stage::InProgress {
+                    state.summary.retries += 1;
+                }
+                // Resynchronize, when `ZACK` disagrees with the end of the
+                // burst. The data past the end has not been sent yet.
+                if let (Frame::ZACK, Burst::Complete { end }) = (frame.frame(), state.burst) {
+                    if offset != end {
+                        state.summary.retries += 1;
+                        offset = offset.min(end);
+                    }
+                }
+                state.advance(offset);
+                write_zdata(port, state, file, offset, 0)?;
                 state.stage = Stage::InProgress;
             }
             Stage::Done => (),
         },
+        Frame::ZSKIP => match state.stage {
+            Stage::Waiting => ZRQINIT_HEADER.write_with(port, &state.escape())?,
+            Stage::Ready => {
+                state.end_file(FileStatus::Skipped);
+                write_next_file(port, state)?;
+            }
+            Stage::InProgress | Stage::Done => (),
+        },
         Frame::ZFIN => match state.stage {
-            Stage::Waiting => ZRQINIT_HEADER.write(port)?,
-            Stage::InProgress => {
+            Stage::Waiting => ZRQINIT_HEADER.write_with(port, &state.escape())?,
+            Stage::Ready | Stage::InProgress => {
                 port.write_byte(b'O')?;
                 port.write_byte(b'O')?;
-                state.stage = Stage::Done;
+                drain(port, state)?;
+                state.end_file(FileStatus::Incomplete);
+                state.finish();
             }
-            Stage::Ready | Stage::Done => (),
+            Stage::Done => (),
         },
+        Frame::ZNAK => {
+            state.summary.retries += 1;
+            if state.stage == Stage::Waiting {
+                ZRQINIT_HEADER.write_with(port, &state.escape())?;
+            }
+        }
+        // The receiver verifies the sender by a random number, which is
+        // echoed back:
+        Frame::ZCHALLENGE => ZACK_HEADER
+            .with_count(frame.count())
+            .write_with(port, &state.escape())?,
         _ => {
             if state.stage == Stage::Waiting {
-                ZRQINIT_HEADER.write(port)?;
+                ZRQINIT_HEADER.write_with(port, &state.escape())?;
             }
         }
     }
     Ok(())
 }
 
-/// Receives a file using the ZMODEM file transfer protocol.
+/// Listens to the peer for a step, and determines the role matching the one
+/// started on the far end: `Role::Receiver`, when the peer is trying to send,
+/// i.e. `ZRQINIT` or the `rz\r` preceding it has been seen, or `Role::Sender`,
+/// when the peer is trying to receive, i.e. `ZRINIT` has been seen. `None` is
+/// returned, when neither has been seen yet, and the call should be repeated.
+///
+/// The session continues with `zmodem2::receive` or `zmodem2::send` using the
+/// same state, which announces this side to the peer, and thus the header
+/// consumed here is answered by a repeated one. The port is flushed at the
+/// end of each call.
 ///
 /// # Errors
 ///
 /// * `Err(Error::Read)` when the read I/O fails with the serial port
 /// * `Err(Error::Write)` when the write I/O fails with the serial port
-/// * `Err(Error::Data)` when corrupted data has been detected
-pub fn receive<P, F>(port: &mut P, file: &mut F, state: &mut State) -> Result<(), Error>
+/// * `Err(Error::Quota)` when the peer input exceeded a quota set by
+///   `State::set_quotas`
+pub fn negotiate<P>(port: &mut P, state: &mut State<'_>) -> Result<Option<Role>, Error>
 where
     P: Read + Write,
-    F: Write,
 {
-    if state.stage == Stage::Waiting {
-        write_zrinit(port)?;
-    }
-    if read_zpad(port).is_err() {
-        return Ok(());
-    }
-    let header = match Header::read(port) {
-        Err(_) => {
-            ZNAK_HEADER.write(port)?;
-            return Ok(());
+    state.link_event = None;
+    let mut sniffer = Sniffer::new(port);
+    let result =
+        read_frame(&mut sniffer, state).map(|header| match header.map(|header| header.frame()) {
+            Some(Frame::ZRINIT) => Some(Role::Sender),
+            Some(Frame::ZRQINIT) => Some(Role::Receiver),
+            _ if sniffer.seen => Some(Role::Receiver),
+            _ => None,
+        });
+    let flushed = port.flush();
+    state.record_error(result.and_then(|role| flushed.map(|()| role)))
+}
+
+/// Port, which watches the bytes read for `rz\r` sent by a sender, which
+/// starts the receiver in a shell
+struct Sniffer<'p, P> {
+    port: &'p mut P,
+    matched: usize,
+    seen: bool,
+}
+
+impl<'p, P> Sniffer<'p, P> {
+    /// The command preceding `ZRQINIT`
+    const COMMAND: &'static [u8] = b"rz\r";
+
+    fn new(port: &'p mut P) -> Self {
+        Self {
+            port,
+            matched: 0,
+            seen: false,
+        }
+    }
+
+    fn sniff(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.matched = if *b == Self::COMMAND[self.matched] {
+                self.matched + 1
+            } else {
+                usize::from(*b == Self::COMMAND[0])
+            };
+            if self.matched == Self::COMMAND.len() {
+                self.seen = true;
+                self.matched = 0;
+            }
         }
-        Ok(header) => header,
+    }
+}
+
+impl<P> Read for Sniffer<'_, P>
+where
+    P: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let len = self.port.read(buf)?;
+        self.sniff(&buf[..len as usize]);
+        Ok(len)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let byte = self.port.read_byte()?;
+        self.sniff(&[byte]);
+        Ok(byte)
+    }
+}
+
+impl<P> Write for Sniffer<'_, P>
+where
+    P: Write,
+{
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.port.write_all(buf)
+    }
+}
+
+/// Receives a file using the ZMODEM file transfer protocol. The port is
+/// flushed at the end of each call.
+///
+/// # Errors
+///
+/// * `Err(Error::Read)` when the read I/O fails with the serial port
+/// * `Err(Error::Write)` when the write I/O fails with the serial port
+/// * `Err(Error::Data)` when corrupted data has been detected
+/// * `Err(Error::Cancelled)` when the session has been cancelled
+/// * `Err(Error::Quota)` when the peer input exceeded a quota set by
+///   `State::set_quotas`
+pub fn receive<P, F>(port: &mut P, file: &mut F, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Read + Write,
+    F: Write,
+{
+    receive_sink(port, &mut Plain(file), state)
+}
+
+/// Receives a file using the ZMODEM file transfer protocol similarly to
+/// `zmodem2::receive`, but calls `Sink::begin` of `sink` when each file of the
+/// batch has been accepted, e.g. in order to start a new entry of a `TarSink`.
+///
+/// # Errors
+///
+/// * `Err(Error::Read)` when the read I/O fails with the serial port
+/// * `Err(Error::Write)` when the write I/O fails with the serial port
+/// * `Err(Error::Data)` when corrupted data has been detected
+/// * `Err(Error::Cancelled)` when the session has been cancelled
+/// * `Err(Error::Quota)` when the peer input exceeded a quota set by
+///   `State::set_quotas`
+pub fn receive_sink<P, S>(port: &mut P, sink: &mut S, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Read + Write,
+    S: Sink,
+{
+    let result = receive_step(port, sink, state);
+    let flushed = port.flush();
+    state.record_error(result.and(flushed))
+}
+
+fn receive_step<P, F>(port: &mut P, file: &mut F, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Read + Write,
+    F: Sink,
+{
+    state.start(Role::Receiver);
+    if state.cancelled() {
+        return write_abort(port, state);
+    }
+    let duration = state.quotas.and_then(|quotas| quotas.duration);
+    if duration.is_some_and(|limit| state.summary().duration().is_some_and(|ms| ms > limit)) {
+        return Err(Error::Quota(Quota::Duration));
+    }
+    // Continue the frame paused by the step budget:
+    if let Some(encoding) = state.zdata.take() {
+        return read_zdata(port, state, encoding, file);
+    }
+    // Repeat `ZRINIT`, unless the previous call received a header:
+    let timeout = state.frame.take().is_none();
+    if state.stage == Stage::Waiting && timeout {
+        write_zrinit(port, state)?;
+    }
+    let Some(header) = read_frame(port, state)? else {
+        return Ok(());
     };
+    state.frame = Some(header.frame());
     match header.frame() {
         Frame::ZFILE => match state.stage {
-            Stage::Waiting | Stage::Ready => {
-                read_zfile(port, state, header.encoding())?;
-                state.stage = Stage::Ready;
+            Stage::Waiting | Stage::Ready | Stage::InProgress => {
+                read_zfile(port, state, &header, file)?;
             }
-            Stage::InProgress | Stage::Done => (),
+            Stage::Done => (),
         },
         Frame::ZDATA => match state.stage {
-            Stage::Waiting => write_zrinit(port)?,
+            Stage::Waiting => write_zrinit(port, state)?,
             Stage::Ready | Stage::InProgress => {
-                if header.count() != state.count {
-                    ZRPOS_HEADER.with_count(state.count).write(port)?;
-                    return Ok(());
+                if header.count() > state.count {
+                    return write_zrpos(port, state);
                 }
+                // The sender has restarted, and the data preceding the
+                // current position has been already received, and is
+                // discarded:
+                state.repositioned = None;
+                state.overlap = state.count - header.count();
+                state.zdata_subpackets = 0;
                 read_zdata(port, state, header.encoding(), file)?;
                 state.stage = Stage::InProgress;
             }
             Stage::Done => (),
         },
         Frame::ZEOF => match state.stage {
-            Stage::InProgress => {
+            Stage::Ready | Stage::InProgress => {
                 if header.count() == state.count {
-                    write_zrinit(port)?;
+                    write_staged(state, file)?;
+                    if let Some(mut converter) = state.converter.take() {
+                        converter.finish(file)?;
+                    }
+                    file.flush()?;
+                    state.save_resume()?;
+                    state.end_file(FileStatus::Transferred);
+                    // Wait for the next file in the batch:
+                    state.count = 0;
+                    state.stage = Stage::Waiting;
+                    write_zrinit(port, state)?;
                 }
             }
-            Stage::Waiting | Stage::Ready | Stage::Done => (),
+            Stage::Waiting | Stage::Done => (),
+        },
+        Frame::ZCOMMAND => match state.stage {
+            Stage::Waiting if state.command.is_some() => read_zcommand(port, state, &header)?,
+            Stage::Waiting | Stage::Ready | Stage::InProgress | Stage::Done => (),
+        },
+        // The sender timed out waiting for `ZRINIT`. The progress is kept, as
+        // the sender answers with `ZFILE`, which is followed by `ZRPOS`.
+        Frame::ZRQINIT => match state.stage {
+            // `ZRINIT` was already sent in the beginning of the call:
+            Stage::Waiting if timeout => (),
+            Stage::Waiting | Stage::Ready | Stage::InProgress => write_zrinit(port, state)?,
+            Stage::Done => (),
         },
         Frame::ZFIN => match state.stage {
-            Stage::InProgress => {
-                ZFIN_HEADER.write(port)?;
-                state.stage = Stage::Done;
+            Stage::Waiting | Stage::InProgress => {
+                write_staged(state, file)?;
+                ZFIN_HEADER.write_with(port, &state.escape())?;
+                state.end_file(FileStatus::Incomplete);
+                read_oo(port);
+                drain(port, state)?;
+                state.finish();
             }
-            Stage::Waiting | Stage::Ready | Stage::Done => (),
+            Stage::Ready | Stage::Done => (),
         },
         _ => (),
     }
     Ok(())
 }
 
+/// Consumes the trailing CRLF of the `ZFIN` header, and the "OO" (over and
+/// out) sent by the sender as the answer to `ZFIN`, so that the port can be
+/// used for the next session. The sequence is optional, and thus a read error
+/// is ignored.
+fn read_oo<P>(port: &mut P)
+where
+    P: Read,
+{
+    let mut count = 0;
+    while count < 2 {
+        match port.read_byte() {
+            Ok(b'O') => count += 1,
+            Ok(b'\r' | b'\n' | 0x8d | 0x8a) if count == 0 => (),
+            _ => return,
+        }
+    }
+}
+
+/// Flushes the output of the closing handshake, and consumes the residual
+/// input until a read times out, when enabled by `State::set_drain`
+fn drain<P>(port: &mut P, state: &State<'_>) -> Result<(), Error>
+where
+    P: Read + Write,
+{
+    if !state.drain {
+        return Ok(());
+    }
+    port.flush()?;
+    for _ in 0..MAX_GARBAGE {
+        if port.read_byte().is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Offers the next file in the batch, or finishes the session with `ZFIN`
+/// when there are no files left
+fn write_next_file<P>(port: &mut P, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Write,
+{
+    match state.next_file.take() {
+        Some(file) => {
+            state.file = file;
+            state.count = 0;
+            let transport = state.negotiate_transport();
+            write_zfile(port, state, transport)?;
+            state.begin_file();
+            state.stage = Stage::Ready;
+            state.zfile_retries = Some(0);
+            Ok(())
+        }
+        None => ZFIN_HEADER.write_with(port, &state.escape()),
+    }
+}
+
+/// Announces the session with `ZRQINIT` after a timeout according to the
+/// policy set with `State::set_announce`, and finishes the session after the
+/// announcements allowed by `Announce::Limited`
+fn announce<P>(port: &mut P, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Write,
+{
+    match state.announce {
+        Announce::Passive => Ok(()),
+        Announce::Limited(max) if state.announcements >= max => {
+            state.finish();
+            Err(Error::Unanswered)
+        }
+        Announce::Repeat | Announce::Limited(_) => {
+            state.announcements += 1;
+            ZRQINIT_HEADER.write_with(port, &state.escape())
+        }
+    }
+}
+
+/// Repeats `ZFILE` after a timeout, until the receiver answers it with
+/// `ZRPOS`, `ZSKIP` or `ZCRC`, and finishes the session after the retries
+fn repeat_zfile<P>(port: &mut P, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Write,
+{
+    let Some(retries) = state.zfile_retries else {
+        return Ok(());
+    };
+    if retries == MAX_ZFILE_RETRIES {
+        state.zfile_retries = None;
+        state.end_file(FileStatus::Incomplete);
+        state.finish();
+        return Err(Error::Unanswered);
+    }
+    state.zfile_retries = Some(retries + 1);
+    state.summary.retries += 1;
+    let transport = state.negotiate_transport();
+    write_zfile(port, state, transport)
+}
+
+/// Writes the abort sequence, and finishes the session
+fn write_abort<P>(port: &mut P, state: &mut State<'_>) -> Result<(), Error>
+where
+    P: Write,
+{
+    port.write_all(&ABORT)?;
+    state.end_file(FileStatus::Incomplete);
+    state.finish();
+    Err(Error::Cancelled)
+}
+
 /// Writes ZRINIT
-fn write_zrinit<P>(port: &mut P) -> Result<(), Error>
+fn write_zrinit<P>(port: &mut P, state: &State<'_>) -> Result<(), Error>
+where
+    P: Write,
+{
+    let mut zrinit = Zrinit::CANFDX | Zrinit::CANOVIO;
+    if !state.crc16 {
+        zrinit |= Zrinit::CANFC32;
+    }
+    if state.parity {
+        zrinit |= Zrinit::ESC8;
+    }
+    if let Some(codec) = state.codec.as_ref() {
+        zrinit |= codec.transport().capability();
+    }
+    ZRINIT_HEADER
+        .with_zrinit(zrinit)
+        .write_with(port, &state.escape())
+}
+
+/// Writes `ZRPOS` requesting a restart from the current position. A request
+/// repeating the previous one is suppressed until the line has drained, as
+/// the data in flight before the sender restarts would otherwise answer each
+/// with a new request, and congest a half-duplex link.
+fn write_zrpos<P>(port: &mut P, state: &mut State<'_>) -> Result<(), Error>
 where
     P: Write,
 {
-    let zrinit = Zrinit::CANFDX | Zrinit::CANOVIO | Zrinit::CANFC32;
-    Header::new(Encoding::ZHEX, Frame::ZRINIT, &[0, 0, 0, zrinit.bits()]).write(port)
+    if state.repositioned == Some(state.count) {
+        state.summary.suppressed_repositions += 1;
+        return Ok(());
+    }
+    state.repositioned = Some(state.count);
+    state.summary.retries += 1;
+    ZRPOS_HEADER
+        .with_count(state.count)
+        .write_with(port, &state.escape())
 }
 
 /// Write ZRFILE
-fn write_zfile<P>(port: &mut P, buf: &mut Buffer, name: &str, size: u32) -> Result<(), Error>
+fn write_zfile<P>(port: &mut P, state: &mut State<'_>, transport: u8) -> Result<(), Error>
 where
     P: Write,
 {
-    let size = String::<17>::try_from(size).or(Err(Error::Data))?;
+    let size = String::<17>::try_from(state.file.size).or(Err(Error::Data))?;
+    let encoding = state.data_encoding();
+    let escape = state.escape();
+    let buf = &mut state.buf;
     buf.clear();
-    buf.extend_from_slice(name.as_bytes());
+    buf.extend_from_slice(state.file.name.as_bytes());
     buf.push(b'\0');
     buf.extend_from_slice(size.as_ref());
     buf.push(b'\0');
-    Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4]).write(port)?;
-    write_subpacket(port, Encoding::ZBIN32, Packet::ZCRCW, buf)
+    Header::new(encoding, Frame::ZFILE, &[0; 4])
+        .with_zf(&[0, 0, transport, 0])
+        .write_with(port, &escape)?;
+    write_subpacket(port, encoding, Packet::ZCRCW, buf, &escape)
+}
+
+/// Parses the name and the fields of the `ZFILE` subpacket
+fn parse_zfile(payload: &str) -> Result<FileInfo, Error> {
+    let mut file = FileInfo::new();
+    for (i, field) in payload.split('\0').enumerate() {
+        if i == 0 {
+            file.name = String::from_str(field).or(Err(Error::Data))?;
+        }
+        if i == 1 {
+            let mut fields = field.split_ascii_whitespace();
+            if let Some(field) = fields.next() {
+                file.size = u32::from_str(field).or(Err(Error::Data))?;
+            }
+            // The informational fields keep their defaults, when they are
+            // malformed, e.g. a decimal time sent by another implementation:
+            if let Some(field) = fields.next() {
+                file.mtime = u32::from_str_radix(field, 8).unwrap_or(0);
+            }
+            if let Some(field) = fields.next() {
+                file.mode = u32::from_str_radix(field, 8).unwrap_or(0);
+            }
+            // Skip the serial number:
+            fields.next();
+            if let Some(field) = fields.next() {
+                file.files_remaining = u32::from_str(field).ok();
+            }
+            if let Some(field) = fields.next() {
+                file.bytes_remaining = u64::from_str(field).ok();
+            }
+        }
+    }
+    Ok(file)
 }
 
 /// Parses filename and size from the subpacket sent after the `Frame::ZFiLE`
-/// header.
-fn read_zfile<P>(port: &mut P, state: &mut State, encoding: Encoding) -> Result<(), Error>
+/// header, and answers either with `ZRPOS` or `ZSKIP`. An accepted file is
+/// begun in `sink`.
+fn read_zfile<P, S>(
+    port: &mut P,
+    state: &mut State<'_>,
+    header: &Header,
+    sink: &mut S,
+) -> Result<(), Error>
 where
     P: Read + Write,
+    S: Sink,
 {
-    match read_subpacket(port, &mut state.buf, encoding) {
-        Ok(_) => {
-            let payload = core::str::from_utf8(state.buf.as_slice()).or(Err(Error::Data))?;
-            for (i, field) in payload.split('\0').enumerate() {
-                if i == 0 {
-                    state.file_name = String::from_str(field).or(Err(Error::Data))?;
-                }
-                if i == 1 {
-                    if let Some(field) = field.split_ascii_whitespace().next() {
-                        state.file_size = u32::from_str(field).or(Err(Error::Data))?;
-                    }
-                }
+    if read_subpacket_with(port, state, header.encoding(), true).is_err() {
+        state.reject(NakReason::Subpacket, Some(header), &[]);
+        return ZNAK_HEADER.write_with(port, &state.escape());
+    }
+    let payload = core::str::from_utf8(state.buf.as_slice()).or(Err(Error::Data))?;
+    let mut file = parse_zfile(payload)?;
+    // The file has been already accepted, and the answer was lost, or the
+    // sender timed out waiting for the data:
+    if state.stage != Stage::Waiting {
+        return ZRPOS_HEADER
+            .with_count(state.count)
+            .write_with(port, &state.escape());
+    }
+    state.offer_batch(&file);
+    // The file cannot be decoded without a matching codec:
+    let transport = header.zf()[2];
+    state.codec_active = transport != 0;
+    let supported = transport == 0
+        || state
+            .codec
+            .as_ref()
+            .is_some_and(|codec| codec.transport().option() == transport);
+    if let Some(storage) = state.dry_run.as_mut() {
+        if let Some(entry) = storage.get_mut(state.dry_run_len) {
+            entry.clone_from(&file);
+            state.dry_run_len += 1;
+        }
+    }
+    if let Some((policy, _)) = state.name_policy.as_ref() {
+        file.name = policy.apply(&file.name)?;
+    }
+    // An incomplete file is resumed from the stored offset:
+    let record = state
+        .resume
+        .as_mut()
+        .and_then(|store| store.load())
+        .filter(|record| {
+            record.name == file.name && record.size == file.size && !record.is_complete()
+        });
+    // Otherwise, an existing file is not replaced:
+    let mut available = true;
+    if let (Some((policy, exists)), None) = (state.name_policy.as_mut(), record.as_ref()) {
+        let quota = state.quotas.map(|quotas| quotas.alternatives);
+        match resolve(*policy, &file.name, *exists, quota)? {
+            Some(name) => file.name = name,
+            None => available = false,
+        }
+    }
+    let decision = match state.pre_accept.as_mut() {
+        _ if !supported || !available || state.dry_run.is_some() => Decision::Skip,
+        Some(callback) => callback(&file),
+        None => Decision::Accept(record.as_ref().map_or(state.count, ResumeRecord::offset)),
+    };
+    match decision {
+        Decision::Accept(offset) => state.count = offset,
+        Decision::Rename(name) => {
+            file.name = name;
+            state.count = 0;
+        }
+        Decision::Skip => {
+            state.file = file;
+            state.begin_file();
+            state.end_file(FileStatus::Skipped);
+            return ZSKIP_HEADER.write_with(port, &state.escape());
+        }
+    }
+    state.file = file;
+    state.converter = match state.newline {
+        Some(newline) if header.zf()[0] == ZCNL => Some(Converter::new(newline)),
+        _ => None,
+    };
+    state.resume_crc = match record {
+        Some(record) if record.offset == state.count => record.crc,
+        _ if state.count == 0 => Some(0),
+        _ => None,
+    };
+    state.save_resume()?;
+    sink.begin(&state.file)?;
+    state.begin_file();
+    state.stage = Stage::Ready;
+    ZRPOS_HEADER
+        .with_count(state.count)
+        .write_with(port, &state.escape())
+}
+
+/// Reads the command from the subpacket sent after the `Frame::ZCOMMAND`
+/// header, and answers with `ZCOMPL` carrying the status from the handler.
+fn read_zcommand<P>(port: &mut P, state: &mut State<'_>, header: &Header) -> Result<(), Error>
+where
+    P: Read + Write,
+{
+    if read_subpacket_with(port, state, header.encoding(), true).is_err() {
+        state.reject(NakReason::Subpacket, Some(header), &[]);
+        return ZNAK_HEADER.write_with(port, &state.escape());
+    }
+    let escape = state.escape();
+    let Some(handler) = state.command.as_mut() else {
+        return Ok(());
+    };
+    let command = state.buf.split(|b| *b == 0).next().unwrap_or_default();
+    // The sender does not wait for the command to finish:
+    if header.zf()[0] & ZCACK1 != 0 {
+        ZCOMPL_HEADER.write_with(port, &escape)?;
+        handler(command);
+        return Ok(());
+    }
+    let status = handler(command);
+    ZCOMPL_HEADER.with_count(status).write_with(port, &escape)
+}
+
+/// Writes ZDATA
+fn write_zdata<P, F>(
+    port: &mut P,
+    state: &mut State<'_>,
+    file: &mut F,
+    offset: u32,
+    sent: usize,
+) -> Result<(), Error>
+where
+    P: Read + Write,
+    F: Source,
+{
+    let mut buf = core::mem::take(&mut state.buf);
+    let per_ack = state.subpackets_per_ack();
+    let budget = state.budget;
+    let escape = state.escape();
+    let encoding = state.data_encoding();
+    let mut summary = state.summary;
+    let codec = state.active_codec();
+    let result = write_zdata_burst(
+        port,
+        &mut buf,
+        codec,
+        file,
+        offset,
+        sent,
+        per_ack,
+        budget,
+        encoding,
+        &escape,
+        &mut summary,
+    );
+    state.buf = buf;
+    state.summary = summary;
+    state.burst = result?;
+    Ok(())
+}
+
+/// Writes a burst of subpackets from `offset`, or ZEOF at the end of file.
+/// When `sent` subpackets have been already written, a paused burst is
+/// continued without a header. The burst ends with `ZCRCW` after `per_ack`
+/// subpackets. At most `budget` subpackets are written, unless it is zero.
+#[allow(clippy::too_many_arguments)]
+fn write_zdata_burst<P, F>(
+    port: &mut P,
+    buf: &mut Buffer,
+    codec: Option<&mut dyn Codec>,
+    file: &mut F,
+    offset: u32,
+    sent: usize,
+    per_ack: usize,
+    budget: usize,
+    encoding: Encoding,
+    escape: &EscapeSet,
+    summary: &mut TransferSummary,
+) -> Result<Burst, Error>
+where
+    P: Read + Write,
+    F: Source,
+{
+    // Leave room for the data added by the codec:
+    let overhead = codec.as_ref().map_or(0, |codec| codec.overhead());
+    let chunk = (BUFFER_SIZE - 2).saturating_sub(overhead).max(1);
+    // A file held in memory is sent without copying, and a file escaped
+    // ahead without escaping, unless it is encoded:
+    if codec.is_none() {
+        if let Some(data) = file.contents() {
+            let mut chunks = SliceChunks {
+                data,
+                offset: offset as usize,
+            };
+            return write_zdata_chunks(
+                port,
+                buf,
+                &mut chunks,
+                chunk,
+                offset,
+                sent,
+                per_ack,
+                budget,
+                encoding,
+                escape,
+                summary,
+            );
+        }
+        if let Some(source) = file.escaped() {
+            let mut chunks = EscapedChunks {
+                source,
+                offset,
+                encoding,
+                escape,
+            };
+            return write_zdata_chunks(
+                port,
+                buf,
+                &mut chunks,
+                chunk,
+                offset,
+                sent,
+                per_ack,
+                budget,
+                encoding,
+                escape,
+                summary,
+            );
+        }
+    }
+    file.seek(offset)?;
+    let mut chunks = FileChunks { file, codec };
+    write_zdata_chunks(
+        port,
+        buf,
+        &mut chunks,
+        chunk,
+        offset,
+        sent,
+        per_ack,
+        budget,
+        encoding,
+        escape,
+        summary,
+    )
+}
+
+/// Writes a burst of subpackets of at most `chunk` bytes from `chunks`
+#[allow(clippy::too_many_arguments)]
+fn write_zdata_chunks<P, C>(
+    port: &mut P,
+    buf: &mut Buffer,
+    chunks: &mut C,
+    chunk: usize,
+    mut offset: u32,
+    mut sent: usize,
+    per_ack: usize,
+    budget: usize,
+    encoding: Encoding,
+    escape: &EscapeSet,
+    summary: &mut TransferSummary,
+) -> Result<Burst, Error>
+where
+    P: Read + Write,
+    C: Chunks,
+{
+    let mut header = sent == 0;
+    for written in 1.. {
+        let (count, data) = chunks.next(buf, chunk)?;
+        if header {
+            if count == 0 {
+                Header::new(encoding, Frame::ZEOF, &[0; 4])
+                    .with_count(offset)
+                    .write_with(port, escape)?;
+                return Ok(Burst::Eof);
+            }
+            Header::new(encoding, Frame::ZDATA, &[0; 4])
+                .with_count(offset)
+                .write_with(port, escape)?;
+            header = false;
+        }
+        sent += 1;
+        if sent == per_ack || (count as usize) < chunk {
+            write_chunk(
+                port,
+                (encoding, Packet::ZCRCW),
+                data,
+                count,
+                escape,
+                summary,
+            )?;
+            let end = offset.checked_add(count).ok_or(Error::Data)?;
+            return Ok(Burst::Complete { end });
+        }
+        write_chunk(
+            port,
+            (encoding, Packet::ZCRCG),
+            data,
+            count,
+            escape,
+            summary,
+        )?;
+        offset = offset.checked_add(count).ok_or(Error::Data)?;
+        if written == budget {
+            return Ok(Burst::Paused { offset, sent });
+        }
+    }
+    Err(Error::Data)
+}
+
+/// Writes a subpacket of `kind` with the data of `chunk` holding `count` file
+/// bytes, and records the bytes added by escaping to `summary`
+fn write_chunk<P>(
+    port: &mut P,
+    (encoding, kind): (Encoding, Packet),
+    chunk: Chunk<'_>,
+    count: u32,
+    escape: &EscapeSet,
+    summary: &mut TransferSummary,
+) -> Result<(), Error>
+where
+    P: Write,
+{
+    let mut port = Counter::new(port);
+    let len = match chunk {
+        Chunk::Raw(data) => {
+            write_subpacket(&mut port, encoding, kind, data, escape)?;
+            data.len()
+        }
+        Chunk::Escaped(data, crc) => {
+            port.write_all(data)?;
+            port.write_byte(ZDLE)?;
+            port.write_byte(kind as u8)?;
+            if encoding == Encoding::ZBIN32 {
+                let crc = update_crc(crc, &[kind as u8]).to_le_bytes();
+                write_slice_escaped(&mut port, &crc, escape)?;
+            } else {
+                // The CRC-16 has been returned in the lower half:
+                #[allow(clippy::cast_possible_truncation)]
+                let mut digest = CRC16.digest_with_initial(crc as u16);
+                digest.update(&[kind as u8]);
+                write_slice_escaped(&mut port, &digest.finalize().to_be_bytes(), escape)?;
+            }
+            count as usize
+        }
+    };
+    // The data, the `ZDLE` preceding the type, the type and the CRC:
+    let unescaped = len + if encoding == Encoding::ZBIN32 { 6 } else { 4 };
+    summary.subpacket_bytes += unescaped as u64;
+    summary.escapes += port.count.saturating_sub(unescaped) as u64;
+    Ok(())
+}
+
+/// Writer, which counts the bytes written to the port
+struct Counter<'p, P> {
+    port: &'p mut P,
+    count: usize,
+}
+
+impl<'p, P> Counter<'p, P> {
+    fn new(port: &'p mut P) -> Self {
+        Self { port, count: 0 }
+    }
+}
+
+impl<P> Write for Counter<'_, P>
+where
+    P: Write,
+{
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.port.write_all(buf)?;
+        self.count += buf.len();
+        Ok(())
+    }
+
+    fn write_byte(&mut self, value: u8) -> Result<(), Error> {
+        self.port.write_byte(value)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.port.flush()
+    }
+}
+
+/// Data of a subpacket
+#[derive(Clone, Copy)]
+enum Chunk<'a> {
+    /// Data to be escaped
+    Raw(&'a [u8]),
+    /// Escaped data, and the CRC of the data before escaping
+    Escaped(&'a [u8], u32),
+}
+
+/// Chunks of the file data sent in a `ZDATA` burst
+trait Chunks {
+    /// Returns the number of file bytes in the next chunk of at most `len`
+    /// bytes, and the data to be sent
+    fn next<'s>(&'s mut self, buf: &'s mut Buffer, len: usize) -> Result<(u32, Chunk<'s>), Error>;
+}
+
+/// Chunks read from a file to the buffer, and encoded with the codec, if any
+struct FileChunks<'c, 'f, F> {
+    file: &'f mut F,
+    codec: Option<&'c mut dyn Codec>,
+}
+
+impl<F> Chunks for FileChunks<'_, '_, F>
+where
+    F: Read,
+{
+    fn next<'s>(&'s mut self, buf: &'s mut Buffer, len: usize) -> Result<(u32, Chunk<'s>), Error> {
+        buf.set_len(len);
+        let count = read_full(self.file, buf)?;
+        let len = encode(&mut self.codec, buf, count)?;
+        Ok((count, Chunk::Raw(&buf[..len])))
+    }
+}
+
+/// Chunks borrowed from the contents of a file held in memory
+struct SliceChunks<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Chunks for SliceChunks<'_> {
+    fn next<'s>(&'s mut self, _: &'s mut Buffer, len: usize) -> Result<(u32, Chunk<'s>), Error> {
+        let data = self.data.get(self.offset..).unwrap_or_default();
+        let data = &data[..len.min(data.len())];
+        self.offset += data.len();
+        Ok((
+            u32::try_from(data.len()).or(Err(Error::Data))?,
+            Chunk::Raw(data),
+        ))
+    }
+}
+
+/// Chunks escaped ahead by an `EscapedSource`
+struct EscapedChunks<'a> {
+    source: &'a mut dyn EscapedSource,
+    offset: u32,
+    encoding: Encoding,
+    escape: &'a EscapeSet,
+}
+
+impl Chunks for EscapedChunks<'_> {
+    fn next<'s>(&'s mut self, _: &'s mut Buffer, len: usize) -> Result<(u32, Chunk<'s>), Error> {
+        let (count, data, crc) =
+            self.source
+                .next_escaped(self.offset, len, self.encoding, self.escape)?;
+        self.offset = self.offset.checked_add(count).ok_or(Error::Data)?;
+        Ok((count, Chunk::Escaped(data, crc)))
+    }
+}
+
+/// Encodes `len` bytes in the beginning of `buf` with `codec`, if any, and
+/// returns the length of the encoded data
+fn encode(codec: &mut Option<&mut dyn Codec>, buf: &mut Buffer, len: u32) -> Result<usize, Error> {
+    let len = len as usize;
+    let Some(codec) = codec else {
+        return Ok(len);
+    };
+    buf.set_len(BUFFER_SIZE - 2);
+    match codec.encode(buf, len)? {
+        len if len <= buf.len() => Ok(len),
+        _ => Err(Error::Data),
+    }
+}
+
+/// Decodes the received subpacket with the codec, if it is enabled for the
+/// current file
+fn decode(state: &mut State<'_>) -> Result<(), Error> {
+    let mut buf = core::mem::take(&mut state.buf);
+    let len = buf.len();
+    buf.set_len(BUFFER_SIZE);
+    let result = match state.active_codec() {
+        Some(codec) => codec.decode(&mut buf, len),
+        None => Ok(len),
+    };
+    state.buf = buf;
+    match result? {
+        len if len <= BUFFER_SIZE => {
+            state.buf.set_len(len);
+            Ok(())
+        }
+        _ => Err(Error::Data),
+    }
+}
+
+/// Reads ZDATA
+fn read_zdata<P, F>(
+    port: &mut P,
+    state: &mut State<'_>,
+    encoding: Encoding,
+    file: &mut F,
+) -> Result<(), Error>
+where
+    P: Read + Write,
+    F: Write,
+{
+    for subpackets in 1.. {
+        if state.cancelled() {
+            return write_abort(port, state);
+        }
+        state.zdata_subpackets += 1;
+        let limit = state.quotas.and_then(|quotas| quotas.subpackets);
+        if limit.is_some_and(|limit| state.zdata_subpackets > limit) {
+            return Err(Error::Quota(Quota::Subpackets));
+        }
+        let zcrc = match read_subpacket_with(port, state, encoding, subpackets == 1) {
+            Ok(zcrc) => {
+                if state.buf.is_empty() {
+                    write_zrpos(port, state)?;
+                }
+                zcrc
+            }
+            Err(Error::Data) => {
+                let header = Header::new(encoding, Frame::ZDATA, &[0; 4]);
+                state.reject(NakReason::Subpacket, Some(&header), &[]);
+                ZNAK_HEADER
+                    .with_count(state.count)
+                    .write_with(port, &state.escape())?;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        if !state.buf.is_empty() {
+            decode(state)?;
+        }
+        if state.overlap > 0 {
+            let len = state.buf.len();
+            let skip = usize::try_from(state.overlap).map_or(len, |overlap| overlap.min(len));
+            state.buf.copy_within(skip.., 0);
+            state.buf.truncate(len - skip);
+            state.overlap -= u32::try_from(skip).map_err(|_| Error::Data)?;
+        }
+        let count = state.count;
+        let len = u32::try_from(state.buf.len()).map_err(|_| Error::Data)?;
+        let end = count.checked_add(len).ok_or(Error::Data)?;
+        stage_data(state, file)?;
+        state.advance(end);
+        if state.resume.is_some() {
+            state.resume_crc = state.resume_crc.map(|crc| update_crc(crc, &state.buf));
+            let interval = state.resume_interval;
+            if count.checked_div(interval) != state.count.checked_div(interval) {
+                write_staged(state, file)?;
+                file.flush()?;
+                state.save_resume()?;
+            }
+        }
+        if state.crosses_checkpoint(count, state.count) {
+            write_staged(state, file)?;
+            file.flush()?;
+            if let Some(checkpoint) = state.checkpoint.as_mut() {
+                checkpoint.reached = Some(state.count);
+            }
+        }
+        match zcrc {
+            Packet::ZCRCW => {
+                write_staged(state, file)?;
+                ZACK_HEADER
+                    .with_count(state.count)
+                    .write_with(port, &state.escape())?;
+                return Ok(());
+            }
+            Packet::ZCRCE => return write_staged(state, file),
+            Packet::ZCRCQ => {
+                ZACK_HEADER
+                    .with_count(state.count)
+                    .write_with(port, &state.escape())?;
+            }
+            Packet::ZCRCG => (),
+        }
+        if subpackets == state.budget {
+            state.zdata = Some(encoding);
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Writes the data of the received subpacket to `file`, or accumulates it to
+/// the storage set by `State::set_coalesced_writes`
+fn stage_data<F>(state: &mut State<'_>, file: &mut F) -> Result<(), Error>
+where
+    F: Write,
+{
+    let capacity = state.coalesce.as_ref().map_or(0, |storage| storage.len());
+    if state.coalesce_len + state.buf.len() > capacity {
+        write_staged(state, file)?;
+    }
+    match state.coalesce.as_mut() {
+        Some(storage) if state.buf.len() <= storage.len() => {
+            let len = state.coalesce_len;
+            storage[len..len + state.buf.len()].copy_from_slice(&state.buf);
+            state.coalesce_len += state.buf.len();
+            Ok(())
+        }
+        _ => write_file(&mut state.converter, file, &state.buf),
+    }
+}
+
+/// Writes the data accumulated by `stage_data` to `file`
+fn write_staged<F>(state: &mut State<'_>, file: &mut F) -> Result<(), Error>
+where
+    F: Write,
+{
+    let len = core::mem::take(&mut state.coalesce_len);
+    match state.coalesce.as_deref() {
+        Some(storage) if len > 0 => write_file(&mut state.converter, file, &storage[..len]),
+        _ => Ok(()),
+    }
+}
+
+/// Writes `data` to `file` through the newline converter, if any
+fn write_file<F>(converter: &mut Option<Converter>, file: &mut F, data: &[u8]) -> Result<(), Error>
+where
+    F: Write,
+{
+    match converter.as_mut() {
+        Some(converter) => converter.write(file, data),
+        None => file.write_all(data),
+    }
+}
+
+/// Skips at most `limit` bytes preceding the next header, and its (ZPAD,
+/// [ZPAD,] ZDLE) sequence, and returns the number of `ZPAD` characters.
+/// Exhausting `limit` fails with `Err(Error::Quota(Quota::Garbage))`, and a
+/// read failing after skipping some bytes with `Err(Error::Data)`.
+fn read_zpad<P>(port: &mut P, limit: usize) -> Result<usize, Error>
+where
+    P: Read,
+{
+    let mut pads = 0;
+    for skipped in 0..limit {
+        let b = match port.read_byte() {
+            Ok(b) => b,
+            Err(err) if skipped == 0 => return Err(err),
+            Err(_) => return Err(Error::Data),
+        };
+        match b {
+            ZPAD => pads += 1,
+            ZDLE if pads > 0 => return Ok(pads),
+            _ => pads = 0,
+        }
+    }
+    Err(Error::Quota(Quota::Garbage))
+}
+
+/// Reads a header preceded by `pads` `ZPAD` characters with the given
+/// conformance. When `crc_width` is set, a binary header is accepted also
+/// with the CRC of the other binary encoding.
+fn read_header<P>(
+    port: &mut P,
+    conformance: Conformance,
+    pads: usize,
+    crc_width: bool,
+) -> Result<Header, Error>
+where
+    P: Read,
+{
+    if conformance == Conformance::Lenient {
+        return Header::read_with(&mut Lenient::new(port), crc_width);
+    }
+    let header = Header::read_with(port, crc_width)?;
+    let hex = header.encoding() == Encoding::ZHEX;
+    if pads != if hex { 2 } else { 1 } {
+        return Err(Error::Data);
+    }
+    if hex && (port.read_byte()? != b'\r' || port.read_byte()? != b'\n') {
+        return Err(Error::Data);
+    }
+    Ok(header)
+}
+
+/// Reads a header, and answers a rejected header with `ZNAK`. Returns `None`,
+/// when no header was available, or it was rejected, and records the link
+/// condition.
+fn read_frame<P>(port: &mut P, state: &mut State<'_>) -> Result<Option<Header>, Error>
+where
+    P: Read + Write,
+{
+    let garbage = state.quotas.map_or(MAX_GARBAGE, |quotas| quotas.garbage);
+    let pads = if state.parity {
+        read_zpad(&mut Parity::new(port), garbage)
+    } else {
+        read_zpad(port, garbage)
+    };
+    let pads = match pads {
+        Ok(pads) => pads,
+        Err(Error::Read) => {
+            state.record_link_event(LinkEvent::Timeout);
+            return Ok(None);
+        }
+        Err(err @ Error::Quota(_)) if state.quotas.is_some() => return Err(err),
+        Err(_) => {
+            state.record_link_event(LinkEvent::Garbage);
+            return Ok(None);
+        }
+    };
+    let mut recorder = Recorder::new(port);
+    let limit = state.quotas.map_or(usize::MAX, |quotas| quotas.header);
+    let mut bounded = Bounded::new(&mut recorder, limit, Quota::Header);
+    let header = if state.parity {
+        read_header(
+            &mut Parity::new(&mut bounded),
+            state.conformance,
+            pads,
+            state.crc_width,
+        )
+    } else {
+        read_header(&mut bounded, state.conformance, pads, state.crc_width)
+    };
+    if let Err(err @ Error::Quota(_)) = header {
+        return Err(err);
+    }
+    if let Ok(header) = header {
+        // The first recorded byte is the encoding declared by the peer:
+        let declared = recorder.bytes.first().map(|b| b & 0x7f);
+        if declared != Some(header.encoding() as u8) {
+            state.summary.crc_width_mismatches += 1;
+        }
+        state.link_events_in_row = 0;
+        return Ok(Some(header));
+    }
+    state.record_link_event(LinkEvent::BadHeader);
+    state.reject(NakReason::Header, None, &recorder.bytes);
+    ZNAK_HEADER.write_with(port, &state.escape())?;
+    Ok(None)
+}
+
+/// Reader, which records the bytes read from the port for diagnostics
+struct Recorder<'p, P> {
+    port: &'p mut P,
+    bytes: ArrayBuf<HEADER_SIZE>,
+}
+
+impl<'p, P> Recorder<'p, P> {
+    fn new(port: &'p mut P) -> Self {
+        Self {
+            port,
+            bytes: ArrayBuf::new(),
+        }
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(self.bytes.capacity() - self.bytes.len());
+        self.bytes.extend_from_slice(&bytes[..len]);
+    }
+}
+
+impl<P> Read for Recorder<'_, P>
+where
+    P: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let len = self.port.read(buf)?;
+        self.record(&buf[..len as usize]);
+        Ok(len)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let byte = self.port.read_byte()?;
+        self.record(&[byte]);
+        Ok(byte)
+    }
+}
+
+/// Reads and unescapes a ZMODEM protocol subpacket
+fn read_subpacket<P>(port: &mut P, buf: &mut Buffer, encoding: Encoding) -> Result<Packet, Error>
+where
+    P: Read,
+{
+    buf.clear();
+    let result = loop {
+        let byte = port.read_byte()?;
+        if byte == ZDLE {
+            let byte = port.read_byte()?;
+            if let Ok(packet) = Packet::try_from(byte) {
+                buf.push(packet as u8);
+                break packet;
+            }
+            if byte == ZESC8 {
+                buf.push(read_byte_unescaped(port)? | 0x80);
+            } else {
+                buf.push(UNZDLE_TABLE[byte as usize]);
+            }
+        } else {
+            buf.push(byte);
+        }
+
+        if buf.len() == buf.capacity() {
+            let packet = skip_subpacket_tail(port, encoding)?;
+            buf.set_len(0);
+            return Ok(packet);
+        }
+    };
+
+    let crc_len = if encoding == Encoding::ZBIN32 { 4 } else { 2 };
+    let mut crc = [0u8; 4];
+    for b in crc.iter_mut().take(crc_len) {
+        *b = read_byte_unescaped(port)?;
+    }
+    check_crc(buf, &crc[..crc_len], encoding)?;
+
+    // Pop ZCRC
+    buf.pop().ok_or(Error::Data)?;
+    Ok(result)
+}
+
+/// Reads a subpacket tolerating the quirks of the peer. The padding of a
+/// `ZHEX` header is skipped, when the subpacket is the `first` one after it.
+fn read_subpacket_with<P>(
+    port: &mut P,
+    state: &mut State<'_>,
+    encoding: Encoding,
+    first: bool,
+) -> Result<Packet, Error>
+where
+    P: Read,
+{
+    if state.quirks.is_empty() {
+        return read_subpacket(port, &mut state.buf, encoding);
+    }
+    let padded = first && encoding == Encoding::ZHEX;
+    let mut port = Tolerant::new(port, state.quirks, padded);
+    read_subpacket(&mut port, &mut state.buf, encoding)
+}
+
+/// Skips the tail of the subpacket (including CRC).
+fn skip_subpacket_tail<P>(port: &mut P, encoding: Encoding) -> Result<Packet, Error>
+where
+    P: Read,
+{
+    let result;
+    loop {
+        let byte = port.read_byte()?;
+        if byte == ZDLE {
+            let byte = port.read_byte()?;
+            if let Ok(packet) = Packet::try_from(byte) {
+                result = packet;
+                break;
+            }
+        }
+    }
+    let crc_len = if encoding == Encoding::ZBIN32 { 4 } else { 2 };
+    for _ in 0..crc_len {
+        read_byte_unescaped(port)?;
+    }
+    Ok(result)
+}
+
+fn write_subpacket<P>(
+    port: &mut P,
+    encoding: Encoding,
+    kind: Packet,
+    data: &[u8],
+    escape: &EscapeSet,
+) -> Result<(), Error>
+where
+    P: Write,
+{
+    let kind = kind as u8;
+    write_slice_escaped(port, data, escape)?;
+    port.write_byte(ZDLE)?;
+    port.write_byte(kind)?;
+    match encoding {
+        Encoding::ZBIN32 => {
+            let mut digest = CRC32.digest();
+            digest.update(data);
+            digest.update(&[kind]);
+            write_slice_escaped(port, &digest.finalize().to_le_bytes(), escape)
+        }
+        // Subpackets following a ZHEX header are binary with CRC-16:
+        Encoding::ZBIN | Encoding::ZHEX => {
+            let mut digest = CRC16.digest();
+            digest.update(data);
+            digest.update(&[kind]);
+            write_slice_escaped(port, &digest.finalize().to_be_bytes(), escape)
+        }
+    }
+}
+
+fn check_crc(data: &[u8], crc: &[u8], encoding: Encoding) -> Result<(), Error> {
+    let mut crc2 = [0u8; 4];
+    let crc2_len = make_crc(data, &mut crc2, encoding);
+    if *crc == crc2[..crc2_len] {
+        Ok(())
+    } else {
+        Err(Error::Data)
+    }
+}
+
+fn make_crc(data: &[u8], out: &mut [u8], encoding: Encoding) -> usize {
+    if encoding == Encoding::ZBIN32 {
+        let crc = CRC32.checksum(data).to_le_bytes();
+        out[..4].copy_from_slice(&crc[..4]);
+        4
+    } else {
+        let crc = CRC16.checksum(data).to_be_bytes();
+        out[..2].copy_from_slice(&crc[..2]);
+        2
+    }
+}
+
+/// Encodes `data` as lower-case hexadecimal digits to `out`, which must be
+/// twice as long
+fn encode_hex(data: &[u8], out: &mut [u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for (byte, pair) in data.iter().zip(out.chunks_exact_mut(2)) {
+        pair[0] = DIGITS[usize::from(byte >> 4)];
+        pair[1] = DIGITS[usize::from(byte & 0xf)];
+    }
+}
+
+/// Decodes the hexadecimal digits of `hex` to `out`, which must be half as
+/// long
+fn decode_hex(hex: &[u8], out: &mut [u8]) -> Result<(), Error> {
+    fn digit(c: u8) -> Result<u8, Error> {
+        match c {
+            b'0'..=b'9' => Ok(c - b'0'),
+            b'a'..=b'f' => Ok(c - b'a' + 10),
+            b'A'..=b'F' => Ok(c - b'A' + 10),
+            _ => Err(Error::Data),
+        }
+    }
+    if hex.len() != out.len() * 2 {
+        return Err(Error::Data);
+    }
+    for (pair, byte) in hex.chunks_exact(2).zip(out.iter_mut()) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Ok(())
+}
+
+fn write_slice_escaped<P>(port: &mut P, buf: &[u8], escape: &EscapeSet) -> Result<(), Error>
+where
+    P: Write,
+{
+    for value in buf {
+        write_byte_escaped(port, *value, escape)?;
+    }
+
+    Ok(())
+}
+
+fn write_byte_escaped<P>(port: &mut P, value: u8, escape: &EscapeSet) -> Result<(), Error>
+where
+    P: Write,
+{
+    if escape.high && value & 0x80 != 0 {
+        port.write_byte(ZDLE)?;
+        port.write_byte(ZESC8)?;
+        return write_byte_escaped(port, value & 0x7f, escape);
+    }
+    let escaped = ZDLE_TABLE[value as usize];
+    if escaped != value {
+        port.write_byte(ZDLE)?;
+    } else if escape.contains(value) {
+        port.write_byte(ZDLE)?;
+        return port.write_byte(value ^ ZESCAPE_BIT);
+    }
+    port.write_byte(escaped)
+}
+
+fn read_byte_unescaped<P>(port: &mut P) -> Result<u8, Error>
+where
+    P: Read,
+{
+    let b = port.read_byte()?;
+    if b != ZDLE {
+        return Ok(b);
+    }
+    match port.read_byte()? {
+        // The byte without the 8th bit, which is not escaped again:
+        ZESC8 => match port.read_byte()? {
+            ZDLE => Ok(UNZDLE_TABLE[port.read_byte()? as usize] | 0x80),
+            b => Ok(b | 0x80),
+        },
+        b => Ok(UNZDLE_TABLE[b as usize]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source::Plain;
+    use crate::Sink as _;
+    use crate::{
+        decode_hex, kermit, negotiate, proto, read_header, read_subpacket, read_zpad, receive,
+        receive_dir, receive_sink, run_receive, send, send_dir, send_source, write_subpacket,
+        Announce, BatchProgress, Buffer, BufferedPort, ChunkSource, Codec, Conformance, Decision,
+        DeferredBuf, DeferredWrite, DequePort, DoubleBuffer, Encoding, Error, ErrorContext,
+        EscapeSet, EscapedSource, Event, FileInfo, FileStatus, FileSummary, Frame, Header, Hooks,
+        LinkEvent, MappedSource, NakReason, NamePolicy, NameRules, Newline, Packet, Pipelined,
+        Quirks, Quota, Quotas, Read, ResumeRecord, ResumeStore, Role, RttPort, Seek, Session,
+        Source, Stage, State, TarSink, TransferSummary, Transport, Write, Zrinit, ABORT, CRC32,
+        MAX_GARBAGE, MAX_ZFILE_RETRIES, UNZDLE_TABLE, XON, ZACK_HEADER, ZDLE, ZDLE_TABLE,
+        ZNAK_HEADER, ZPAD, ZRINIT_HEADER, ZRQINIT_HEADER,
+    };
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use heapless::String;
+    use std::{
+        cell::Cell,
+        collections::VecDeque,
+        fs,
+        io::Cursor,
+        os::unix::net::UnixStream,
+        path::PathBuf,
+        sync::{mpsc, Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    const ZDATA_HEADER: Header = Header::new(Encoding::ZBIN32, Frame::ZDATA, &[0; 4]);
+
+    /// Serial port double with a prerecorded transcript of incoming bytes
+    struct Port {
+        rx: Cursor<Vec<u8>>,
+        tx: Vec<u8>,
+    }
+
+    impl Port {
+        fn new(rx: Vec<u8>) -> Self {
+            Self {
+                rx: Cursor::new(rx),
+                tx: vec![],
+            }
+        }
+    }
+
+    impl std::io::Read for Port {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            std::io::Read::read(&mut self.rx, buf)
+        }
+    }
+
+    impl std::io::Write for Port {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            std::io::Write::write(&mut self.tx, buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// One end of an in-memory duplex serial link
+    struct Pipe {
+        tx: mpsc::Sender<Vec<u8>>,
+        rx: mpsc::Receiver<Vec<u8>>,
+        buf: Cursor<Vec<u8>>,
+    }
+
+    /// Creates both ends of an in-memory duplex serial link
+    fn make_pipe() -> (Pipe, Pipe) {
+        let (tx_a, rx_a) = mpsc::channel();
+        let (tx_b, rx_b) = mpsc::channel();
+        let a = Pipe {
+            tx: tx_a,
+            rx: rx_b,
+            buf: Cursor::default(),
+        };
+        let b = Pipe {
+            tx: tx_b,
+            rx: rx_a,
+            buf: Cursor::default(),
+        };
+        (a, b)
+    }
+
+    impl std::io::Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.buf.position() == self.buf.get_ref().len() as u64 {
+                match self.rx.recv() {
+                    Ok(data) => self.buf = Cursor::new(data),
+                    Err(_) => return Ok(0),
+                }
+            }
+            std::io::Read::read(&mut self.buf, buf)
+        }
+    }
+
+    impl std::io::Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            // The peer might have already finished:
+            self.tx.send(buf.to_vec()).unwrap_or_default();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Byte rate and one-way latency of a serial line
+    #[derive(Clone, Copy)]
+    struct Profile {
+        /// Bytes per second, or zero for an unlimited rate
+        rate: u64,
+        /// One-way latency in microseconds
+        latency: u64,
+    }
+
+    /// Line without any delays
+    const IDEAL: Profile = Profile {
+        rate: 0,
+        latency: 0,
+    };
+
+    /// 9600 baud with 8N1 framing
+    const BAUD_9600: Profile = Profile {
+        rate: 960,
+        latency: 0,
+    };
+
+    /// 64 kbit/s geostationary satellite link
+    const SATELLITE: Profile = Profile {
+        rate: 8000,
+        latency: 300_000,
+    };
+
+    /// One direction of an in-memory serial line, which models the byte rate
+    /// and the latency against a virtual clock in microseconds. `fault` is
+    /// invoked for each byte sent along with its index, and it can corrupt
+    /// the byte.
+    struct Line<'a> {
+        profile: Profile,
+        clock: &'a Cell<u64>,
+        free: u64,
+        queue: VecDeque<(u64, u8)>,
+        sent: usize,
+        fault: &'a mut dyn FnMut(usize, &mut u8),
+    }
+
+    impl<'a> Line<'a> {
+        fn new(
+            profile: Profile,
+            clock: &'a Cell<u64>,
+            fault: &'a mut dyn FnMut(usize, &mut u8),
+        ) -> Self {
+            Self {
+                profile,
+                clock,
+                free: 0,
+                queue: VecDeque::new(),
+                sent: 0,
+                fault,
+            }
+        }
+    }
+
+    /// Endpoint of a duplex serial line, which is driven from a single
+    /// thread. Writing blocks until the bytes have been transmitted, and
+    /// reading blocks until the bytes in flight have arrived.
+    struct Link<'a, 'b> {
+        rx: &'b mut Line<'a>,
+        tx: &'b mut Line<'a>,
+    }
+
+    impl std::io::Read for Link<'_, '_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let clock = self.rx.clock;
+            let mut count = 0;
+            while count < buf.len() {
+                let Some(&(arrival, b)) = self.rx.queue.front() else {
+                    break;
+                };
+                if arrival > clock.get() {
+                    if count > 0 {
+                        break;
+                    }
+                    clock.set(arrival);
+                }
+                self.rx.queue.pop_front();
+                buf[count] = b;
+                count += 1;
+            }
+            Ok(count)
+        }
+    }
+
+    impl std::io::Write for Link<'_, '_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let line = &mut *self.tx;
+            let byte_time = 1_000_000_u64.checked_div(line.profile.rate).unwrap_or(0);
+            for b in buf {
+                let mut b = *b;
+                (line.fault)(line.sent, &mut b);
+                line.sent += 1;
+                line.free = line.free.max(line.clock.get()) + byte_time;
+                line.queue.push_back((line.free + line.profile.latency, b));
+            }
+            line.clock.set(line.clock.get().max(line.free));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Steps a sender and a receiver against each other until both have
+    /// finished, and returns the received data, and the summaries of the
+    /// sender and the receiver. `fault` is invoked for each byte sent to the
+    /// receiver along with its index, and it can corrupt the byte.
+    fn simulate(
+        data: &[u8],
+        fault: &mut dyn FnMut(usize, &mut u8),
+    ) -> (Vec<u8>, TransferSummary, TransferSummary) {
+        let mut sender = State::new_file("foo", u32::try_from(data.len()).unwrap()).unwrap();
+        let mut receiver = State::new();
+        let (output, _) = simulate_states(&mut sender, &mut receiver, data, IDEAL, fault);
+        (output, sender.summary(), receiver.summary())
+    }
+
+    /// Steps the given sender and receiver states against each other over a
+    /// line with the given profile, and returns the received data, and the
+    /// elapsed virtual time in microseconds
+    fn simulate_states(
+        sender: &mut State<'_>,
+        receiver: &mut State<'_>,
+        data: &[u8],
+        profile: Profile,
+        fault: &mut dyn FnMut(usize, &mut u8),
+    ) -> (Vec<u8>, u64) {
+        let clock = Cell::new(0);
+        let mut no_fault = |_: usize, _: &mut u8| ();
+        let mut to_sender = Line::new(profile, &clock, &mut no_fault);
+        let mut to_receiver = Line::new(profile, &clock, fault);
+        let data = simulate_session(sender, receiver, data, &mut to_sender, &mut to_receiver);
+        (data, clock.get())
+    }
+
+    /// Steps the given sender and receiver states against each other over the
+    /// given lines until both are done, and returns the received data
+    fn simulate_session<'a>(
+        sender: &mut State<'_>,
+        receiver: &mut State<'_>,
+        data: &[u8],
+        to_sender: &mut Line<'a>,
+        to_receiver: &mut Line<'a>,
+    ) -> Vec<u8> {
+        let mut file = Cursor::new(data);
+        let mut sink = Sink::default();
+        for _ in 0..10_000 {
+            if sender.stage() == Stage::Done && receiver.stage() == Stage::Done {
+                return sink.data;
+            }
+            // A side is stepped when it has input pending. When neither of
+            // them has, both time out, and are stepped.
+            let sender_ready = sender.stage() != Stage::Done;
+            let receiver_ready = receiver.stage() != Stage::Done;
+            let sender_input = sender_ready && !to_sender.queue.is_empty();
+            let timeout = !sender_input && (!receiver_ready || to_receiver.queue.is_empty());
+            if sender_ready && (timeout || sender_input) {
+                let mut link = Link {
+                    rx: to_sender,
+                    tx: to_receiver,
+                };
+                assert!(send(&mut link, &mut file, sender) == Ok(()));
+            }
+            if receiver_ready && (timeout || !to_receiver.queue.is_empty()) {
+                let mut link = Link {
+                    rx: to_receiver,
+                    tx: to_sender,
+                };
+                assert!(receive(&mut link, &mut sink, receiver) == Ok(()));
+            }
+        }
+        panic!("the transfer did not finish");
+    }
+
+    /// Codec double, which encrypts with XOR, and appends a marker byte
+    struct Xor;
+
+    impl Codec for Xor {
+        fn transport(&self) -> Transport {
+            Transport::Encrypt
+        }
+
+        fn overhead(&self) -> usize {
+            1
+        }
+
+        fn encode(&mut self, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+            buf[..len].iter_mut().for_each(|b| *b ^= 0x5a);
+            *buf.get_mut(len).ok_or(Error::Data)? = 0xa5;
+            Ok(len + 1)
+        }
+
+        fn decode(&mut self, buf: &mut [u8], len: usize) -> Result<usize, Error> {
+            if len == 0 || buf[len - 1] != 0xa5 {
+                return Err(Error::Data);
+            }
+            buf[..len - 1].iter_mut().for_each(|b| *b ^= 0x5a);
+            Ok(len - 1)
+        }
+    }
+
+    /// File sink double, which records the number of writes and flushes
+    #[derive(Default)]
+    struct Sink {
+        data: Vec<u8>,
+        writes: usize,
+        flushes: usize,
+    }
+
+    impl std::io::Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.data.extend_from_slice(buf);
+            self.writes += 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    /// Modification time used in the transcripts
+    const MTIME: u32 = 1_700_000_000;
+
+    /// Creates a transcript of a sender transmitting a batch of files in
+    /// subpackets of the given size
+    fn make_transcript(files: &[(&str, &[u8])], subpacket_size: usize) -> Vec<u8> {
+        make_transcript_encoded(files, subpacket_size, Encoding::ZBIN32)
+    }
+
+    fn make_transcript_encoded(
+        files: &[(&str, &[u8])],
+        subpacket_size: usize,
+        encoding: Encoding,
+    ) -> Vec<u8> {
+        let mut rx = vec![];
+        for (i, (name, data)) in files.iter().enumerate() {
+            let len = u32::try_from(data.len()).unwrap();
+            let left = files.len() - i;
+            let bytes: usize = files[i..].iter().map(|(_, data)| data.len()).sum();
+            let mut zfile = name.as_bytes().to_vec();
+            zfile.push(0);
+            let info = format!("{len} {MTIME:o} 100644 0 {left} {bytes}");
+            zfile.extend_from_slice(info.as_bytes());
+            zfile.push(0);
+            Header::new(encoding, Frame::ZFILE, &[0; 4])
+                .write(&mut rx)
+                .unwrap();
+            write_subpacket(&mut rx, encoding, Packet::ZCRCW, &zfile, &EscapeSet::new()).unwrap();
+            if !data.is_empty() {
+                Header::new(encoding, Frame::ZDATA, &[0; 4])
+                    .write(&mut rx)
+                    .unwrap();
+            }
+            let mut chunks = data.chunks(subpacket_size).peekable();
+            while let Some(chunk) = chunks.next() {
+                let packet = if chunks.peek().is_some() {
+                    Packet::ZCRCG
+                } else {
+                    Packet::ZCRCE
+                };
+                write_subpacket(&mut rx, encoding, packet, chunk, &EscapeSet::new()).unwrap();
+            }
+            Header::new(encoding, Frame::ZEOF, &[0; 4])
+                .with_count(len)
+                .write(&mut rx)
+                .unwrap();
+        }
+        Header::new(Encoding::ZHEX, Frame::ZFIN, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        rx
+    }
+
+    #[rstest::rstest]
+    #[case(Encoding::ZBIN, Frame::ZRQINIT, &[0; 4], &[ZPAD, ZDLE, Encoding::ZBIN as u8, 0, 0, 0, 0, 0, 0, 0])]
+    #[case(Encoding::ZBIN32, Frame::ZRQINIT, &[0; 4], &[ZPAD, ZDLE, Encoding::ZBIN32 as u8, 0, 0, 0, 0, 0, 29, 247, 34, 198])]
+    #[case(Encoding::ZBIN, Frame::ZRQINIT, &[1; 4], &[ZPAD, ZDLE, Encoding::ZBIN as u8, 0, 1, 1, 1, 1, 98, 148])]
+    #[case(Encoding::ZHEX, Frame::ZRQINIT, &[1; 4], &[ZPAD, ZPAD, ZDLE, Encoding::ZHEX as u8, b'0', b'0', b'0', b'1', b'0', b'1', b'0', b'1', b'0', b'1', 54, 50, 57, 52, b'\r', b'\n', XON])]
+    pub fn test_header_write(
+        #[case] encoding: Encoding,
+        #[case] frame: Frame,
+        #[case] flags: &[u8; 4],
+        #[case] expected: &[u8],
+    ) {
+        let header = Header::new(encoding, frame, flags);
+        let mut port = vec![];
+        assert!(header.write(&mut port) == Ok(()));
+        assert_eq!(port, expected);
+    }
+
+    #[rstest::rstest]
+    #[case(&[Encoding::ZHEX as u8, b'0', b'1', b'0', b'1', b'0', b'2', b'0', b'3', b'0', b'4', b'a', b'7', b'5', b'2'], Encoding::ZHEX, Frame::ZRINIT, &[0x1, 0x2, 0x3, 0x4])]
+    #[case(&[Encoding::ZHEX as u8, b'0', b'1', b'0', b'1', b'0', b'2', b'0', b'3', b'0', b'4', b'A', b'7', b'5', b'2'], Encoding::ZHEX, Frame::ZRINIT, &[0x1, 0x2, 0x3, 0x4])]
+    #[case(&[Encoding::ZBIN as u8, Frame::ZRINIT as u8, 0xa, 0xb, 0xc, 0xd, 0xa6, 0xcb], Encoding::ZBIN, Frame::ZRINIT, &[0xa, 0xb, 0xc, 0xd])]
+    #[case(&[Encoding::ZBIN32 as u8, Frame::ZRINIT as u8, 0xa, 0xb, 0xc, 0xd, 0x99, 0xe2, 0xae, 0x4a], Encoding::ZBIN32, Frame::ZRINIT, &[0xa, 0xb, 0xc, 0xd])]
+    #[case(&[Encoding::ZBIN as u8, Frame::ZRINIT as u8, 0xa, ZDLE, b'l', 0xd, ZDLE, b'm', 0x5e, 0x6f], Encoding::ZBIN, Frame::ZRINIT, &[0xa, 0x7f, 0xd, 0xff])]
+    pub fn test_header_read(
+        #[case] port: &[u8],
+        #[case] encoding: Encoding,
+        #[case] frame: Frame,
+        #[case] flags: &[u8; 4],
+    ) {
+        let port = &mut port.to_vec();
+        let port = &mut port.as_slice();
+        assert!(Header::read(port) == Ok(Header::new(encoding, frame, flags)));
+    }
+
+    #[rstest::rstest]
+    #[case(b"00ff7f", Ok(vec![0x00, 0xff, 0x7f]))]
+    #[case(b"0aFa", Ok(vec![0x0a, 0xfa]))]
+    #[case(b"0g", Err(Error::Data))]
+    #[case(b"012", Err(Error::Data))]
+    pub fn test_decode_hex(#[case] hex: &[u8], #[case] expected: Result<Vec<u8>, Error>) {
+        let mut out = vec![0; hex.len() / 2];
+        let result = decode_hex(hex, &mut out).map(|()| out);
+        assert!(result == expected);
+    }
+
+    #[test]
+    pub fn test_proto_escapes() {
+        assert_eq!(ZDLE_TABLE[0x7f], proto::ZRUB0);
+        assert_eq!(ZDLE_TABLE[0xff], proto::ZRUB1);
+        assert_eq!(ZDLE_TABLE[usize::from(ZDLE)], ZDLE ^ proto::ZESCAPE_BIT);
+        assert_eq!(UNZDLE_TABLE[usize::from(proto::ZRUB0)], 0x7f);
+        assert_eq!(UNZDLE_TABLE[usize::from(proto::ZRUB1)], 0xff);
+    }
+
+    #[rstest::rstest]
+    #[case(0, Ok(Frame::ZRQINIT))]
+    #[case(19, Ok(Frame::ZSTDERR))]
+    #[case(20, Err(Error::Data))]
+    pub fn test_frame_try_from(#[case] value: u8, #[case] expected: Result<Frame, Error>) {
+        assert!(Frame::try_from(value) == expected);
+    }
+
+    #[rstest::rstest]
+    #[case(Encoding::ZBIN, Packet::ZCRCE, &[])]
+    #[case(Encoding::ZBIN, Packet::ZCRCW, &[0x00])]
+    #[case(Encoding::ZBIN32, Packet::ZCRCQ, &[0, 1, 2, 3, 4, 0x60, 0x60])]
+    #[case(Encoding::ZHEX, Packet::ZCRCG, &[0x18, 0x11, 0xff])]
+    pub fn test_subpacket_read_write(
+        #[case] encoding: Encoding,
+        #[case] packet: Packet,
+        #[case] data: &[u8],
+    ) {
+        let mut buf = Buffer::new();
+        let mut port = vec![];
+        assert!(write_subpacket(&mut port, encoding, packet, data, &EscapeSet::new()) == Ok(()));
+        buf.clear();
+        assert!(read_subpacket(&mut port.as_slice(), &mut buf, encoding) == Ok(packet));
+        assert!(buf == data);
+    }
+
+    #[rstest::rstest]
+    #[case(&[], &[0x01, 0x02, 0x81])]
+    #[case(&[0x01, 0x81], &[ZDLE, 0x41, 0x02, ZDLE, 0xc1])]
+    pub fn test_subpacket_escape_set(#[case] bytes: &[u8], #[case] expected: &[u8]) {
+        let mut escape = EscapeSet::new();
+        for byte in bytes {
+            assert!(escape.insert(*byte) == Ok(()));
+        }
+        let data = [0x01, 0x02, 0x81];
+        let mut port = vec![];
+        let packet = Packet::ZCRCW;
+        assert!(write_subpacket(&mut port, Encoding::ZBIN32, packet, &data, &escape) == Ok(()));
+        assert_eq!(&port[..expected.len()], expected);
+        let mut buf = Buffer::new();
+        assert!(read_subpacket(&mut port.as_slice(), &mut buf, Encoding::ZBIN32) == Ok(packet));
+        assert!(buf == &data[..]);
+    }
+
+    #[test]
+    pub fn test_escape_set() {
+        let mut escape = EscapeSet::new();
+        assert!(escape.is_empty());
+        assert!(escape.insert(b'A') == Err(Error::Data));
+        assert!(escape.insert(0x7f) == Err(Error::Data));
+        assert!(escape.insert(0x9f) == Ok(()));
+        assert!(escape.contains(0x9f) && !escape.contains(0x1f));
+        assert!(EscapeSet::CONTROL.contains(0x00) && EscapeSet::CONTROL.contains(0x9f));
+        assert!(!EscapeSet::CONTROL.contains(b' ') && !EscapeSet::CONTROL.contains(0xa0));
+        escape.remove(0x9f);
+        assert!(escape == EscapeSet::new());
+        escape.insert_high();
+        assert!(!escape.is_empty() && escape.contains(0xa0) && !escape.contains(0x7f));
+    }
+
+    #[test]
+    pub fn test_send_esc8() {
+        let mut rx = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZRINIT, &[0; 4])
+            .with_zrinit(Zrinit::CANFDX | Zrinit::CANOVIO | Zrinit::CANFC32 | Zrinit::ESC8)
+            .write(&mut rx)
+            .unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        let data: Vec<u8> = (0..=255).collect();
+        let mut file = Cursor::new(&data[..]);
+        let mut state = State::new_file("foo", 256).unwrap();
+        state.set_zesc8(true);
+        let mut port = Port::new(rx);
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        assert!(port.tx.iter().all(|b| *b < 0x80));
+        // The escaped subpacket is decoded by the receiver:
+        let zdata = port
+            .tx
+            .windows(3)
+            .rposition(|w| w == [ZPAD, ZDLE, Encoding::ZBIN32 as u8]);
+        let mut tx = &port.tx[zdata.unwrap() + 2..];
+        assert_eq!(
+            Header::read(&mut tx).map(|header| header.frame()),
+            Ok(Frame::ZDATA)
+        );
+        let mut buf = Buffer::new();
+        assert!(read_subpacket(&mut tx, &mut buf, Encoding::ZBIN32) == Ok(Packet::ZCRCW));
+        assert_eq!(buf.as_slice(), &data[..]);
+    }
+
+    #[test]
+    pub fn test_send_esc8_session() {
+        let esc8 = Zrinit::CANFDX | Zrinit::CANOVIO | Zrinit::CANFC32 | Zrinit::ESC8;
+        let data = [0xaa; 16];
+        let mut state = State::new_file("foo", 16).unwrap();
+        for (zrinit, zesc8, escaped) in [
+            (esc8, false, false),
+            (esc8, true, true),
+            (
+                Zrinit::CANFDX | Zrinit::CANOVIO | Zrinit::CANFC32,
+                true,
+                false,
+            ),
+        ] {
+            let mut rx = vec![];
+            ZRINIT_HEADER.with_zrinit(zrinit).write(&mut rx).unwrap();
+            Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4])
+                .write(&mut rx)
+                .unwrap();
+            // The escapes of the previous session are not carried over:
+            assert!(state.reset_file("foo", 16) == Ok(()));
+            state.set_zesc8(zesc8);
+            let mut port = Port::new(rx);
+            let mut file = Cursor::new(&data[..]);
+            assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+            assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+            assert_eq!(port.tx.iter().all(|b| *b < 0x80), escaped);
+        }
+    }
+
+    #[rstest::rstest]
+    #[case::crc16(Zrinit::CANFDX | Zrinit::CANOVIO, Encoding::ZBIN)]
+    #[case::crc32(Zrinit::CANFDX | Zrinit::CANOVIO | Zrinit::CANFC32, Encoding::ZBIN32)]
+    pub fn test_send_encoding(#[case] zrinit: Zrinit, #[case] encoding: Encoding) {
+        let mut rx = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZRINIT, &[0; 4])
+            .with_zrinit(zrinit)
+            .write(&mut rx)
+            .unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        let data = [0x55; 100];
+        let mut file = Cursor::new(&data[..]);
+        let mut state = State::new_file("foo", 100).unwrap();
+        let mut port = Port::new(rx);
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        let mut tx = &port.tx[..];
+        for frame in [Frame::ZFILE, Frame::ZDATA] {
+            let offset = tx
+                .windows(3)
+                .position(|w| w[..2] == [ZPAD, ZDLE] && w[2] != Encoding::ZHEX as u8)
+                .unwrap();
+            assert_eq!(tx[offset + 2], encoding as u8);
+            tx = &tx[offset + 2..];
+            assert_eq!(
+                Header::read(&mut tx).map(|header| header.frame()),
+                Ok(frame)
+            );
+            let mut buf = Buffer::new();
+            assert!(read_subpacket(&mut tx, &mut buf, encoding).is_ok());
+        }
+    }
+
+    #[rstest::rstest]
+    #[case(&[ZPAD, ZDLE], Ok(1))]
+    #[case(&[ZPAD, ZPAD, ZDLE], Ok(2))]
+    #[case(&[ZDLE], Err(Error::Data))]
+    #[case(&[ZPAD, XON], Err(Error::Data))]
+    #[case(&[ZPAD, ZPAD, XON], Err(Error::Data))]
+    #[case(&[], Err(Error::Read))]
+    #[case(&[0; 100], Err(Error::Data))]
+    #[case(&[0; MAX_GARBAGE], Err(Error::Quota(Quota::Garbage)))]
+    pub fn test_zpad_read(#[case] port: &[u8], #[case] expected: Result<usize, Error>) {
+        assert!(read_zpad(&mut port.to_vec().as_slice(), MAX_GARBAGE) == expected);
+    }
+
+    // Headers as sent by lrzsz, and their deviations from the specification.
+    // The `ZRQINIT` headers have all-zero flags and CRC.
+    #[rstest::rstest]
+    #[case::spec(b"**\x18B00000000000000\r\n\x11", true, true)]
+    #[case::lrzsz_trailer(b"rz\r**\x18B00000000000000\r\x8a\x11", true, false)]
+    #[case::parity_trailer(b"**\x18B00000000000000\x8d\x8a\x11", true, false)]
+    #[case::parity_digits(b"**\x18B\xb0\xb0000000000000\r\n", true, false)]
+    #[case::early_xon(b"**\x18B0000000\x110000000\r\n", true, false)]
+    #[case::early_xoff(b"**\x18B000000\x93000000000\r\n", true, false)]
+    #[case::single_pad(b"*\x18B00000000000000\r\n", true, false)]
+    #[case::triple_pad(b"***\x18B00000000000000\r\n", true, false)]
+    #[case::missing_trailer(b"**\x18B00000000000000", true, false)]
+    #[case::bad_crc(b"**\x18B00000000000001\r\n", false, false)]
+    #[case::bin(b"*\x18A\0\0\0\0\0\0\0", true, true)]
+    #[case::bin_double_pad(b"**\x18A\0\0\0\0\0\0\0", true, false)]
+    pub fn test_conformance(#[case] port: &[u8], #[case] lenient: bool, #[case] strict: bool) {
+        let modes = [
+            (Conformance::Lenient, lenient),
+            (Conformance::Strict, strict),
+        ];
+        for (conformance, expected) in modes {
+            let mut port = port;
+            let pads = read_zpad(&mut port, MAX_GARBAGE).unwrap();
+            let header = read_header(&mut port, conformance, pads, false);
+            assert_eq!(header.is_ok(), expected, "{conformance:?}");
+            if let Ok(header) = header {
+                assert_eq!(header.frame(), Frame::ZRQINIT);
+            }
+        }
+    }
+
+    #[rstest::rstest]
+    #[case(Conformance::Lenient, 0)]
+    #[case(Conformance::Strict, 1)]
+    pub fn test_receive_conformance(#[case] conformance: Conformance, #[case] retries: u32) {
+        let mut port = Port::new(b"rz\r**\x18B00000000000000\r\x8a\x11".to_vec());
+        let mut state = State::new();
+        state.set_conformance(conformance);
+        assert!(receive(&mut port, &mut Sink::default(), &mut state) == Ok(()));
+        assert_eq!(state.summary().retries(), retries);
+    }
+
+    #[test]
+    pub fn test_header_flags() {
+        let header = Header::new(Encoding::ZHEX, Frame::ZFILE, &[1, 2, 3, 4]);
+        assert_eq!(header.count(), 0x0403_0201);
+        assert_eq!(header.zf(), [4, 3, 2, 1]);
+        assert!(header.with_zf(&header.zf()) == header);
+        let zrinit = Zrinit::CANFDX | Zrinit::CANFC32;
+        let header = Header::new(Encoding::ZHEX, Frame::ZRINIT, &[0; 4]).with_zrinit(zrinit);
+        assert_eq!(header.zf(), [zrinit.bits(), 0, 0, 0]);
+        assert_eq!(header.zrinit(), zrinit);
+    }
+
+    #[test]
+    pub fn test_send_peer_capabilities() {
+        let zrinit = Zrinit::CANFDX | Zrinit::CANFC32 | Zrinit::ESCCTL;
+        let mut rx = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZRINIT, &[0; 4])
+            .with_zrinit(zrinit)
+            .write(&mut rx)
+            .unwrap();
+        let mut port = Port::new(rx);
+        let mut state = State::new_file("foo", 3).unwrap();
+        assert_eq!(state.peer_capabilities(), None);
+        assert!(send(&mut port, &mut Cursor::new(b"foo"), &mut state) == Ok(()));
+        assert_eq!(state.peer_capabilities(), Some(zrinit));
+        assert!(state.stage() == Stage::Ready);
+    }
+
+    /// Returns the frame of the first header in `tx`, if any
+    fn first_frame(tx: &[u8]) -> Option<Frame> {
+        let mut tx = tx;
+        read_zpad(&mut tx, MAX_GARBAGE).ok()?;
+        Header::read(&mut tx).ok().map(|header| header.frame())
+    }
+
+    #[test]
+    pub fn test_send_challenge() {
+        let mut rx = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZCHALLENGE, &[0; 4])
+            .with_count(0xdead_beef)
+            .write(&mut rx)
+            .unwrap();
+        let mut port = Port::new(rx);
+        let mut state = State::new_file("foo", 3).unwrap();
+        assert!(send(&mut port, &mut Cursor::new(b"foo"), &mut state) == Ok(()));
+        let mut expected = vec![];
+        ZRQINIT_HEADER.write(&mut expected).unwrap();
+        ZACK_HEADER
+            .with_count(0xdead_beef)
+            .write(&mut expected)
+            .unwrap();
+        assert_eq!(port.tx, expected);
+        assert!(state.stage() == Stage::Waiting);
+    }
+
+    #[rstest::rstest]
+    #[case::matching(3000, Frame::ZEOF, 3000, 0)]
+    #[case::behind(2000, Frame::ZDATA, 2000, 1)]
+    #[case::ahead(9999, Frame::ZEOF, 3000, 1)]
+    pub fn test_send_zack_position(
+        #[case] ack: u32,
+        #[case] frame: Frame,
+        #[case] count: u32,
+        #[case] retries: u32,
+    ) {
+        let header = |frame, count| {
+            let mut rx = vec![];
+            Header::new(Encoding::ZHEX, frame, &[0; 4])
+                .with_count(count)
+                .write(&mut rx)
+                .unwrap();
+            rx
+        };
+        let data = [0x55; 3000];
+        let mut file = Cursor::new(&data[..]);
+        let mut state = State::new_file("foo", 3000).unwrap();
+        let streaming = u32::from((Zrinit::CANFDX | Zrinit::CANOVIO).bits()) << 24;
+        for rx in [header(Frame::ZRINIT, streaming), header(Frame::ZRPOS, 0)] {
+            let mut port = Port::new(rx);
+            assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        }
+        let mut port = Port::new(header(Frame::ZACK, ack));
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        let mut tx = &port.tx[..];
+        read_zpad(&mut tx, MAX_GARBAGE).unwrap();
+        let header = Header::read(&mut tx).unwrap();
+        assert_eq!((header.frame(), header.count()), (frame, count));
+        assert_eq!(state.summary().retries(), retries);
+    }
+
+    #[rstest::rstest]
+    #[case::none(0, 1500)]
+    #[case::aligned(1024, 1024)]
+    #[case::multiple(512, 1024)]
+    #[case::unaligned(1000, 1000)]
+    pub fn test_send_reposition_alignment(#[case] alignment: u32, #[case] expected: u32) {
+        let mut rx = vec![];
+        ZRINIT_HEADER.write(&mut rx).unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4])
+            .with_count(1500)
+            .write(&mut rx)
+            .unwrap();
+        let data = [0x55; 3000];
+        let mut file = Cursor::new(&data[..]);
+        let mut state = State::new_file("foo", 3000).unwrap();
+        state.set_reposition_alignment(alignment);
+        let mut port = Port::new(rx);
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        port.tx.clear();
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        let mut tx = &port.tx[..];
+        read_zpad(&mut tx, MAX_GARBAGE).unwrap();
+        let header = Header::read(&mut tx).unwrap();
+        assert_eq!((header.frame(), header.count()), (Frame::ZDATA, expected));
+    }
+
+    #[test]
+    pub fn test_receive_overlap() {
+        let data: Vec<u8> = (0..3000u32).map(|i| i.to_le_bytes()[0]).collect();
+        let mut rx = make_transcript(&[("foo", &data[..1500])], 512);
+        // Cut the transcript before ZEOF, and re-send from the aligned
+        // position:
+        let zeof = rx
+            .windows(3)
+            .rposition(|w| w == [ZPAD, ZDLE, Encoding::ZBIN32 as u8]);
+        rx.truncate(zeof.unwrap());
+        Header::new(Encoding::ZBIN32, Frame::ZDATA, &[0; 4])
+            .with_count(1024)
+            .write(&mut rx)
+            .unwrap();
+        let mut chunks = data[1024..].chunks(512).peekable();
+        while let Some(chunk) = chunks.next() {
+            let packet = if chunks.peek().is_some() {
+                Packet::ZCRCG
+            } else {
+                Packet::ZCRCE
+            };
+            write_subpacket(&mut rx, Encoding::ZBIN32, packet, chunk, &EscapeSet::new()).unwrap();
+        }
+        Header::new(Encoding::ZBIN32, Frame::ZEOF, &[0; 4])
+            .with_count(3000)
+            .write(&mut rx)
+            .unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZFIN, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert_eq!(sink.data, data);
+        assert_eq!(state.summary().retries(), 0);
+    }
+
+    #[rstest::rstest]
+    #[case::hyperterminal(Quirks::HYPERTERMINAL, Encoding::ZBIN32, true)]
+    #[case::tera_term(Quirks::TERA_TERM, Encoding::ZHEX, true)]
+    #[case::securecrt(Quirks::SECURECRT, Encoding::ZHEX, false)]
+    pub fn test_receive_quirks(
+        #[case] quirks: Quirks,
+        #[case] encoding: Encoding,
+        #[case] late_xon: bool,
+    ) {
+        let data: Vec<u8> = (0..3000u32).map(|i| i.to_le_bytes()[0]).collect();
+        let mut rx = make_transcript_encoded(&[("foo", &data)], 512, encoding);
+        if late_xon {
+            // XON in the middle of the second data subpacket:
+            let at = rx.windows(2).position(|w| w == [ZDLE, Packet::ZCRCG as u8]);
+            rx.insert(at.unwrap() + 100, XON);
+        }
+        let run = |quirks: Quirks| {
+            let mut port = Port::new(rx.clone());
+            let mut sink = Sink::default();
+            let mut state = State::new();
+            state.set_quirks(quirks);
+            for _ in 0..20 {
+                if state.stage() == Stage::Done {
+                    break;
+                }
+                if receive(&mut port, &mut sink, &mut state).is_err() {
+                    break;
+                }
+            }
+            (sink.data, state.summary().retries())
+        };
+        assert_eq!(run(quirks), (data.clone(), 0));
+        assert_ne!(run(Quirks::empty()).0, data);
+    }
+
+    #[rstest::rstest]
+    #[case::zrqinit(b"", Some(ZRQINIT_HEADER), Some(Role::Receiver))]
+    #[case::command(b"rz\r", Some(ZRQINIT_HEADER), Some(Role::Receiver))]
+    #[case::command_only(b"rz\r", None, Some(Role::Receiver))]
+    #[case::zrinit(b"", Some(ZRINIT_HEADER), Some(Role::Sender))]
+    #[case::garbage(b"rz -e\r", None, None)]
+    #[case::timeout(b"", None, None)]
+    pub fn test_negotiate(
+        #[case] prefix: &[u8],
+        #[case] header: Option<Header>,
+        #[case] role: Option<Role>,
+    ) {
+        let mut rx = prefix.to_vec();
+        if let Some(header) = header {
+            header.write(&mut rx).unwrap();
+        }
+        let mut port = Port::new(rx);
+        let mut state = State::new();
+        assert_eq!(negotiate(&mut port, &mut state), Ok(role));
+    }
+
+    #[test]
+    pub fn test_negotiate_receive() {
+        let data = [0x55; 3000];
+        let mut rx = b"rz\r".to_vec();
+        ZRQINIT_HEADER.write(&mut rx).unwrap();
+        rx.extend_from_slice(&make_transcript(&[("foo", &data)], 512));
+        let mut port = Port::new(rx);
+        let mut state = State::new();
+        assert_eq!(negotiate(&mut port, &mut state), Ok(Some(Role::Receiver)));
+        let mut sink = Sink::default();
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert_eq!(sink.data, data);
+        assert_eq!(first_frame(&port.tx), Some(Frame::ZRINIT));
+    }
+
+    #[rstest::rstest]
+    #[case::octal("3 17 644", 0o17, 0o644, None)]
+    #[case::decimal_mtime("3 1700000009 644", 0, 0o644, None)]
+    #[case::garbage_mode("3 17 rw-r--r--", 0o17, 0, None)]
+    #[case::remaining("3 17 644 0 2 10", 0o17, 0o644, Some(2))]
+    #[case::garbage_remaining("3 17 644 0 -1 x", 0o17, 0o644, None)]
+    pub fn test_receive_zfile_info(
+        #[case] info: &str,
+        #[case] mtime: u32,
+        #[case] mode: u32,
+        #[case] files: Option<u32>,
+    ) {
+        let mut rx = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        let zfile = format!("foo\0{info}\0");
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCW,
+            zfile.as_bytes(),
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        let mut port = Port::new(rx);
+        let mut state = State::new();
+        let mut sink = Sink::default();
+        assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        assert_eq!(state.stage(), Stage::Ready);
+        assert_eq!(state.file_size(), 3);
+        assert_eq!(state.file_info().mtime(), mtime);
+        assert_eq!(state.file_info().mode(), mode);
+        assert_eq!(state.file_info().files_remaining(), files);
+    }
+
+    #[rstest::rstest]
+    // The data, and a control character in the CRC of the subpacket:
+    #[case::default(EscapeSet::new(), Zrinit::CANFC32, 0, 0)]
+    #[case::control(EscapeSet::CONTROL, Zrinit::CANFC32, 1001, 99)]
+    #[case::escctl(EscapeSet::new(), Zrinit::CANFC32 | Zrinit::ESCCTL, 1001, 99)]
+    #[case::crc16(EscapeSet::CONTROL, Zrinit::empty(), 1001, 99)]
+    pub fn test_send_escape_overhead(
+        #[case] escape: EscapeSet,
+        #[case] zrinit: Zrinit,
+        #[case] escapes: u64,
+        #[case] overhead: u64,
+    ) {
+        let mut rx = vec![];
+        ZRINIT_HEADER.with_zrinit(zrinit).write(&mut rx).unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        let data = [0x01; 1000];
+        let mut file = Cursor::new(&data[..]);
+        let mut state = State::new_file("foo", 1000).unwrap();
+        state.set_escape(escape);
+        let mut port = Port::new(rx);
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        assert_eq!(state.summary().escape_overhead(), None);
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        assert_eq!(state.summary().escape_overhead(), Some(overhead));
+        assert_eq!(state.summary().escapes(), escapes);
+    }
+
+    #[test]
+    pub fn test_send_escctl_session() {
+        let data = [0x01; 16];
+        let mut state = State::new_file("foo", 16).unwrap();
+        for (zrinit, escapes) in [(Zrinit::CANFC32 | Zrinit::ESCCTL, 16), (Zrinit::CANFC32, 0)] {
+            let mut rx = vec![];
+            ZRINIT_HEADER.with_zrinit(zrinit).write(&mut rx).unwrap();
+            Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4])
+                .write(&mut rx)
+                .unwrap();
+            // The escapes of the previous session are not carried over:
+            assert!(state.reset_file("foo", 16) == Ok(()));
+            let mut port = Port::new(rx);
+            let mut file = Cursor::new(&data[..]);
+            assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+            assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+            assert!(state.summary().escapes() >= escapes);
+            assert_eq!(state.summary().escapes() == 0, escapes == 0);
+        }
+    }
+
+    #[test]
+    pub fn test_receive_zrpos_suppressed() {
+        let data = [0x55; 3000];
+        let mut rx = make_transcript(&[("foo", &data)], 512);
+        // The frames in flight, which the sender sent before restarting:
+        let zdata = rx
+            .windows(3)
+            .position(|w| w == [ZPAD, ZDLE, Encoding::ZBIN32 as u8])
+            .unwrap();
+        let zdata = zdata
+            + rx[zdata + 1..]
+                .windows(3)
+                .position(|w| w == [ZPAD, ZDLE, Encoding::ZBIN32 as u8])
+                .unwrap()
+            + 1;
+        let mut ahead = vec![];
+        for _ in 0..3 {
+            Header::new(Encoding::ZBIN32, Frame::ZDATA, &[0; 4])
+                .with_count(1024)
+                .write(&mut ahead)
+                .unwrap();
+        }
+        rx.splice(zdata..zdata, ahead);
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert_eq!(sink.data, data);
+        assert_eq!(state.summary().retries(), 1);
+        assert_eq!(state.summary().suppressed_repositions(), 2);
+    }
+
+    #[rstest::rstest]
+    #[case::streaming(Zrinit::CANFDX | Zrinit::CANOVIO, Quirks::empty(), 2)]
+    #[case::half_duplex(Zrinit::CANOVIO, Quirks::empty(), 0)]
+    #[case::no_overlapped_io(Zrinit::CANFDX, Quirks::empty(), 0)]
+    #[case::zcrcw_only(Zrinit::CANFDX | Zrinit::CANOVIO, Quirks::ZCRCW_ONLY, 0)]
+    pub fn test_send_stop_and_wait(
+        #[case] zrinit: Zrinit,
+        #[case] quirks: Quirks,
+        #[case] streamed: usize,
+    ) {
+        let mut rx = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZRINIT, &[0; 4])
+            .with_zrinit(zrinit)
+            .write(&mut rx)
+            .unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        let data = [0x55; 3000];
+        let mut file = Cursor::new(&data[..]);
+        let mut state = State::new_file("foo", 3000).unwrap();
+        state.set_quirks(quirks);
+        let mut port = Port::new(rx);
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        let count = |packet: Packet| {
+            let end = [ZDLE, packet as u8];
+            port.tx.windows(2).filter(|w| *w == end).count()
+        };
+        // `ZFILE` and the burst end with `ZCRCW`:
+        assert_eq!(count(Packet::ZCRCW), 2);
+        assert_eq!(count(Packet::ZCRCG), streamed);
+    }
+
+    #[test]
+    pub fn test_send_zfile_retries() {
+        let mut rx = vec![];
+        ZRINIT_HEADER.write(&mut rx).unwrap();
+        let mut file = Cursor::new(b"foo");
+        let mut state = State::new_file("foo", 3).unwrap();
+        let mut step = |rx: Vec<u8>| {
+            let mut port = Port::new(rx);
+            let result = send(&mut port, &mut file, &mut state);
+            (result, first_frame(&port.tx))
+        };
+        assert_eq!(step(rx), (Ok(()), Some(Frame::ZRQINIT)));
+        // The first call without an answer reads, and the following ones
+        // repeat ZFILE:
+        assert_eq!(step(vec![]), (Ok(()), None));
+        for _ in 0..MAX_ZFILE_RETRIES {
+            assert_eq!(step(vec![]), (Ok(()), Some(Frame::ZFILE)));
+        }
+        assert_eq!(step(vec![]), (Err(Error::Unanswered), None));
+        // The session has been given up:
+        assert_eq!(step(vec![]), (Ok(()), None));
+        assert_eq!(state.stage(), Stage::Done);
+        assert_eq!(state.summary().retries(), MAX_ZFILE_RETRIES);
+    }
+
+    #[test]
+    pub fn test_send_zfile_answered() {
+        let mut file = Cursor::new(b"foo");
+        let mut state = State::new_file("foo", 3).unwrap();
+        let mut step = |frame: Option<Frame>| {
+            let mut rx = vec![];
+            if let Some(frame) = frame {
+                Header::new(Encoding::ZHEX, frame, &[0; 4])
+                    .write(&mut rx)
+                    .unwrap();
+            }
+            let mut port = Port::new(rx);
+            assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+            first_frame(&port.tx)
+        };
+        assert_eq!(step(Some(Frame::ZRINIT)), Some(Frame::ZRQINIT));
+        assert_eq!(step(None), None);
+        assert_eq!(step(None), Some(Frame::ZFILE));
+        // ZFILE is repeated before reading the late answer:
+        assert_eq!(step(Some(Frame::ZRPOS)), Some(Frame::ZFILE));
+        assert_eq!(step(None), None);
+        assert_eq!(step(None), None);
+        assert_eq!(state.summary().retries(), 2);
+        assert!(state.stage() == Stage::InProgress);
+    }
+
+    #[test]
+    pub fn test_send_repeated_zrinit() {
+        let header = |frame, count| {
+            let mut rx = vec![];
+            Header::new(Encoding::ZHEX, frame, &[0; 4])
+                .with_count(count)
+                .write(&mut rx)
+                .unwrap();
+            rx
+        };
+        let mut file = Cursor::new(b"foo");
+        let mut state = State::new_file("foo", 3).unwrap();
+        let mut step = |rx: Vec<u8>| {
+            let mut port = Port::new(rx);
+            assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+            (first_frame(&port.tx), state.stage())
+        };
+        // rz repeats ZRINIT, when it times out waiting for ZFILE:
+        let ready = |frame| (frame, Stage::Ready);
+        let in_progress = |frame| (frame, Stage::InProgress);
+        assert_eq!(step(header(Frame::ZRINIT, 0)), ready(Some(Frame::ZRQINIT)));
+        assert_eq!(step(vec![]), ready(None));
+        assert_eq!(step(header(Frame::ZRINIT, 0)), ready(Some(Frame::ZFILE)));
+        // A duplicate received without a timeout is stale:
+        assert_eq!(step(header(Frame::ZRINIT, 0)), ready(None));
+        assert_eq!(
+            step(header(Frame::ZRPOS, 0)),
+            in_progress(Some(Frame::ZDATA))
+        );
+        // ZRINIT before ZEOF does not complete the file:
+        assert_eq!(step(vec![]), in_progress(None));
+        assert_eq!(
+            step(header(Frame::ZRINIT, 0)),
+            in_progress(Some(Frame::ZFILE))
+        );
+        assert_eq!(
+            step(header(Frame::ZRPOS, 3)),
+            in_progress(Some(Frame::ZEOF))
+        );
+        assert_eq!(
+            step(header(Frame::ZRINIT, 0)),
+            in_progress(Some(Frame::ZFIN))
+        );
+        assert_eq!(state.summary().files_transferred(), 1);
+    }
+
+    #[test]
+    pub fn test_receive_repeated_handshake() {
+        let mut zfile = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4])
+            .write(&mut zfile)
+            .unwrap();
+        write_subpacket(
+            &mut zfile,
+            Encoding::ZBIN32,
+            Packet::ZCRCW,
+            b"foo\x003\0",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        let mut zdata = vec![];
+        ZDATA_HEADER.write(&mut zdata).unwrap();
+        write_subpacket(
+            &mut zdata,
+            Encoding::ZBIN32,
+            Packet::ZCRCE,
+            b"foo",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        let mut zrqinit = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZRQINIT, &[0; 4])
+            .write(&mut zrqinit)
+            .unwrap();
+        let mut zeof = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZEOF, &[0; 4])
+            .with_count(3)
+            .write(&mut zeof)
+            .unwrap();
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        let mut step = |rx: &[u8]| {
+            let mut port = Port::new(rx.to_vec());
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            (first_frame(&port.tx), state.stage())
+        };
+        assert_eq!(step(&zfile), (Some(Frame::ZRINIT), Stage::Ready));
+        assert_eq!(step(&zdata), (None, Stage::InProgress));
+        // sz repeats ZFILE, when it times out waiting for ZRPOS, and ZRQINIT,
+        // when it has lost the ZRINIT:
+        let in_progress = |frame| (Some(frame), Stage::InProgress);
+        assert_eq!(step(&zfile), in_progress(Frame::ZRPOS));
+        assert_eq!(step(&zrqinit), in_progress(Frame::ZRINIT));
+        assert_eq!(step(&zeof), (Some(Frame::ZRINIT), Stage::Waiting));
+        assert_eq!(sink.data, b"foo");
+        assert_eq!(state.summary().files_transferred(), 1);
+    }
+
+    #[rstest::rstest]
+    #[case(&[Encoding::ZHEX as u8, b'0', b'1', b'0', b'1'])]
+    #[case(&[Encoding::ZHEX as u8, b'x', b'1', b'0', b'1', b'0', b'2', b'0', b'3', b'0', b'4', b'a', b'7', b'5', b'2'])]
+    #[case(&[Encoding::ZBIN as u8, 0xff, 0xa, 0xb, 0xc, 0xd, 0x8c, 0xf2])]
+    #[case(&[Encoding::ZBIN32 as u8, Frame::ZRINIT as u8, 0xa, 0xb, 0xc, 0xd, 0x99, 0xe2, 0xae, 0x4b])]
+    pub fn test_header_read_invalid(#[case] port: &[u8]) {
+        assert!(Header::read(&mut &port[..]).is_err());
+    }
+
+    #[test]
+    pub fn test_garbage() {
+        let mut seed: u32 = 1;
+        for _ in 0..32 {
+            let mut bytes = vec![];
+            for _ in 0..4096 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                bytes.push(seed.to_be_bytes()[1]);
+                // Make the headers and subpackets more likely:
+                if bytes.len() % 7 == 0 {
+                    bytes.extend_from_slice(&[ZPAD, ZDLE]);
+                }
+            }
+            let len = u64::try_from(bytes.len()).unwrap();
+            let mut port = Port::new(bytes.clone());
+            let mut state = State::new();
+            while port.rx.position() < len && state.stage() != Stage::Done {
+                if receive(&mut port, &mut Sink::default(), &mut state).is_err() {
+                    break;
+                }
+            }
+            let mut port = Port::new(bytes.clone());
+            let mut state = State::new_file("foo", 3).unwrap();
+            while port.rx.position() < len && state.stage() != Stage::Done {
+                if send(&mut port, &mut Cursor::new(b"foo"), &mut state).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_receive_offset_overflow() {
+        let mut rx = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCW,
+            b"foo\0",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        ZDATA_HEADER.with_count(u32::MAX).write(&mut rx).unwrap();
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCE,
+            b"foo",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        let mut port = Port::new(rx);
+        let mut callback = |_: &FileInfo| Decision::Accept(u32::MAX);
+        let mut state = State::new();
+        state.set_pre_accept(&mut callback);
+        let mut sink = Sink::default();
+        assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        assert!(receive(&mut port, &mut sink, &mut state) == Err(Error::Data));
+        assert!(sink.data.is_empty());
+    }
+
+    #[test]
+    pub fn test_receive_late_join() {
+        // The receiver starts in the middle of the sender's retries:
+        let mut zrqinit = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZRQINIT, &[0; 4])
+            .write(&mut zrqinit)
+            .unwrap();
+        let mut rx = zrqinit[5..].to_vec();
+        for _ in 0..3 {
+            rx.extend_from_slice(b"rz\r");
+            rx.extend_from_slice(&zrqinit);
+        }
+        let files: [(&str, &[u8]); 1] = [("foo", b"foo")];
+        rx.extend_from_slice(&make_transcript(&files, 1000));
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        let mut steps = 0;
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            steps += 1;
+        }
+        assert_eq!(sink.data, b"foo");
+        // One step for each ZRQINIT, ZFILE, ZDATA, ZEOF and ZFIN:
+        assert_eq!(steps, 7);
+        let mut znak = vec![];
+        ZNAK_HEADER.write(&mut znak).unwrap();
+        assert!(!port.tx.windows(znak.len()).any(|w| w == znak));
+    }
+
+    #[test]
+    pub fn test_receive_back_to_back() {
+        let mut rx = make_transcript(&[("foo", b"foo")], 1000);
+        rx.extend_from_slice(b"OO");
+        let first = rx.len() as u64;
+        rx.extend_from_slice(&make_transcript(&[("bar", b"bar")], 1000));
+        rx.extend_from_slice(b"OO");
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut storage: [FileSummary; 1] = Default::default();
+        let mut state = State::new();
+        state.set_file_summaries(&mut storage);
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert_eq!(port.rx.position(), first);
+        state.reset();
+        assert!(state.stage() == Stage::Waiting);
+        assert!(state.file_summaries().is_empty());
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert_eq!(port.rx.position(), port.rx.get_ref().len() as u64);
+        assert_eq!(sink.data, b"foobar");
+        assert_eq!(state.summary().files_transferred(), 1);
+        assert_eq!(state.file_summaries()[0].name(), "bar");
+    }
+
+    #[rstest::rstest]
+    #[case::garbage(Quotas::new().with_garbage(50), Quota::Garbage)]
+    #[case::header(Quotas::new().with_header_len(8), Quota::Header)]
+    #[case::subpackets(Quotas::new().with_subpackets(10), Quota::Subpackets)]
+    #[case::duration(Quotas::new().with_duration(100), Quota::Duration)]
+    pub fn test_receive_quotas(#[case] quotas: Quotas, #[case] quota: Quota) {
+        let data = [0x55; 3000];
+        let mut rx = vec![0xaa; 100];
+        rx.extend_from_slice(&make_transcript(&[("foo", &data)], 100));
+        for hardened in [false, true] {
+            let mut port = Port::new(rx.clone());
+            let mut sink = Sink::default();
+            let time = Cell::new(0);
+            let clock = || {
+                time.set(time.get() + 100);
+                time.get()
+            };
+            let mut state = State::new();
+            state.set_clock(&clock);
+            if hardened {
+                assert!(state.set_quotas(quotas) == Ok(()));
+            }
+            let result = loop {
+                match receive(&mut port, &mut sink, &mut state) {
+                    Ok(()) if state.stage() == Stage::Done => break Ok(()),
+                    Ok(()) => (),
+                    Err(err) => break Err(err),
+                }
+            };
+            let expected = if hardened {
+                Err(Error::Quota(quota))
+            } else {
+                Ok(())
+            };
+            assert_eq!(result, expected);
+        }
+    }
+
+    #[test]
+    pub fn test_receive_quotas_garbage_timeout() {
+        // The garbage below the limit is followed by a timeout:
+        let mut port = Port::new(b"rz\r".to_vec());
+        let mut state = State::new();
+        assert!(state.set_quotas(Quotas::new()) == Ok(()));
+        assert!(receive(&mut port, &mut Sink::default(), &mut state) == Ok(()));
+        assert_eq!(state.summary().garbage(), 1);
+        assert!(receive(&mut port, &mut Sink::default(), &mut state) == Ok(()));
+        assert_eq!(state.summary().timeouts(), 1);
+    }
+
+    #[test]
+    pub fn test_quotas_duration_without_clock() {
+        let mut state = State::new();
+        let quotas = Quotas::new().with_duration(100);
+        assert!(state.set_quotas(quotas) == Err(Error::Data));
+        assert!(state.set_quotas(Quotas::new()) == Ok(()));
+        let clock = || 0;
+        state.set_clock(&clock);
+        assert!(state.set_quotas(quotas) == Ok(()));
+    }
+
+    #[rstest::rstest]
+    #[case(false)]
+    #[case(true)]
+    pub fn test_receive_drain(#[case] drain: bool) {
+        let mut zfin = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZFIN, &[0; 4])
+            .write(&mut zfin)
+            .unwrap();
+        let mut rx = make_transcript(&[("foo", b"foo")], 1000);
+        rx.extend_from_slice(b"OO");
+        // The sender repeats ZFIN, when "OO" is late:
+        rx.extend_from_slice(&zfin);
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        state.set_drain(drain);
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        let left = port.rx.get_ref().len() - usize::try_from(port.rx.position()).unwrap();
+        assert_eq!(left, if drain { 0 } else { zfin.len() });
+    }
+
+    #[rstest::rstest]
+    #[case(true, 0, Some(3))]
+    #[case(true, 1, Some(0))]
+    #[case(false, 0, None)]
+    pub fn test_receive_command(
+        #[case] enabled: bool,
+        #[case] zf0: u8,
+        #[case] status: Option<u32>,
+    ) {
+        let mut rx = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZCOMMAND, &[0; 4])
+            .with_zf(&[zf0, 0, 0, 0])
+            .write(&mut rx)
+            .unwrap();
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCW,
+            b"ls -l\0",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZFIN, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        let mut port = Port::new(rx);
+        let mut commands = vec![];
+        let mut handler = |command: &[u8]| {
+            commands.push(command.to_vec());
+            3
+        };
+        let mut state = State::new();
+        if enabled {
+            state.set_command_handler(&mut handler);
+        }
+        let mut sink = Sink::default();
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        let mut tx = &port.tx[..];
+        let mut frames = vec![];
+        while read_zpad(&mut tx, MAX_GARBAGE).is_ok() {
+            let header = Header::read(&mut tx).unwrap();
+            frames.push((header.frame(), header.count()));
+        }
+        let zcompl = frames.iter().find(|(frame, _)| *frame == Frame::ZCOMPL);
+        assert_eq!(zcompl.map(|(_, count)| *count), status);
+        assert_eq!(frames.last().map(|(frame, _)| *frame), Some(Frame::ZFIN));
+        let expected: &[&[u8]] = if enabled { &[b"ls -l"] } else { &[] };
+        assert_eq!(commands, expected);
+    }
+
+    /// Storage double, which completes a write only when waited for
+    #[derive(Default)]
+    struct Dma {
+        data: Vec<u8>,
+        in_flight: Option<(DeferredBuf, usize)>,
+        starts: usize,
+    }
+
+    impl DeferredWrite for Dma {
+        fn start(&mut self, buf: DeferredBuf, len: usize) -> Result<(), Error> {
+            assert!(self.in_flight.is_none());
+            self.in_flight = Some((buf, len));
+            self.starts += 1;
+            Ok(())
+        }
+
+        fn wait(&mut self) -> Result<Option<DeferredBuf>, Error> {
+            Ok(self.in_flight.take().map(|(buf, len)| {
+                self.data.extend_from_slice(&buf[..len]);
+                buf
+            }))
+        }
+    }
+
+    #[test]
+    pub fn test_receive_double_buffer() {
+        let data: Vec<u8> = (0..3000)
+            .map(|i| u8::try_from(i * 7 % 256).unwrap())
+            .collect();
+        let mut port = Port::new(make_transcript(&[("foo", &data)], 1000));
+        let buffers = [0; 2].map(|_| Vec::leak(vec![0; 512]));
+        let mut sink = DoubleBuffer::new(Dma::default(), buffers);
+        let mut state = State::new();
+        assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        // The last full buffer is still being written, while the reception
+        // continues:
+        assert_eq!(sink.get_ref().starts, 5);
+        assert!(sink.get_ref().in_flight.is_some());
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert_eq!(sink.get_ref().starts, 6);
+        assert!(sink.get_ref().in_flight.is_none());
+        assert!(sink.get_ref().data == data);
+    }
+
+    #[test]
+    pub fn test_receive_fatfs() {
+        use std::io::{Read as _, Seek as _};
+        // The files of `fatfs` implement the `std::io` traits:
+        let data: Vec<u8> = (0..3000)
+            .map(|i| u8::try_from(i * 7 % 256).unwrap())
+            .collect();
+        let mut image = Cursor::new(vec![0; 1024 * 1024]);
+        fatfs::format_volume(&mut image, fatfs::FormatVolumeOptions::new()).unwrap();
+        let fs = fatfs::FileSystem::new(image, fatfs::FsOptions::new()).unwrap();
+        let mut file = fs.root_dir().create_file("FOO").unwrap();
+        let mut port = Port::new(make_transcript(&[("foo", &data)], 1000));
+        let mut state = State::new();
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut file, &mut state) == Ok(()));
+        }
+        let mut output = vec![];
+        file.rewind().unwrap();
+        file.read_to_end(&mut output).unwrap();
+        assert!(output == data);
+    }
+
+    /// Serial port handle double, which counts the system calls
+    #[derive(Default)]
+    struct Tty {
+        rx: Cursor<Vec<u8>>,
+        tx: Vec<u8>,
+        reads: usize,
+        writes: usize,
+    }
+
+    impl std::io::Read for Tty {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            std::io::Read::read(&mut self.rx, buf)
+        }
+    }
+
+    impl std::io::Write for Tty {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            std::io::Write::write(&mut self.tx, buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_receive_buffered_port() {
+        let data = [0x55; 3000];
+        let rx = Tty {
+            rx: Cursor::new(make_transcript(&[("foo", &data)], 1000)),
+            ..Tty::default()
+        };
+        let mut port = BufferedPort::new(rx, Tty::default());
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        let mut steps = 0;
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            steps += 1;
+        }
+        assert!(sink.data == data);
+        let (rx, tx) = port.get_ref();
+        assert!(rx.rx.position() == u64::try_from(rx.rx.get_ref().len()).unwrap());
+        assert!(rx.reads < 5);
+        // The output is written once per step:
+        assert!(tx.writes <= steps);
+        assert!(first_frame(&tx.tx) == Some(Frame::ZRINIT));
+    }
+
+    #[rstest::rstest]
+    #[case(false)]
+    #[case(true)]
+    pub fn test_receive_crc16(#[case] corrupt: bool) {
+        let data = [0x55; 3000];
+        let mut rx = make_transcript_encoded(&[("foo", &data)], 1000, Encoding::ZBIN);
+        if corrupt {
+            // Corrupt a byte in the middle of the second data subpacket:
+            let at = rx.windows(2).position(|w| w == [ZDLE, Packet::ZCRCG as u8]);
+            rx[at.unwrap() + 100] ^= 1;
+        }
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        state.set_prefer_crc16(true);
+        for _ in 0..10 {
+            if state.stage() == Stage::Done {
+                break;
+            }
+            let _ = receive(&mut port, &mut sink, &mut state);
+        }
+        let mut tx = port.tx.as_slice();
+        read_zpad(&mut tx, MAX_GARBAGE).unwrap();
+        let zrinit = Header::read(&mut tx).unwrap();
+        assert_eq!(zrinit.frame(), Frame::ZRINIT);
+        assert!(!zrinit.zrinit().contains(Zrinit::CANFC32));
+        assert_eq!(sink.data == data, !corrupt);
+        assert_eq!(state.summary().retries() > 0, corrupt);
+    }
+
+    #[rstest::rstest]
+    #[case(false)]
+    #[case(true)]
+    pub fn test_receive_parity(#[case] tolerant: bool) {
+        let mut rx = vec![];
+        ZRQINIT_HEADER.write(&mut rx).unwrap();
+        // Even parity of a 7E1 link:
+        for b in &mut rx {
+            if b.count_ones() % 2 == 1 {
+                *b |= 0x80;
+            }
+        }
+        let mut port = Port::new(rx);
+        let mut state = State::new();
+        state.set_conformance(Conformance::Strict);
+        state.set_parity_tolerant(tolerant);
+        let _ = receive(&mut port, &mut Sink::default(), &mut state);
+        let mut tx = port.tx.as_slice();
+        read_zpad(&mut tx, MAX_GARBAGE).unwrap();
+        let header = Header::read(&mut tx).unwrap();
+        assert_eq!(header.frame(), Frame::ZRINIT);
+        assert_eq!(header.zrinit().contains(Zrinit::ESC8), tolerant);
+        assert_eq!(state.link_event().is_none(), tolerant);
+    }
+
+    #[rstest::rstest]
+    #[case(Encoding::ZBIN32, Encoding::ZBIN, false)]
+    #[case(Encoding::ZBIN32, Encoding::ZBIN, true)]
+    #[case(Encoding::ZBIN, Encoding::ZBIN32, false)]
+    #[case(Encoding::ZBIN, Encoding::ZBIN32, true)]
+    pub fn test_receive_crc_width(
+        #[case] crc: Encoding,
+        #[case] declared: Encoding,
+        #[case] tolerant: bool,
+    ) {
+        let mut rx = vec![];
+        Header::new(crc, Frame::ZRQINIT, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        rx[2] = declared as u8;
+        let mut port = Port::new(rx);
+        let mut state = State::new();
+        state.set_crc_width_tolerant(tolerant);
+        assert!(receive(&mut port, &mut Sink::default(), &mut state) == Ok(()));
+        assert_eq!(state.last_nak().is_none(), tolerant);
+        let mismatches = state.summary().crc_width_mismatches();
+        assert_eq!(mismatches, u32::from(tolerant));
+    }
+
+    #[test]
+    pub fn test_batch_progress() {
+        let rx = make_transcript(&[("foo", &[0x55; 1500]), ("bar", &[0xaa; 500])], 1000);
+        let mut session = Session::receive(Port::new(rx), Sink::default(), State::new());
+        let batches: Vec<BatchProgress> = session
+            .by_ref()
+            .filter_map(|event| match event.unwrap() {
+                Event::Batch(batch) => Some(batch),
+                _ => None,
+            })
+            .collect();
+        let batch = |file, bytes| BatchProgress {
+            file,
+            files: Some(2),
+            bytes,
+            total: Some(2000),
+        };
+        assert_eq!(batches.first(), Some(&batch(1, 0)));
+        assert!(batches.contains(&batch(1, 1500)));
+        assert!(batches.contains(&batch(2, 1500)));
+        assert_eq!(batches.last(), Some(&batch(2, 2000)));
+        let (_, _, state) = session.into_inner();
+        assert_eq!(state.batch_progress(), Some(batch(2, 2000)));
+        assert_eq!(state.file_info().files_remaining(), Some(1));
+        assert_eq!(state.file_info().bytes_remaining(), Some(500));
+    }
+
+    #[test]
+    pub fn test_session_events() {
+        let rx = make_transcript(&[("foo", &[0x55; 1500]), ("bar", &[])], 1000);
+        let session = Session::receive(Port::new(rx), Sink::default(), State::new());
+        let events: Vec<Event> = session.map(Result::unwrap).collect();
+        let started: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::FileStarted(info) => Some(info.name()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(started, ["foo", "bar"]);
+        let done = events
+            .iter()
+            .filter(|event| **event == Event::FileDone(FileStatus::Transferred))
+            .count();
+        assert_eq!(done, 2);
+        assert!(events.contains(&Event::Progress {
+            count: 1500,
+            size: 1500
+        }));
+        let Some(Event::SessionDone(summary)) = events.last() else {
+            panic!("session not done");
+        };
+        assert_eq!(summary.files_transferred(), 2);
+    }
+
+    #[rstest::rstest]
+    #[case(false)]
+    #[case(true)]
+    pub fn test_run_receive(#[case] cancel: bool) {
+        let data = [0x55; 3000];
+        let mut port = Port::new(make_transcript(&[("foo", &data), ("bar", &data)], 1000));
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        let mut progress = vec![];
+        let mut on_progress =
+            |info: &FileInfo, count| progress.push((info.name().to_owned(), count));
+        let mut on_pre_accept = |info: &FileInfo| match info.name() {
+            "bar" => Decision::Skip,
+            _ => Decision::Accept(0),
+        };
+        let mut steps = 0;
+        let mut on_cancel = || {
+            steps += 1;
+            cancel && steps > 3
+        };
+        let mut hooks = Hooks::new();
+        hooks.set_progress(&mut on_progress);
+        hooks.set_pre_accept(&mut on_pre_accept);
+        hooks.set_cancel(&mut on_cancel);
+        let result = run_receive(&mut port, &mut sink, &mut state, hooks);
+        assert!(state.stage() == Stage::Done);
+        if cancel {
+            assert!(result == Err(Error::Cancelled));
+            assert!(port.tx.ends_with(&ABORT));
+        } else {
+            let summary = result.unwrap();
+            assert_eq!(summary.files_transferred(), 1);
+            assert_eq!(summary.files_skipped(), 1);
+            assert!(sink.data == data);
+            assert_eq!(progress.last(), Some(&("foo".to_owned(), 3000)));
+        }
+    }
+
+    #[test]
+    pub fn test_set_file() {
+        let mut state = State::new();
+        assert!(state.set_file(&"x".repeat(257), 3) == Err(Error::Data));
+        assert!(state.set_file("bar", 5) == Ok(()));
+        assert!(state.set_file("foo", 25_000) == Ok(()));
+        assert_eq!(state.file_name(), "foo");
+        let mut file = Cursor::new(vec![0x55; 25_000]);
+        let mut port = Port::new(vec![]);
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        let mut rx = vec![];
+        ZRINIT_HEADER.write(&mut rx).unwrap();
+        let mut port = Port::new(rx);
+        assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+        assert!(state.stage() == Stage::Ready);
+        assert!(state.set_file("bar", 5) == Err(Error::Data));
+        assert_eq!(state.file_name(), "foo");
+    }
+
+    #[test]
+    pub fn test_receive_nak_header() {
+        let mut rx = vec![];
+        ZRQINIT_HEADER.write(&mut rx).unwrap();
+        // Flip a bit in a hex digit of the first flag:
+        rx[6] ^= 1;
+        let mut port = Port::new(rx);
+        let mut state = State::new();
+        assert!(receive(&mut port, &mut Sink::default(), &mut state) == Ok(()));
+        let nak = state.last_nak().unwrap();
+        assert_eq!(nak.reason(), NakReason::Header);
+        assert_eq!(nak.frame(), None);
+        assert_eq!(nak.encoding(), Some(Encoding::ZHEX));
+        assert_eq!(nak.bytes()[..4], [Encoding::ZHEX as u8, b'0', b'0', b'1']);
+        assert_eq!(state.summary().retries(), 1);
+    }
+
+    #[test]
+    pub fn test_receive_nak_subpacket() {
+        let data = [0x55; 3000];
+        let mut rx = make_transcript(&[("foo", &data)], 1000);
+        let at = rx.windows(2).position(|w| w == [ZDLE, Packet::ZCRCG as u8]);
+        rx[at.unwrap() + 100] ^= 1;
+        let mut port = Port::new(rx);
+        let mut state = State::new();
+        for _ in 0..3 {
+            let _ = receive(&mut port, &mut Sink::default(), &mut state);
+        }
+        let nak = state.last_nak().unwrap();
+        assert_eq!(nak.reason(), NakReason::Subpacket);
+        assert_eq!(nak.frame(), Some(Frame::ZDATA));
+        assert_eq!(nak.encoding(), Some(Encoding::ZBIN32));
+        assert!(nak.bytes().is_empty());
+        assert_eq!(nak.offset(), 1000);
+    }
+
+    #[test]
+    pub fn test_link_events() {
+        let mut rx = vec![0x55; MAX_GARBAGE];
+        let mut header = vec![];
+        ZRQINIT_HEADER.write(&mut header).unwrap();
+        header[6] ^= 1;
+        rx.extend_from_slice(&header);
+        let mut session = Session::receive(Port::new(rx), Sink::default(), State::new());
+        assert!(session.next() == Some(Ok(Event::Link(LinkEvent::Garbage))));
+        assert!(session.state().link_events_in_row() == 1);
+        assert!(session.next() == Some(Ok(Event::Link(LinkEvent::BadHeader))));
+        // The trailing CR, LF and XON of the rejected header:
+        assert!(session.next() == Some(Ok(Event::Link(LinkEvent::Garbage))));
+        assert!(session.next() == Some(Ok(Event::Link(LinkEvent::Timeout))));
+        let state = session.state();
+        assert!(state.link_event() == Some(LinkEvent::Timeout));
+        assert_eq!(state.link_events_in_row(), 4);
+        let summary = state.summary();
+        assert_eq!(summary.garbage(), 2);
+        assert_eq!(summary.bad_headers(), 1);
+        assert_eq!(summary.timeouts(), 1);
+    }
+
+    #[rstest::rstest]
+    #[case(Announce::Repeat, 4, None, Stage::Waiting)]
+    #[case(Announce::Passive, 0, None, Stage::Waiting)]
+    #[case(Announce::Limited(2), 2, Some(2), Stage::Done)]
+    pub fn test_send_announce(
+        #[case] announce: Announce,
+        #[case] announcements: u32,
+        #[case] unanswered: Option<usize>,
+        #[case] stage: Stage,
+    ) {
+        let mut state = State::new_file("foo", 3).unwrap();
+        state.set_announce(announce);
+        let mut written = vec![];
+        for step in 0..4 {
+            let mut port = Port::new(vec![]);
+            let result = send(&mut port, &mut Cursor::new(b"foo"), &mut state);
+            let expected = if unanswered == Some(step) {
+                Err(Error::Unanswered)
+            } else {
+                Ok(())
+            };
+            assert!(result == expected);
+            written.extend_from_slice(&port.tx);
+        }
+        assert_eq!(state.stage(), stage);
+        assert_eq!(state.announcements(), announcements);
+        let mut zrqinit = vec![];
+        ZRQINIT_HEADER.write(&mut zrqinit).unwrap();
+        let count = written
+            .windows(zrqinit.len())
+            .filter(|w| *w == zrqinit)
+            .count();
+        assert_eq!(count, announcements as usize);
+    }
+
+    #[test]
+    pub fn test_kermit_packet() {
+        let mut tx = vec![];
+        kermit::write_packet(&mut tx, kermit::Link::new(), 0, b'Y', &[]).unwrap();
+        assert_eq!(tx, b"\x01# Y>\r");
+    }
+
+    #[rstest::rstest]
+    #[case(false)]
+    #[case(true)]
+    pub fn test_kermit_loopback(#[case] corrupt: bool) {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut acks = vec![];
+        for seq in 0..64 {
+            kermit::write_packet(&mut acks, kermit::Link::new(), seq, b'Y', &[]).unwrap();
+        }
+        let mut port = Port::new(acks);
+        assert!(kermit::send(&mut port, &mut Cursor::new(&data), "foo#bar") == Ok(()));
+        let mut rx = port.tx;
+        if corrupt {
+            // Precede the first data packet with a corrupted copy:
+            let at = rx.windows(3).position(|w| w[0] == 0x01 && w[2] == b' ' + 2);
+            let at = at.unwrap();
+            let end = at + rx[at..].iter().position(|b| *b == b'\r').unwrap() + 1;
+            let mut copy = rx[at..end].to_vec();
+            copy[10] ^= 1;
+            rx.splice(at..at, copy);
+        }
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let name = kermit::receive(&mut port, &mut sink).unwrap();
+        assert_eq!(name, "foo#bar");
+        assert_eq!(sink.data, data);
+        let naks = port.tx.windows(4).filter(|w| w[0] == 0x01 && w[3] == b'N');
+        assert_eq!(naks.count(), usize::from(corrupt));
+    }
+
+    #[test]
+    pub fn test_kermit_long_name() {
+        let mut acks = vec![];
+        kermit::write_packet(&mut acks, kermit::Link::new(), 0, b'Y', &[]).unwrap();
+        let mut port = Port::new(acks);
+        let name = "x".repeat(200);
+        assert!(kermit::send(&mut port, &mut Cursor::new(b"foo"), &name) == Err(Error::Data));
+    }
+
+    #[test]
+    pub fn test_deque_port() {
+        let data: Vec<u8> = (0..=255).cycle().take(3000).collect();
+        let (mut to_receiver, mut to_sender) = (VecDeque::new(), VecDeque::new());
+        let mut file = Cursor::new(data.clone());
+        let mut sink = Sink::default();
+        let mut sender = State::new_file("foo", 3000).unwrap();
+        let mut receiver = State::new();
+        for _ in 0..100 {
+            if sender.stage() == Stage::Done && receiver.stage() == Stage::Done {
+                break;
+            }
+            if sender.stage() != Stage::Done {
+                let mut port = DequePort::new(&mut to_sender, &mut to_receiver);
+                send(&mut port, &mut file, &mut sender).unwrap();
+            }
+            if receiver.stage() != Stage::Done {
+                let mut port = DequePort::new(&mut to_receiver, &mut to_sender);
+                receive(&mut port, &mut sink, &mut receiver).unwrap();
+            }
+        }
+        assert!(sender.stage() == Stage::Done);
+        assert!(receiver.stage() == Stage::Done);
+        assert!(sink.data == data);
+    }
+
+    #[test]
+    pub fn test_receive_cancel() {
+        let data = [0x55; 3000];
+        let mut port = Port::new(make_transcript(&[("foo", &data)], 500));
+        let mut sink = Sink::default();
+        let token = AtomicBool::new(false);
+        let mut state = State::new();
+        state.set_cancel_token(&token);
+        state.set_checkpoint(1000);
+        state.set_step_budget(1);
+        while state.checkpoint().is_none() {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        // The token is set e.g. by an interrupt handler in the middle of the
+        // data:
+        token.store(true, Ordering::Relaxed);
+        let len = port.tx.len();
+        assert!(receive(&mut port, &mut sink, &mut state) == Err(Error::Cancelled));
+        assert_eq!(&port.tx[len..], &ABORT);
+        assert_eq!(sink.data.len(), 1000);
+        assert!(state.stage() == Stage::Done);
+        let context = state.last_error_context().unwrap();
+        assert_eq!(context.offset(), 1000);
+    }
+
+    #[test]
+    pub fn test_send_cancel() {
+        let token = AtomicBool::new(true);
+        let mut port = Port::new(vec![]);
+        let mut state = State::new_file("foo", 3).unwrap();
+        state.set_cancel_token(&token);
+        let result = send(&mut port, &mut Cursor::new(b"foo"), &mut state);
+        assert!(result == Err(Error::Cancelled));
+        assert_eq!(port.tx, ABORT);
+        assert!(state.stage() == Stage::Done);
+    }
+
+    /// Serial port double for the `futures` I/O traits
+    #[cfg(feature = "futures")]
+    struct AsyncPort {
+        rx: futures_lite::io::Cursor<Vec<u8>>,
+        tx: Vec<u8>,
+    }
+
+    #[cfg(feature = "futures")]
+    impl futures_lite::AsyncRead for AsyncPort {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::pin::Pin::new(&mut self.rx).poll_read(cx, buf)
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    impl futures_lite::AsyncWrite for AsyncPort {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::pin::Pin::new(&mut self.tx).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    pub fn test_receive_blocking() {
+        use futures_lite::io::Cursor as AsyncCursor;
+        let data = [0x55; 3000];
+        let rx = make_transcript(&[("foo", &data)], 1000);
+        let mut port = crate::Blocking::new(
+            AsyncPort {
+                rx: AsyncCursor::new(rx),
+                tx: vec![],
+            },
+            Duration::from_millis(100),
+        );
+        let mut file = crate::Blocking::new(AsyncCursor::new(vec![]), Duration::from_millis(100));
+        let mut state = State::new();
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut file, &mut state) == Ok(()));
+        }
+        assert!(file.into_inner().into_inner() == data);
+        assert!(first_frame(&port.get_ref().tx) == Some(Frame::ZRINIT));
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    pub fn test_receive_blocking_timeout() {
+        /// Port, which never receives data
+        struct Silent;
+
+        impl futures_lite::AsyncRead for Silent {
+            fn poll_read(
+                self: std::pin::Pin<&mut Self>,
+                _: &mut std::task::Context<'_>,
+                _: &mut [u8],
+            ) -> std::task::Poll<std::io::Result<usize>> {
+                std::task::Poll::Pending
+            }
+        }
+
+        let mut port = crate::Blocking::new(Silent, Duration::from_millis(10));
+        let mut buf = [0; 16];
+        assert!(std::io::Read::read(&mut port, &mut buf).unwrap() == 0);
+    }
+
+    #[test]
+    pub fn test_receive_step_budget() {
+        let data = [0x55; 3000];
+        let mut port = Port::new(make_transcript(&[("foo", &data)], 100));
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        state.set_step_budget(4);
+        let mut steps = 0;
+        while state.stage() != Stage::Done {
+            let len = sink.data.len();
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            assert!(sink.data.len() - len <= 400);
+            steps += 1;
+        }
+        assert_eq!(sink.data, data);
+        // ZFILE, ZEOF, ZFIN, and 30 subpackets in the steps of four:
+        assert_eq!(steps, 11);
+    }
+
+    #[test]
+    pub fn test_receive_flush() {
+        let mut port = Port::new(make_transcript(&[("foo", b"bar")], 1024));
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert_eq!(sink.data, b"bar");
+        assert_eq!(sink.flushes, 1);
+    }
+
+    #[rstest::rstest]
+    #[case::disabled(None, 30)]
+    #[case::partial(Some(450), 8)]
+    #[case::whole(Some(4096), 1)]
+    #[case::small(Some(50), 30)]
+    pub fn test_receive_coalesced_writes(#[case] capacity: Option<usize>, #[case] writes: usize) {
+        let data: Vec<u8> = (0..3000u32).map(|i| i.to_le_bytes()[0]).collect();
+        let mut port = Port::new(make_transcript(&[("foo", &data)], 100));
+        let mut sink = Sink::default();
+        let mut storage = vec![0; capacity.unwrap_or(0)];
+        let mut state = State::new();
+        if capacity.is_some() {
+            state.set_coalesced_writes(&mut storage);
+        }
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert_eq!(sink.data, data);
+        assert_eq!(sink.writes, writes);
+    }
+
+    #[test]
+    pub fn test_receive_checkpoint() {
+        let data = [0xaa; 1000];
+        let mut port = Port::new(make_transcript(&[("foo", &data)], 100));
+        let mut sink = Sink::default();
+        let mut offsets = vec![];
+        let mut state = State::new();
+        state.set_checkpoint(250);
+        state.set_step_budget(1);
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            offsets.extend(state.checkpoint());
+        }
+        assert_eq!(sink.data, data);
+        assert_eq!(sink.flushes, 5);
+        assert_eq!(offsets, [300, 500, 800, 1000]);
+    }
+
+    #[test]
+    pub fn test_send_checkpoint() {
+        let data = vec![0x55; 25_000];
+        let mut file = Cursor::new(&data[..]);
+        let mut state = State::new_file("foo", 25_000).unwrap();
+        state.set_checkpoint(5000);
+        let mut offsets = vec![];
+        for (frame, count) in SEND_ANSWERS {
+            let mut rx = vec![];
+            Header::new(Encoding::ZHEX, frame, &[0; 4])
+                .with_count(count)
+                .write(&mut rx)
+                .unwrap();
+            let mut port = Port::new(rx);
+            assert!(send(&mut port, &mut file, &mut state) == Ok(()));
+            offsets.push(state.checkpoint());
+        }
+        // The checkpoints follow the offsets acknowledged by the receiver, and
+        // are reached again after `ZRPOS` rewinds:
+        assert_eq!(
+            offsets,
+            [
+                None,
+                None,
+                Some(10_220),
+                None,
+                Some(11_720),
+                Some(21_940),
+                Some(25_000)
+            ]
+        );
+    }
+
+    /// Resume store double, which keeps the record in memory
+    #[derive(Default)]
+    struct Flash {
+        record: Option<ResumeRecord>,
+        saves: usize,
+    }
+
+    impl ResumeStore for Flash {
+        fn load(&mut self) -> Option<ResumeRecord> {
+            self.record.clone()
+        }
+
+        fn save(&mut self, record: &ResumeRecord) -> Result<(), Error> {
+            self.record = Some(record.clone());
+            self.saves += 1;
+            Ok(())
+        }
+    }
+
+    #[rstest::rstest]
+    #[case::short(NamePolicy::Short, "report.pdf", 0, Ok("REPORT.PDF"))]
+    #[case::short_long(NamePolicy::Short, "longfilename.text", 0, Ok("LONGFILE.TEX"))]
+    #[case::short_path(NamePolicy::Short, "dir/a b+c.tar.gz", 0, Ok("AB_CTAR.GZ"))]
+    #[case::short_dotfile(NamePolicy::Short, ".bashrc", 0, Ok("BASHRC"))]
+    #[case::short_empty(NamePolicy::Short, "", 0, Ok("_"))]
+    #[case::short_alternative(NamePolicy::Short, "longfilename.text", 1, Ok("LONGFI~1.TEX"))]
+    #[case::custom(PORTABLE, "my file (1).txt", 0, Ok("my_file_.txt"))]
+    #[case::custom_alternative(PORTABLE, "my file (1).txt", 12, Ok("my_fi~12.txt"))]
+    #[case::custom_extension(PORTABLE, "a.verylongextension", 0, Ok("a.verylongex"))]
+    #[case::short_full(NamePolicy::Short, "foo", 1_234_567, Err(Error::Data))]
+    #[case::custom_full(TINY, "abc.txt", 10, Err(Error::Data))]
+    #[case::custom_clamped(HUGE, &"a".repeat(300), 1, Ok(&*format!("{}~1", "a".repeat(254))))]
+    pub fn test_name_policy(
+        #[case] policy: NamePolicy,
+        #[case] name: &str,
+        #[case] n: u32,
+        #[case] expected: Result<&str, Error>,
+    ) {
+        let name = policy.alternative(name, n);
+        assert_eq!(
+            name.as_ref().map(String::as_str),
+            expected.as_ref().copied()
+        );
+    }
+
+    const PORTABLE: NamePolicy = NamePolicy::Custom {
+        max_len: 12,
+        allowed: NamePolicy::portable,
+    };
+
+    const TINY: NamePolicy = NamePolicy::Custom {
+        max_len: 3,
+        allowed: NamePolicy::portable,
+    };
+
+    const HUGE: NamePolicy = NamePolicy::Custom {
+        max_len: 1000,
+        allowed: NamePolicy::portable,
+    };
+
+    #[rstest::rstest]
+    #[case::no_room(TINY, None, Ok(()), 10)]
+    #[case::exhausted(NamePolicy::Short, None, Ok(()), 100)]
+    #[case::quota(NamePolicy::Short, Some(3), Err(Error::Quota(Quota::Alternatives)), 4)]
+    pub fn test_receive_name_policy_exhausted(
+        #[case] policy: NamePolicy,
+        #[case] alternatives: Option<u32>,
+        #[case] expected: Result<(), Error>,
+        #[case] checks: usize,
+    ) {
+        let mut rx = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCW,
+            b"abc.txt\x003\0",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        let mut port = Port::new(rx);
+        let mut checked = 0;
+        let mut exists = |_: &str| {
+            checked += 1;
+            true
+        };
+        let mut state = State::new();
+        state.set_name_policy(policy, &mut exists);
+        if let Some(alternatives) = alternatives {
+            assert!(state.set_quotas(Quotas::new().with_alternatives(alternatives)) == Ok(()));
+        }
+        let result = receive(&mut port, &mut Sink::default(), &mut state);
+        assert_eq!(result, expected);
+        if result.is_ok() {
+            // The file is skipped:
+            assert_eq!(
+                first_frame(&port.tx[port.tx.len() - 21..]),
+                Some(Frame::ZSKIP)
+            );
+        }
+        drop(state);
+        assert_eq!(checked, checks);
+    }
+
+    #[test]
+    pub fn test_receive_name_policy() {
+        let mut rx = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCW,
+            b"logs/Long File Name.txt\x003\0",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        let mut port = Port::new(rx);
+        let mut exists = |name: &str| ["LONGFILE.TXT", "LONGFI~1.TXT"].contains(&name);
+        let mut state = State::new();
+        state.set_name_policy(NamePolicy::Short, &mut exists);
+        assert!(receive(&mut port, &mut Sink::default(), &mut state) == Ok(()));
+        assert_eq!(state.file_name(), "LONGFI~2.TXT");
+        assert_eq!(
+            first_frame(&port.tx[port.tx.len() - 21..]),
+            Some(Frame::ZRPOS)
+        );
+    }
+
+    #[test]
+    pub fn test_receive_resume_store() {
+        let data: Vec<u8> = (0..1000)
+            .map(|i| u8::try_from(i * 7 % 256).unwrap())
+            .collect();
+        let transcript = |offset: usize, end: usize| {
+            let mut rx = vec![];
+            Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4])
+                .write(&mut rx)
+                .unwrap();
+            write_subpacket(
+                &mut rx,
+                Encoding::ZBIN32,
+                Packet::ZCRCW,
+                b"foo\x001000\0",
+                &EscapeSet::new(),
+            )
+            .unwrap();
+            ZDATA_HEADER
+                .with_count(u32::try_from(offset).unwrap())
+                .write(&mut rx)
+                .unwrap();
+            for chunk in data[offset..end].chunks(100) {
+                write_subpacket(
+                    &mut rx,
+                    Encoding::ZBIN32,
+                    Packet::ZCRCG,
+                    chunk,
+                    &EscapeSet::new(),
+                )
+                .unwrap();
+            }
+            rx
+        };
+        let mut flash = Flash::default();
+        // The power is lost after 400 bytes:
+        let mut port = Port::new(transcript(0, 400));
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        state.set_resume_store(250, &mut flash);
+        while receive(&mut port, &mut sink, &mut state) == Ok(()) {}
+        let record = flash.record.clone().unwrap();
+        assert_eq!(record.name(), "foo");
+        assert_eq!(record.size(), 1000);
+        assert_eq!(record.offset(), 300);
+        assert_eq!(record.crc(), Some(CRC32.checksum(&data[..300])));
+        assert_eq!(sink.flushes, 1);
+        // The transfer is resumed after the reboot:
+        let mut rx = transcript(300, 900);
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCE,
+            &data[900..],
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        Header::new(Encoding::ZBIN32, Frame::ZEOF, &[0; 4])
+            .with_count(1000)
+            .write(&mut rx)
+            .unwrap();
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        state.set_resume_store(250, &mut flash);
+        assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        assert_eq!(state.count(), 300);
+        while state.stage() != Stage::Waiting {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert!(sink.data == data[300..]);
+        let record = flash.record.clone().unwrap();
+        assert!(record.is_complete());
+        assert_eq!(record.crc(), Some(CRC32.checksum(&data)));
+        assert_eq!(flash.saves, 7);
+    }
+
+    #[test]
+    pub fn test_receive_resume() {
+        let mut port = Port::new(make_transcript(&[("foo", b"bar")], 1024));
+        let mut state = State::new();
+        state.set_count(2);
+        assert!(receive(&mut port, &mut Sink::default(), &mut state) == Ok(()));
+        let mut expected = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZRPOS, &[0; 4])
+            .with_count(2)
+            .write(&mut expected)
+            .unwrap();
+        assert!(port.tx.ends_with(&expected));
+    }
+
+    #[test]
+    pub fn test_receive_batch() {
+        let files: [(&str, &[u8]); 2] = [("foo", b"foo"), ("bar", &[0x55; 1500])];
+        let mut port = Port::new(make_transcript(&files, 1000));
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        let mut names = vec![];
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            if state.stage() == Stage::Ready {
+                let file = state.file_info();
+                assert_eq!(file.mtime(), MTIME);
+                assert_eq!(file.mode(), 0o100_644);
+                names.push((file.name().to_string(), file.size()));
+            }
+        }
+        assert_eq!(names, [("foo".to_string(), 3), ("bar".to_string(), 1500)]);
+        assert_eq!(sink.data, [files[0].1, files[1].1].concat());
+        assert_eq!(sink.flushes, 2);
+    }
+
+    #[test]
+    pub fn test_receive_batch_stages() {
+        // `ZEOF` of an empty file is accepted without `ZDATA`, and the
+        // receiver waits for the next file of the batch, which is `ZFIN`:
+        let mut port = Port::new(make_transcript(&[("foo", b"")], 100));
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        let mut steps = vec![];
+        for _ in 0..3 {
+            let len = port.tx.len();
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            steps.push((state.stage(), first_frame(&port.tx[len..])));
+        }
+        assert_eq!(
+            steps,
+            [
+                (Stage::Ready, Some(Frame::ZRINIT)),
+                (Stage::Waiting, Some(Frame::ZRINIT)),
+                (Stage::Done, Some(Frame::ZFIN)),
+            ]
+        );
+        assert_eq!(sink.flushes, 1);
+    }
+
+    #[test]
+    pub fn test_receive_batch_zfin_ready() {
+        // `ZFIN` is ignored after `ZFILE` has been accepted, until `ZEOF`:
+        let mut tail = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZEOF, &[0; 4])
+            .write(&mut tail)
+            .unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZFIN, &[0; 4])
+            .write(&mut tail)
+            .unwrap();
+        let mut rx = make_transcript(&[("foo", b"")], 100);
+        assert!(rx.ends_with(&tail));
+        rx.truncate(rx.len() - tail.len());
+        Header::new(Encoding::ZHEX, Frame::ZFIN, &[0; 4])
+            .write(&mut rx)
+            .unwrap();
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        let len = port.tx.len();
+        assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        assert!(state.stage() == Stage::Ready);
+        assert_eq!(port.tx.len(), len);
+    }
+
+    #[test]
+    pub fn test_tar_sink() {
+        let files: [(&str, &[u8]); 3] = [("foo", b"foo"), ("bar", &[0x55; 1024]), ("baz", b"")];
+        let mut port = Port::new(make_transcript(&files, 100));
+        let mut sink = TarSink::new(vec![]);
+        let mut state = State::new();
+        while state.stage() != Stage::Done {
+            assert!(receive_sink(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        let tar = sink.finish().ok().unwrap();
+        let mut offset = 0;
+        for (name, data) in files {
+            let header = &tar[offset..offset + 512];
+            assert_eq!(&header[..=name.len()], format!("{name}\0").as_bytes());
+            assert_eq!(
+                &header[124..136],
+                format!("{:011o}\0", data.len()).as_bytes()
+            );
+            assert_eq!(&header[136..148], format!("{MTIME:011o}\0").as_bytes());
+            assert_eq!(&header[257..263], b"ustar\0");
+            let checksum: u32 = header[..148]
+                .iter()
+                .chain(&[b' '; 8])
+                .chain(&header[156..])
+                .map(|b| u32::from(*b))
+                .sum();
+            assert_eq!(&header[148..156], format!("{checksum:06o}\0 ").as_bytes());
+            offset += 512;
+            assert_eq!(&tar[offset..offset + data.len()], data);
+            offset += data.len().div_ceil(512) * 512;
+        }
+        assert_eq!(&tar[offset..], [0; 1024]);
+    }
+
+    /// Sink, which records the names of the files begun in it
+    #[derive(Default)]
+    struct Entries {
+        names: Vec<std::string::String>,
+        data: Vec<u8>,
+    }
+
+    impl Write for Entries {
+        fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+            self.data.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    impl crate::Sink for Entries {
+        fn begin(&mut self, file: &FileInfo) -> Result<(), Error> {
+            self.names.push(file.name().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_receive_sink() {
+        let files: [(&str, &[u8]); 3] = [("foo", b"foo"), ("bar", b"bar"), ("baz", b"")];
+        let mut port = Port::new(make_transcript(&files, 1000));
+        let mut sink = Entries::default();
+        let mut callback = |file: &FileInfo| match file.name() {
+            "bar" => Decision::Skip,
+            _ => Decision::Accept(0),
+        };
+        let mut state = State::new();
+        state.set_pre_accept(&mut callback);
+        while state.stage() != Stage::Done {
+            assert!(receive_sink(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        // A skipped file is not begun:
+        assert_eq!(sink.names, ["foo", "baz"]);
+        assert_eq!(sink.data, b"foo");
+    }
+
+    #[rstest::rstest]
+    #[case::parent("../../etc/passwd", Some("etc/passwd"))]
+    #[case::absolute("/foo//./bar", Some("foo/bar"))]
+    #[case::backslash("..\\foo\\..\\bar", Some("foo/bar"))]
+    #[case::empty("/./..", None)]
+    pub fn test_tar_sink_name(#[case] name: &str, #[case] expected: Option<&str>) {
+        let mut file = FileInfo::new();
+        file.name = name.try_into().unwrap();
+        file.size = 3;
+        let mut sink = TarSink::new(vec![]);
+        assert!(sink.begin(&file) == Ok(()));
+        match expected {
+            Some(expected) => {
+                assert!(sink.write_all(b"foo") == Ok(()));
+                let tar = sink.finish().ok().unwrap();
+                assert_eq!(&tar[..=expected.len()], format!("{expected}\0").as_bytes());
+            }
+            None => assert!(sink.write_all(b"foo") == Err(Error::Data)),
+        }
+    }
+
+    #[test]
+    pub fn test_chunk_source() {
+        let chunks: [&[u8]; 4] = [b"abc", b"", b"defgh", b"ij"];
+        let mut source = ChunkSource::<_, 4>::new(chunks);
+        let mut buf = [0; 4];
+        assert!(source.read(&mut buf) == Ok(4));
+        assert_eq!(&buf, b"abcd");
+        assert!(source.read(&mut buf) == Ok(4));
+        assert_eq!(&buf, b"efgh");
+        assert!(source.seek(3) == Err(Error::Data));
+        assert!(source.seek(4) == Ok(()));
+        assert!(source.read(&mut buf) == Ok(4));
+        assert_eq!(&buf, b"efgh");
+        assert!(source.seek(9) == Ok(()));
+        assert!(source.read(&mut buf) == Ok(1));
+        assert_eq!(&buf[..1], b"j");
+        assert!(source.seek(6) == Ok(()));
+        assert!(source.read(&mut buf) == Ok(4));
+        assert_eq!(&buf, b"ghij");
+        assert!(source.read(&mut buf) == Ok(0));
+        assert!(source.seek(11) == Err(Error::Data));
+    }
+
+    #[test]
+    pub fn test_mapped_source() {
+        let mut source = MappedSource::new(b"abcdefghij");
+        let mut buf = [0; 4];
+        assert!(source.contents() == Some(&b"abcdefghij"[..]));
+        assert!(source.read(&mut buf) == Ok(4));
+        assert_eq!(&buf, b"abcd");
+        assert!(source.seek(8) == Ok(()));
+        assert!(source.read(&mut buf) == Ok(2));
+        assert_eq!(&buf[..2], b"ij");
+        assert!(source.read(&mut buf) == Ok(0));
+        assert!(source.read_byte() == Err(Error::Read));
+        assert!(source.seek(10) == Ok(()));
+        assert!(source.seek(11) == Err(Error::Data));
+    }
+
+    /// Headers answering a 25000-byte file: a burst, a resynchronization,
+    /// and the end of the file
+    const SEND_ANSWERS: [(Frame, u32); 7] = [
+        (Frame::ZRINIT, 0),
+        (Frame::ZRPOS, 0),
+        (Frame::ZACK, 10_220),
+        (Frame::ZRPOS, 1_500),
+        (Frame::ZACK, 11_720),
+        (Frame::ZACK, 21_940),
+        (Frame::ZACK, 25_000),
+    ];
+
+    /// Sends a 25000-byte file from `file` answered with `SEND_ANSWERS`, and
+    /// returns the written data
+    fn send_transcript<F>(file: &mut F) -> Vec<u8>
+    where
+        F: Read + Seek,
+    {
+        send_source_transcript(&mut Plain(file))
+    }
+
+    /// Sends a 25000-byte file from `source` with `send_source` answered with
+    /// `SEND_ANSWERS`, and returns the written data
+    fn send_source_transcript<S>(source: &mut S) -> Vec<u8>
+    where
+        S: Source,
+    {
+        send_negotiated_transcript(source, Zrinit::empty(), EscapeSet::new())
+    }
+
+    /// Sends a 25000-byte file from `source` with `send_source` answered with
+    /// `SEND_ANSWERS`, where the ZRINIT carries `zrinit`, and the sender
+    /// escapes `escape`, and returns the written data
+    fn send_negotiated_transcript<S>(source: &mut S, zrinit: Zrinit, escape: EscapeSet) -> Vec<u8>
+    where
+        S: Source,
+    {
+        let mut state = State::new_file("foo", 25_000).unwrap();
+        state.set_escape(escape);
+        let mut tx = vec![];
+        for (frame, count) in SEND_ANSWERS {
+            let mut rx = vec![];
+            let header = Header::new(Encoding::ZHEX, frame, &[0; 4]).with_count(count);
+            match frame {
+                Frame::ZRINIT => header.with_zrinit(zrinit),
+                _ => header,
+            }
+            .write(&mut rx)
+            .unwrap();
+            let mut port = Port::new(rx);
+            assert!(send_source(&mut port, source, &mut state) == Ok(()));
+            tx.extend_from_slice(&port.tx);
+        }
+        tx
+    }
+
+    /// File, which returns at most 7 bytes per read
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+            let len = buf.len().min(7);
+            Read::read(&mut self.0, &mut buf[..len])
+        }
+    }
+
+    impl Seek for Trickle {
+        fn seek(&mut self, offset: u32) -> Result<(), Error> {
+            Seek::seek(&mut self.0, offset)
+        }
+    }
+
+    #[test]
+    pub fn test_send_short_reads() {
+        let data: Vec<u8> = (0..=255).cycle().take(25_000).collect();
+        let expected = send_transcript(&mut Cursor::new(data.clone()));
+        assert!(send_transcript(&mut Trickle(Cursor::new(data.clone()))) == expected);
+        let mut trickle = Trickle(Cursor::new(data));
+        let mut buf = [0; 20];
+        assert!(trickle.read_exact(&mut buf) == Ok(()));
+        assert_eq!(buf[19], 19);
+        assert_eq!(trickle.read_byte(), Ok(20));
+        trickle.seek(24_990).unwrap();
+        assert!(trickle.read_exact(&mut buf) == Err(Error::Read));
+    }
+
+    #[test]
+    pub fn test_send_mapped_source() {
+        let data: Vec<u8> = (0..25_000)
+            .map(|i| u8::try_from(i * 7 % 256).unwrap())
+            .collect();
+        let path = make_dir("mapped-source").join("foo");
+        fs::write(&path, &data).unwrap();
+        let file = fs::File::open(&path).unwrap();
+        // SAFETY: The file is not modified while it is mapped.
+        let mmap = unsafe { memmap2::Mmap::map(&file) }.unwrap();
+        let expected = send_transcript(&mut Cursor::new(&data));
+        assert!(send_source_transcript(&mut MappedSource::new(&mmap)) == expected);
+        assert!(send_source_transcript(&mut MappedSource::new(&data)) == expected);
+        assert!(send_transcript(&mut MappedSource::new(&data)) == expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Source, which counts the subpackets taken from the escaped source of
+    /// the inner source
+    struct Counted<S>(S, usize);
+
+    impl<S: Source> Read for Counted<S> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+            self.0.read(buf)
+        }
+    }
+
+    impl<S: Source> Seek for Counted<S> {
+        fn seek(&mut self, offset: u32) -> Result<(), Error> {
+            self.0.seek(offset)
+        }
+    }
+
+    impl<S: Source> Source for Counted<S> {
+        fn escaped(&mut self) -> Option<&mut dyn EscapedSource> {
+            Some(self)
+        }
+    }
+
+    impl<S: Source> EscapedSource for Counted<S> {
+        fn next_escaped(
+            &mut self,
+            offset: u32,
+            len: usize,
+            encoding: Encoding,
+            escape: &EscapeSet,
+        ) -> Result<(u32, &[u8], u32), Error> {
+            self.1 += 1;
+            self.0
+                .escaped()
+                .ok_or(Error::Read)?
+                .next_escaped(offset, len, encoding, escape)
+        }
+    }
+
+    #[rstest::rstest]
+    #[case::rendezvous(0, Zrinit::CANFC32, EscapeSet::new())]
+    #[case::prefetch(4, Zrinit::CANFC32, EscapeSet::new())]
+    #[case::crc16(4, Zrinit::empty(), EscapeSet::new())]
+    #[case::control(4, Zrinit::CANFC32, EscapeSet::CONTROL)]
+    #[case::escctl(4, Zrinit::CANFC32 | Zrinit::ESCCTL, EscapeSet::new())]
+    #[case::crc16_control(0, Zrinit::empty(), EscapeSet::CONTROL)]
+    pub fn test_send_pipelined(
+        #[case] depth: usize,
+        #[case] zrinit: Zrinit,
+        #[case] escape: EscapeSet,
+    ) {
+        let data: Vec<u8> = (0..25_000)
+            .map(|i| u8::try_from(i * 7 % 256).unwrap())
+            .collect();
+        let expected =
+            send_negotiated_transcript(&mut Plain(&mut Cursor::new(&data)), zrinit, escape);
+        let mut source = Counted(Pipelined::new(Cursor::new(data), depth), 0);
+        assert!(send_negotiated_transcript(&mut source, zrinit, escape) == expected);
+        // The subpackets are taken from the worker, not read directly:
+        assert!(source.1 > 0);
+    }
+
+    /// Creates an empty temporary directory for a test
+    fn make_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zmodem2-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&dir).unwrap_or_default();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    pub fn test_receive_dir() {
+        let dir = make_dir("receive-dir");
+        let files: [(&str, &[u8]); 2] = [("../foo", b"foo"), ("bar", &[0x55; 1500])];
+        let mut port = Port::new(make_transcript(&files, 1000));
+        let mut state = State::new();
+        assert!(receive_dir(&mut port, &mut state, &dir) == Ok(()));
+        assert_eq!(fs::read(dir.join("foo")).unwrap(), files[0].1);
+        assert_eq!(fs::read(dir.join("bar")).unwrap(), files[1].1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[rstest::rstest]
+    #[case(NameRules::Posix, "../foo", Ok("foo"))]
+    #[case(NameRules::Posix, "..\\foo", Ok("foo"))]
+    #[case(NameRules::Posix, "foo/..", Err(Error::Data))]
+    #[case(NameRules::Posix, "con.txt ", Ok("con.txt "))]
+    #[case(NameRules::Windows, "c:\\dir\\foo", Ok("foo"))]
+    #[case(NameRules::Windows, "a<b>c:d?.txt", Ok("a_b_c_d_.txt"))]
+    #[case(NameRules::Windows, "foo. . ", Ok("foo"))]
+    #[case(NameRules::Windows, "con.txt ", Ok("_con.txt"))]
+    #[case(NameRules::Windows, "NUL", Ok("_NUL"))]
+    #[case(NameRules::Windows, "Com1 .tar.gz", Ok("_Com1 .tar.gz"))]
+    #[case(NameRules::Windows, "com10", Ok("com10"))]
+    #[case(NameRules::Windows, "...", Err(Error::Data))]
+    pub fn test_name_rules(
+        #[case] rules: NameRules,
+        #[case] name: &str,
+        #[case] expected: Result<&str, Error>,
+    ) {
+        assert!(rules.sanitize(name).as_deref() == expected.as_deref());
+    }
+
+    #[test]
+    pub fn test_receive_dir_failure() {
+        let dir = make_dir("receive-dir-failure");
+        let mut transcript = make_transcript(&[("foo", &[0x55; 1500])], 1000);
+        transcript.truncate(transcript.len() / 2);
+        let mut port = Port::new(transcript);
+        let mut state = State::new();
+        assert!(receive_dir(&mut port, &mut state, &dir) == Err(Error::Read));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_receive_dir_commit_failure() {
+        let dir = make_dir("receive-dir-commit-failure");
+        // The final name is taken by a directory, which cannot be replaced:
+        fs::create_dir(dir.join("foo")).unwrap();
+        fs::write(dir.join("foo").join("bar"), b"bar").unwrap();
+        let mut port = Port::new(make_transcript(&[("foo", b"foo")], 1000));
+        let mut state = State::new();
+        assert!(receive_dir(&mut port, &mut state, &dir) == Err(Error::Write));
+        assert!(!dir.join("foo.part").exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_receive_pre_accept() {
+        let files: [(&str, &[u8]); 3] = [("foo", b""), ("bar", b"bar"), ("baz", b"baz")];
+        let mut port = Port::new(make_transcript(&files, 1000));
+        let mut sink = Sink::default();
+        let mut callback = |file: &FileInfo| match file.name() {
+            "foo" => Decision::Skip,
+            "bar" => Decision::Rename(String::try_from("qux").unwrap()),
+            _ => Decision::Accept(0),
+        };
+        let mut state = State::new();
+        state.set_pre_accept(&mut callback);
+        let mut names = vec![];
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            if state.stage() == Stage::Ready {
+                names.push(state.file_name().to_string());
             }
-            ZRPOS_HEADER.with_count(0).write(port)
         }
-        _ => ZNAK_HEADER.write(port).or(Err(Error::Data)),
+        assert_eq!(names, ["qux", "baz"]);
+        assert_eq!(sink.data, b"barbaz");
+        let mut zskip = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZSKIP, &[0; 4])
+            .write(&mut zskip)
+            .unwrap();
+        assert_eq!(
+            port.tx.windows(zskip.len()).filter(|w| *w == zskip).count(),
+            1
+        );
     }
-}
 
-/// Writes ZDATA
-fn write_zdata<P, F>(port: &mut P, buf: &mut Buffer, file: &mut F, offset: u32) -> Result<(), Error>
-where
-    P: Read + Write,
-    F: Read + Seek,
-{
-    let mut offset = offset;
-    buf.set_len(BUFFER_SIZE - 2);
-    file.seek(offset)?;
-    let mut count: u32 = file.read(buf)?;
-    if count == 0 {
-        ZEOF_HEADER.with_count(offset).write(port)?;
-        return Ok(());
-    }
-    ZDATA_HEADER.with_count(offset).write(port)?;
-    for _ in 1..SUBPACKET_PER_ACK {
+    #[rstest::rstest]
+    #[case(Newline::Lf, 2, b"a\nb\rc\nd\r\r")]
+    #[case(Newline::CrLf, 2, b"a\r\nb\rc\r\nd\r\r")]
+    #[case(Newline::Lf, 0, b"a\r\nb\rc\r\nd\r\r")]
+    pub fn test_receive_newline(
+        #[case] newline: Newline,
+        #[case] zf0: u8,
+        #[case] expected: &[u8],
+    ) {
+        let data = b"a\r\nb\rc\r\nd\r\r";
+        let mut rx = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4])
+            .with_zf(&[zf0, 0, 0, 0])
+            .write(&mut rx)
+            .unwrap();
         write_subpacket(
-            port,
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCW,
+            b"foo\0",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        ZDATA_HEADER.write(&mut rx).unwrap();
+        // The first CRLF is split between the subpackets:
+        write_subpacket(
+            &mut rx,
             Encoding::ZBIN32,
             Packet::ZCRCG,
-            &buf[..count as usize],
-        )?;
-        offset += count;
+            &data[..2],
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCE,
+            &data[2..],
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        Header::new(Encoding::ZBIN32, Frame::ZEOF, &[0; 4])
+            .with_count(u32::try_from(data.len()).unwrap())
+            .write(&mut rx)
+            .unwrap();
+        let mut port = Port::new(rx);
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        state.set_newline(newline);
+        for _ in 0..3 {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        assert!(state.stage() == Stage::Waiting);
+        assert_eq!(sink.data, expected);
+    }
 
-        count = file.read(buf)?;
-        if (count as usize) < buf.len() {
-            break;
+    #[test]
+    pub fn test_receive_dry_run() {
+        let files: [(&str, &[u8]); 3] = [("foo", b"foo"), ("bar", b""), ("baz", b"baz")];
+        let mut port = Port::new(make_transcript(&files, 1000));
+        let mut sink = Sink::default();
+        let mut storage: [FileInfo; 2] = Default::default();
+        let mut state = State::new();
+        state.set_dry_run(&mut storage);
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            assert!(state.stage() != Stage::Ready);
         }
+        assert!(sink.data.is_empty());
+        let offered: Vec<_> = state
+            .offered_files()
+            .iter()
+            .map(|file| (file.name(), file.size(), file.mtime()))
+            .collect();
+        assert_eq!(offered, [("foo", 3, MTIME), ("bar", 0, MTIME)]);
+        assert_eq!(state.summary().files_skipped(), 3);
     }
-    write_subpacket(
-        port,
-        Encoding::ZBIN32,
-        Packet::ZCRCW,
-        &buf[..count as usize],
-    )
-}
 
-/// Reads ZDATA
-fn read_zdata<P, F>(
-    port: &mut P,
-    state: &mut State,
-    encoding: Encoding,
-    file: &mut F,
-) -> Result<(), Error>
-where
-    P: Read + Write,
-    F: Write,
-{
-    loop {
-        let zcrc = match read_subpacket(port, &mut state.buf, encoding) {
-            Ok(zcrc) => {
-                if state.buf.is_empty() {
-                    ZRPOS_HEADER.with_count(state.count).write(port)?;
-                }
-                zcrc
+    #[test]
+    pub fn test_receive_error_context() {
+        struct Full;
+        impl crate::Write for Full {
+            fn write_all(&mut self, _: &[u8]) -> Result<(), Error> {
+                Err(Error::Write)
             }
-            Err(Error::Data) => {
-                ZNAK_HEADER.with_count(state.count).write(port)?;
-                continue;
+        }
+        let files: [(&str, &[u8]); 1] = [("foo", &[0x55; 1500])];
+        let mut port = Port::new(make_transcript(&files, 1000));
+        let mut state = State::new();
+        let result = loop {
+            match receive(&mut port, &mut Full, &mut state) {
+                Ok(()) => assert!(state.stage() != Stage::Done),
+                Err(err) => break err,
             }
-            Err(err) => return Err(err),
         };
-        file.write_all(&state.buf)?;
-        state.count += u32::try_from(state.buf.len()).map_err(|_| Error::Data)?;
-        match zcrc {
-            Packet::ZCRCW => {
-                ZACK_HEADER.with_count(state.count).write(port)?;
-                return Ok(());
-            }
-            Packet::ZCRCE => return Ok(()),
-            Packet::ZCRCQ => {
-                ZACK_HEADER.with_count(state.count).write(port)?;
-            }
-            Packet::ZCRCG => (),
-        }
-    }
-}
-
-/// Skips (ZPAD, [ZPAD,] ZDLE) sequence.
-fn read_zpad<P>(port: &mut P) -> Result<(), Error>
-where
-    P: Read,
-{
-    if port.read_byte()? != ZPAD {
-        return Err(Error::Data);
+        assert_eq!(result, Error::Write);
+        let context: ErrorContext = state.last_error_context().unwrap();
+        assert_eq!(context.stage(), Stage::Ready);
+        assert_eq!(context.frame(), Some(Frame::ZDATA));
+        assert_eq!(context.offset(), 0);
     }
 
-    let mut b = port.read_byte()?;
-    if b == ZPAD {
-        b = port.read_byte()?;
+    #[test]
+    pub fn test_expected_frames() {
+        let files: [(&str, &[u8]); 1] = [("foo", b"foo")];
+        let mut port = Port::new(make_transcript(&files, 1000));
+        let mut sink = Sink::default();
+        let mut state = State::new();
+        assert!(state.expected_frames().is_empty());
+        let mut expected = vec![];
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+            expected.push(state.expected_frames());
+        }
+        assert_eq!(
+            expected,
+            [
+                &[Frame::ZDATA, Frame::ZEOF][..],
+                &[Frame::ZDATA, Frame::ZEOF, Frame::ZFIN],
+                &[Frame::ZRQINIT, Frame::ZFILE, Frame::ZFIN],
+                &[],
+            ]
+        );
+        let mut port = Port::new(vec![]);
+        let mut state = State::new_file("foo", 3).unwrap();
+        assert!(send(&mut port, &mut Cursor::new(b"foo"), &mut state) == Ok(()));
+        assert_eq!(state.expected_frames(), [Frame::ZRINIT]);
     }
 
-    if b == ZDLE {
-        return Ok(());
+    #[rstest::rstest]
+    #[case(0)]
+    #[case(1)]
+    #[case(1500)]
+    #[case(30000)]
+    pub fn test_simulate(#[case] len: usize) {
+        let data: Vec<u8> = (0..len)
+            .map(|i| u8::try_from(i * 7 % 256).unwrap())
+            .collect();
+        let (received, sender, receiver) = simulate(&data, &mut |_, _| ());
+        assert_eq!(received, data);
+        assert_eq!(sender.files_transferred(), 1);
+        assert_eq!(receiver.files_transferred(), 1);
+        assert_eq!(receiver.bytes(), len as u64);
+        assert_eq!(receiver.retries(), 0);
     }
 
-    Err(Error::Data)
-}
-
-/// Reads and unescapes a ZMODEM protocol subpacket
-fn read_subpacket<P>(port: &mut P, buf: &mut Buffer, encoding: Encoding) -> Result<Packet, Error>
-where
-    P: Read,
-{
-    buf.clear();
-    let result = loop {
-        let byte = port.read_byte()?;
-        if byte == ZDLE {
-            let byte = port.read_byte()?;
-            if let Ok(packet) = Packet::try_from(byte) {
-                buf.push(packet as u8);
-                break packet;
-            }
-            buf.push(UNZDLE_TABLE[byte as usize]);
-        } else {
-            buf.push(byte);
+    #[rstest::rstest]
+    #[case(true, true)]
+    #[case(true, false)]
+    #[case(false, true)]
+    pub fn test_simulate_codec(#[case] send_codec: bool, #[case] receive_codec: bool) {
+        let data: Vec<u8> = b"abcdefghijklmnopqrstuvwxyz"
+            .iter()
+            .copied()
+            .cycle()
+            .take(5000)
+            .collect();
+        let (mut a, mut b) = (Xor, Xor);
+        let mut sender = State::new_file("foo", 5000).unwrap();
+        if send_codec {
+            sender.set_codec(&mut a);
         }
-
-        if buf.len() == buf.capacity() {
-            let packet = skip_subpacket_tail(port, encoding)?;
-            buf.set_len(0);
-            return Ok(packet);
+        let mut receiver = State::new();
+        if receive_codec {
+            receiver.set_codec(&mut b);
         }
-    };
+        let mut wire = vec![];
+        let (received, _) =
+            simulate_states(&mut sender, &mut receiver, &data, IDEAL, &mut |_, b| {
+                wire.push(*b);
+            });
+        assert!(received == data);
+        let plain = wire.windows(64).any(|w| w == &data[1000..1064]);
+        assert_eq!(plain, !(send_codec && receive_codec));
+    }
 
-    let crc_len = if encoding == Encoding::ZBIN32 { 4 } else { 2 };
-    let mut crc = [0u8; 4];
-    for b in crc.iter_mut().take(crc_len) {
-        *b = read_byte_unescaped(port)?;
+    #[test]
+    pub fn test_receive_unsupported_transport() {
+        let mut rx = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE, &[0; 4])
+            .with_zf(&[0, 0, Transport::Compress.option(), 0])
+            .write(&mut rx)
+            .unwrap();
+        write_subpacket(
+            &mut rx,
+            Encoding::ZBIN32,
+            Packet::ZCRCW,
+            b"foo\0",
+            &EscapeSet::new(),
+        )
+        .unwrap();
+        let mut port = Port::new(rx);
+        let mut state = State::new();
+        assert!(receive(&mut port, &mut Sink::default(), &mut state) == Ok(()));
+        assert!(state.stage() == Stage::Waiting);
+        let mut zskip = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZSKIP, &[0; 4])
+            .write(&mut zskip)
+            .unwrap();
+        assert!(port.tx.ends_with(&zskip));
     }
-    check_crc(buf, &crc[..crc_len], encoding)?;
 
-    // Pop ZCRC
-    buf.pop().unwrap();
-    Ok(result)
-}
+    #[rstest::rstest]
+    #[case(BAUD_9600)]
+    #[case(SATELLITE)]
+    pub fn test_simulate_line(#[case] profile: Profile) {
+        let data = [0x55; 15000];
+        let mut sender = State::new_file("foo", 15000).unwrap();
+        let mut receiver = State::new();
+        let (received, elapsed) =
+            simulate_states(&mut sender, &mut receiver, &data, profile, &mut |_, _| ());
+        assert!(received == data);
+        // The data alone takes this long to transmit:
+        let transmit = 15000 * 1_000_000 / profile.rate;
+        // ZRQINIT, ZRINIT, ZFILE, ZRPOS, two bursts with ZACK, ZEOF, ZRINIT
+        // and ZFIN in both directions are each a one-way trip:
+        let trips = 11 * profile.latency;
+        assert!(elapsed >= transmit + trips);
+        assert!(elapsed <= 2 * (transmit + trips));
+    }
 
-/// Skips the tail of the subpacket (including CRC).
-fn skip_subpacket_tail<P>(port: &mut P, encoding: Encoding) -> Result<Packet, Error>
-where
-    P: Read,
-{
-    let result;
-    loop {
-        let byte = port.read_byte()?;
-        if byte == ZDLE {
-            let byte = port.read_byte()?;
-            if let Ok(packet) = Packet::try_from(byte) {
-                result = packet;
-                break;
-            }
-        }
+    #[rstest::rstest]
+    #[case(1)]
+    #[case(3)]
+    pub fn test_simulate_step_budget(#[case] budget: usize) {
+        let data: Vec<u8> = (0..15000)
+            .map(|i| u8::try_from(i * 7 % 256).unwrap())
+            .collect();
+        let len = u32::try_from(data.len()).unwrap();
+        let mut sender = State::new_file("foo", len).unwrap();
+        let mut receiver = State::new();
+        sender.set_step_budget(budget);
+        receiver.set_step_budget(budget);
+        let (output, _) = simulate_states(&mut sender, &mut receiver, &data, IDEAL, &mut |_, _| ());
+        assert!(output == data);
+        assert_eq!(sender.summary().retries(), 0);
+        assert_eq!(receiver.summary().retries(), 0);
     }
-    let crc_len = if encoding == Encoding::ZBIN32 { 4 } else { 2 };
-    for _ in 0..crc_len {
-        read_byte_unescaped(port)?;
+
+    #[test]
+    pub fn test_simulate_strict() {
+        let data = [0x55; 15000];
+        let mut sender = State::new_file("foo", 15000).unwrap();
+        let mut receiver = State::new();
+        sender.set_conformance(Conformance::Strict);
+        receiver.set_conformance(Conformance::Strict);
+        let (output, _) = simulate_states(&mut sender, &mut receiver, &data, IDEAL, &mut |_, _| ());
+        assert!(output == data);
+        assert_eq!(sender.summary().retries(), 0);
+        assert_eq!(receiver.summary().retries(), 0);
     }
-    Ok(result)
-}
 
-fn write_subpacket<P>(
-    port: &mut P,
-    encoding: Encoding,
-    kind: Packet,
-    data: &[u8],
-) -> Result<(), Error>
-where
-    P: Write,
-{
-    let kind = kind as u8;
-    write_slice_escaped(port, data)?;
-    port.write_byte(ZDLE)?;
-    port.write_byte(kind)?;
-    match encoding {
-        Encoding::ZBIN32 => {
-            let mut digest = CRC32.digest();
-            digest.update(data);
-            digest.update(&[kind]);
-            write_slice_escaped(port, &digest.finalize().to_le_bytes())
-        }
-        Encoding::ZBIN => {
-            let mut digest = CRC16.digest();
-            digest.update(data);
-            digest.update(&[kind]);
-            write_slice_escaped(port, &digest.finalize().to_be_bytes())
-        }
-        Encoding::ZHEX => {
-            unimplemented!()
-        }
+    #[test]
+    pub fn test_simulate_back_to_back() {
+        let clock = Cell::new(0);
+        let mut no_fault_a = |_: usize, _: &mut u8| ();
+        let mut no_fault_b = |_: usize, _: &mut u8| ();
+        let mut to_a = Line::new(IDEAL, &clock, &mut no_fault_a);
+        let mut to_b = Line::new(IDEAL, &clock, &mut no_fault_b);
+        let mut a = State::new_file("foo", 3).unwrap();
+        let mut b = State::new();
+        let received = simulate_session(&mut a, &mut b, b"foo", &mut to_a, &mut to_b);
+        assert_eq!(received, b"foo");
+        // The sides swap the directions on the same line:
+        a.reset();
+        b.reset_file("bar", 3).unwrap();
+        let received = simulate_session(&mut b, &mut a, b"bar", &mut to_b, &mut to_a);
+        assert_eq!(received, b"bar");
+        assert_eq!(a.summary().files_transferred(), 1);
+        assert_eq!(b.summary().files_transferred(), 1);
+        assert_eq!(a.summary().retries(), 0);
     }
-}
 
-fn check_crc(data: &[u8], crc: &[u8], encoding: Encoding) -> Result<(), Error> {
-    let mut crc2 = [0u8; 4];
-    let crc2_len = make_crc(data, &mut crc2, encoding);
-    if *crc == crc2[..crc2_len] {
-        Ok(())
-    } else {
-        Err(Error::Data)
+    #[test]
+    pub fn test_simulate_fault() {
+        let data = [0x55; 15000];
+        let (data_out, _, receiver) = simulate(&data, &mut |i, b| {
+            if i == 5000 {
+                *b ^= 0x01;
+            }
+        });
+        assert_eq!(data_out, data);
+        assert!(receiver.retries() > 0);
     }
-}
 
-fn make_crc(data: &[u8], out: &mut [u8], encoding: Encoding) -> usize {
-    if encoding == Encoding::ZBIN32 {
-        let crc = CRC32.checksum(data).to_le_bytes();
-        out[..4].copy_from_slice(&crc[..4]);
-        4
-    } else {
-        let crc = CRC16.checksum(data).to_be_bytes();
-        out[..2].copy_from_slice(&crc[..2]);
-        2
+    #[test]
+    pub fn test_receive_summary() {
+        let files: [(&str, &[u8]); 3] = [("foo", b"foo"), ("bar", b"bar"), ("baz", &[0x55; 1500])];
+        let mut port = Port::new(make_transcript(&files, 1000));
+        let mut sink = Sink::default();
+        let mut callback = |file: &FileInfo| match file.name() {
+            "bar" => Decision::Skip,
+            _ => Decision::Accept(0),
+        };
+        let mut storage: [FileSummary; 2] = Default::default();
+        let time = Cell::new(100);
+        let clock = || {
+            time.set(time.get() + 10);
+            time.get()
+        };
+        let mut state = State::new();
+        state.set_pre_accept(&mut callback);
+        state.set_file_summaries(&mut storage);
+        state.set_clock(&clock);
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut port, &mut sink, &mut state) == Ok(()));
+        }
+        let summary = state.summary();
+        assert_eq!(summary.files_transferred(), 2);
+        assert_eq!(summary.files_skipped(), 1);
+        assert_eq!(summary.bytes(), 1503);
+        assert_eq!(summary.retries(), 0);
+        assert!(summary.duration().is_some_and(|duration| duration > 0));
+        let entries: Vec<_> = state
+            .file_summaries()
+            .iter()
+            .map(|file| (file.name().to_string(), file.count(), file.status()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("foo".to_string(), 3, FileStatus::Transferred),
+                ("bar".to_string(), 0, FileStatus::Skipped),
+            ]
+        );
     }
-}
 
-#[allow(dead_code)]
-fn write_slice_escaped<P>(port: &mut P, buf: &[u8]) -> Result<(), Error>
-where
-    P: Write,
-{
-    for value in buf {
-        write_byte_escaped(port, *value)?;
+    #[test]
+    pub fn test_send_dir() {
+        let src = make_dir("send-dir-src");
+        let dst = make_dir("send-dir-dst");
+        let files: [(&str, &[u8]); 4] = [
+            ("a.bin", b"a"),
+            ("b.bin", &[0x55; 15000]),
+            ("c.bin", b""),
+            ("d.txt", b"d"),
+        ];
+        for (name, data) in files {
+            fs::write(src.join(name), data).unwrap();
+        }
+        fs::create_dir(src.join("e.bin")).unwrap();
+        let (mut a, mut b) = make_pipe();
+        let sender = thread::spawn(move || {
+            let mut progress = vec![];
+            let mut callback = |file: &FileInfo, count| {
+                progress.push((file.name().to_string(), count));
+            };
+            let result = send_dir(&mut a, &src, Some("*.bin"), &mut callback);
+            fs::remove_dir_all(&src).unwrap();
+            (result, progress)
+        });
+        let mut state = State::new();
+        assert!(receive_dir(&mut b, &mut state, &dst) == Ok(()));
+        drop(b);
+        let (result, progress) = sender.join().unwrap();
+        assert!(result == Ok(()));
+        for (name, data) in &files[..3] {
+            let size = u32::try_from(data.len()).unwrap();
+            assert_eq!(fs::read(dst.join(name)).unwrap(), *data);
+            assert!(progress.contains(&((*name).to_string(), size)));
+        }
+        assert_eq!(fs::read_dir(&dst).unwrap().count(), 3);
+        fs::remove_dir_all(&dst).unwrap();
     }
 
-    Ok(())
-}
+    /// Sends bytes of every value from `a` to `b` with two threads, and
+    /// returns the sent and the received data
+    fn transfer<A, B>(mut a: A, mut b: B) -> (Vec<u8>, Vec<u8>)
+    where
+        A: Read + crate::Write + Send + 'static,
+        B: Read + crate::Write,
+    {
+        let data: Vec<u8> = (0..20000u32).map(|i| i.to_le_bytes()[0]).collect();
+        let mut file = Cursor::new(data.clone());
+        let sender = thread::spawn(move || {
+            let mut state = State::new_file("foo", 20000).unwrap();
+            while state.stage() != Stage::Done {
+                send(&mut a, &mut file, &mut state)?;
+            }
+            Ok::<(), Error>(())
+        });
+        let mut sink = vec![];
+        let mut state = State::new();
+        while state.stage() != Stage::Done {
+            assert!(receive(&mut b, &mut sink, &mut state) == Ok(()));
+        }
+        assert!(sender.join().unwrap() == Ok(()));
+        (data, sink)
+    }
 
-fn write_byte_escaped<P>(port: &mut P, value: u8) -> Result<(), Error>
-where
-    P: Write,
-{
-    let escaped = ZDLE_TABLE[value as usize];
-    if escaped != value {
-        port.write_byte(ZDLE)?;
+    #[test]
+    pub fn test_unix_stream() {
+        let timeout = Duration::from_secs(1);
+        let (a, b) = UnixStream::pair().unwrap();
+        let a = BufferedPort::from_unix_stream(a, timeout).unwrap();
+        let b = BufferedPort::from_unix_stream(b, timeout).unwrap();
+        let (data, sink) = transfer(a, b);
+        assert_eq!(sink, data);
     }
-    port.write_byte(escaped)
-}
 
-fn read_byte_unescaped<P>(port: &mut P) -> Result<u8, Error>
-where
-    P: Read,
-{
-    let b = port.read_byte()?;
-    Ok(if b == ZDLE {
-        UNZDLE_TABLE[port.read_byte()? as usize]
-    } else {
-        b
-    })
-}
+    /// RTT channel double, which holds at most 64 bytes
+    #[derive(Clone, Default)]
+    struct Channel(Arc<Mutex<VecDeque<u8>>>);
 
-#[cfg(test)]
-mod tests {
-    use crate::{
-        read_subpacket, read_zpad, write_subpacket, Buffer, Encoding, Error, Frame, Header, Packet,
-        XON, ZDLE, ZPAD,
-    };
+    impl Channel {
+        fn write(&self, buf: &[u8]) -> usize {
+            let mut queue = self.0.lock().unwrap();
+            let len = buf.len().min(64 - queue.len());
+            queue.extend(&buf[..len]);
+            len
+        }
 
-    #[rstest::rstest]
-    #[case(Encoding::ZBIN, Frame::ZRQINIT, &[0; 4], &[ZPAD, ZDLE, Encoding::ZBIN as u8, 0, 0, 0, 0, 0, 0, 0])]
-    #[case(Encoding::ZBIN32, Frame::ZRQINIT, &[0; 4], &[ZPAD, ZDLE, Encoding::ZBIN32 as u8, 0, 0, 0, 0, 0, 29, 247, 34, 198])]
-    #[case(Encoding::ZBIN, Frame::ZRQINIT, &[1; 4], &[ZPAD, ZDLE, Encoding::ZBIN as u8, 0, 1, 1, 1, 1, 98, 148])]
-    #[case(Encoding::ZHEX, Frame::ZRQINIT, &[1; 4], &[ZPAD, ZPAD, ZDLE, Encoding::ZHEX as u8, b'0', b'0', b'0', b'1', b'0', b'1', b'0', b'1', b'0', b'1', 54, 50, 57, 52, b'\r', b'\n', XON])]
-    pub fn test_header_write(
-        #[case] encoding: Encoding,
-        #[case] frame: Frame,
-        #[case] flags: &[u8; 4],
-        #[case] expected: &[u8],
-    ) {
-        let header = Header::new(encoding, frame, flags);
-        let mut port = vec![];
-        assert!(header.write(&mut port) == Ok(()));
-        assert_eq!(port, expected);
+        fn read(&self, buf: &mut [u8]) -> usize {
+            let mut queue = self.0.lock().unwrap();
+            let len = buf.len().min(queue.len());
+            for (b, byte) in buf.iter_mut().zip(queue.drain(..len)) {
+                *b = byte;
+            }
+            if len == 0 {
+                thread::yield_now();
+            }
+            len
+        }
     }
 
-    #[rstest::rstest]
-    #[case(&[Encoding::ZHEX as u8, b'0', b'1', b'0', b'1', b'0', b'2', b'0', b'3', b'0', b'4', b'a', b'7', b'5', b'2'], Encoding::ZHEX, Frame::ZRINIT, &[0x1, 0x2, 0x3, 0x4])]
-    #[case(&[Encoding::ZBIN as u8, Frame::ZRINIT as u8, 0xa, 0xb, 0xc, 0xd, 0xa6, 0xcb], Encoding::ZBIN, Frame::ZRINIT, &[0xa, 0xb, 0xc, 0xd])]
-    #[case(&[Encoding::ZBIN32 as u8, Frame::ZRINIT as u8, 0xa, 0xb, 0xc, 0xd, 0x99, 0xe2, 0xae, 0x4a], Encoding::ZBIN32, Frame::ZRINIT, &[0xa, 0xb, 0xc, 0xd])]
-    #[case(&[Encoding::ZBIN as u8, Frame::ZRINIT as u8, 0xa, ZDLE, b'l', 0xd, ZDLE, b'm', 0x5e, 0x6f], Encoding::ZBIN, Frame::ZRINIT, &[0xa, 0x7f, 0xd, 0xff])]
-    pub fn test_header_read(
-        #[case] port: &[u8],
-        #[case] encoding: Encoding,
-        #[case] frame: Frame,
-        #[case] flags: &[u8; 4],
-    ) {
-        let port = &mut port.to_vec();
-        let port = &mut port.as_slice();
-        assert!(Header::read(port) == Ok(Header::new(encoding, frame, flags)));
+    #[test]
+    pub fn test_rtt() {
+        let (up, down) = (Channel::default(), Channel::default());
+        let target = RttPort::new(
+            {
+                let up = up.clone();
+                move |buf: &[u8]| up.write(buf)
+            },
+            {
+                let down = down.clone();
+                move |buf: &mut [u8]| down.read(buf)
+            },
+        )
+        .with_polls(1_000_000);
+        let host = RttPort::new(
+            move |buf: &[u8]| down.write(buf),
+            move |buf: &mut [u8]| up.read(buf),
+        )
+        .with_polls(1_000_000);
+        let (data, sink) = transfer(target, host);
+        assert_eq!(sink, data);
     }
 
     #[rstest::rstest]
-    #[case(Encoding::ZBIN, Packet::ZCRCE, &[])]
-    #[case(Encoding::ZBIN, Packet::ZCRCW, &[0x00])]
-    #[case(Encoding::ZBIN32, Packet::ZCRCQ, &[0, 1, 2, 3, 4, 0x60, 0x60])]
-    pub fn test_subpacket_read_write(
-        #[case] encoding: Encoding,
-        #[case] packet: Packet,
-        #[case] data: &[u8],
-    ) {
-        let mut buf = Buffer::new();
-        let mut port = vec![];
-        assert!(write_subpacket(&mut port, encoding, packet, data) == Ok(()));
-        buf.clear();
-        assert!(read_subpacket(&mut port.as_slice(), &mut buf, encoding) == Ok(packet));
-        assert!(buf == data);
+    #[case(0, 1)]
+    #[case(3, 3)]
+    pub fn test_rtt_full(#[case] polls: u32, #[case] expected: u32) {
+        let count = Cell::new(0);
+        let mut port = RttPort::new(
+            |_: &[u8]| {
+                count.set(count.get() + 1);
+                0
+            },
+            |_: &mut [u8]| 0,
+        )
+        .with_polls(polls);
+        assert!(port.write_all(b"foo") == Err(Error::Write));
+        assert_eq!(count.get(), expected);
     }
 
-    #[rstest::rstest]
-    #[case(&[ZPAD, ZDLE], Ok(()))]
-    #[case(&[ZPAD, ZPAD, ZDLE], Ok(()))]
-    #[case(&[ZDLE], Err(Error::Data))]
-    #[case(&[ZPAD, XON], Err(Error::Data))]
-    #[case(&[ZPAD, ZPAD, XON], Err(Error::Data))]
-    #[case(&[], Err(Error::Read))]
-    #[case(&[0; 100], Err(Error::Data))]
-    pub fn test_zpad_read(#[case] port: &[u8], #[case] expected: Result<(), Error>) {
-        assert!(read_zpad(&mut port.to_vec().as_slice()) == expected);
+    #[cfg(feature = "pty")]
+    #[test]
+    pub fn test_pty() {
+        let (master, slave) = crate::openpty(Duration::from_secs(1)).unwrap();
+        let (data, sink) = transfer(master, slave);
+        assert_eq!(sink, data);
     }
 }
@@ -0,0 +1,80 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Adapter for the files of `littlefs2`
+
+use crate::{Error, Read, Seek, Write};
+use littlefs2_core::{Error as LfsError, SeekFrom};
+
+/// File on a littlefs volume, which can be used as the file sink of
+/// `zmodem2::receive`, and as the file source of `zmodem2::send`. As
+/// `littlefs2` lends the open files to a closure, the adapter borrows the file
+/// for the duration of the transfer.
+pub struct LittlefsFile<'a, F: ?Sized> {
+    file: &'a F,
+}
+
+impl<'a, F: ?Sized> LittlefsFile<'a, F> {
+    /// Creates a new instance
+    #[must_use]
+    pub fn new(file: &'a F) -> Self {
+        Self { file }
+    }
+}
+
+/// Converts a littlefs error to `error`, or to `Error::Data` for corrupted
+/// storage
+fn convert(lfs_error: LfsError, error: Error) -> Error {
+    if lfs_error == LfsError::CORRUPTION {
+        Error::Data
+    } else {
+        error
+    }
+}
+
+impl<F> Read for LittlefsFile<'_, F>
+where
+    F: littlefs2_core::Read + ?Sized,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let len = self.file.read(buf).map_err(|e| convert(e, Error::Read))?;
+        u32::try_from(len).or(Err(Error::Data))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut buf = [0; 1];
+        self.file
+            .read_exact(&mut buf)
+            .map_err(|e| convert(e, Error::Read))?;
+        Ok(buf[0])
+    }
+}
+
+impl<F> Write for LittlefsFile<'_, F>
+where
+    F: littlefs2_core::Write + ?Sized,
+{
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.file
+            .write_all(buf)
+            .map_err(|e| convert(e, Error::Write))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.file.flush().map_err(|e| convert(e, Error::Write))
+    }
+}
+
+impl<F> Seek for LittlefsFile<'_, F>
+where
+    F: littlefs2_core::Seek + ?Sized,
+{
+    fn seek(&mut self, offset: u32) -> Result<(), Error> {
+        let new_offset = self
+            .file
+            .seek(SeekFrom::Start(offset))
+            .map_err(|e| convert(e, Error::Data))?;
+        if usize::try_from(offset) != Ok(new_offset) {
+            return Err(Error::Read);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Text-mode newline conversion
+
+use crate::{Error, Write};
+
+/// Local newline convention for the text files received with the `ZCNL`
+/// conversion option
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Newline {
+    /// Line feed, i.e. `\n`
+    Lf,
+    /// Carriage return followed by line feed, i.e. `\r\n`
+    CrLf,
+}
+
+/// Converter, which rewrites the newlines of a file to the local convention.
+/// The state is kept across subpackets, as a newline sequence can be split
+/// between two of them.
+#[derive(Clone, Copy)]
+pub(crate) struct Converter {
+    newline: Newline,
+    cr: bool,
+}
+
+impl Converter {
+    pub(crate) const fn new(newline: Newline) -> Self {
+        Self { newline, cr: false }
+    }
+
+    /// Writes `buf` to `file` with the newlines converted
+    pub(crate) fn write<F>(&mut self, file: &mut F, buf: &[u8]) -> Result<(), Error>
+    where
+        F: Write,
+    {
+        let mut start = 0;
+        for (i, b) in buf.iter().enumerate() {
+            match (self.newline, *b) {
+                (Newline::Lf, b'\r') => {
+                    file.write_all(&buf[start..i])?;
+                    if self.cr {
+                        file.write_byte(b'\r')?;
+                    }
+                    start = i + 1;
+                }
+                (Newline::Lf, b) if self.cr && b != b'\n' => {
+                    file.write_byte(b'\r')?;
+                }
+                (Newline::CrLf, b'\n') if !self.cr => {
+                    file.write_all(&buf[start..i])?;
+                    file.write_byte(b'\r')?;
+                    start = i;
+                }
+                _ => (),
+            }
+            self.cr = *b == b'\r';
+        }
+        file.write_all(&buf[start..])
+    }
+
+    /// Writes the carriage return held back at the end of the file
+    pub(crate) fn finish<F>(&mut self, file: &mut F) -> Result<(), Error>
+    where
+        F: Write,
+    {
+        if self.newline == Newline::Lf && self.cr {
+            file.write_byte(b'\r')?;
+        }
+        self.cr = false;
+        Ok(())
+    }
+}
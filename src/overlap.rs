@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Double-buffered sink for overlapped writes
+
+use crate::{Error, Write};
+
+/// Buffer owned by the storage during a write
+pub type DeferredBuf = &'static mut [u8];
+
+/// Storage, which completes a write in the background, such as an SD card
+/// driven with DMA. The storage owns the buffer of a write in progress, and
+/// hands it back after the write has completed, and thus the buffer cannot be
+/// modified or freed, while the hardware still reads it.
+pub trait DeferredWrite {
+    /// Starts writing the first `len` bytes of `buf`. The write can be still
+    /// in progress after returning. The storage keeps `buf` until it is
+    /// handed back by `DeferredWrite::wait`, also when the write could not be
+    /// started.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Write)` when the write cannot be started
+    fn start(&mut self, buf: DeferredBuf, len: usize) -> Result<(), Error>;
+
+    /// Waits until the write in progress, if any, has completed, and hands
+    /// back its buffer. `None` is returned, when no buffer is held.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Write)` when the write has failed
+    fn wait(&mut self) -> Result<Option<DeferredBuf>, Error>;
+
+    /// Flushes the storage after the writes have completed
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Write)` when the storage cannot be flushed
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Sink with two buffers, which overlaps the writes to a `DeferredWrite`
+/// storage with the reception of the next subpackets, as advertised by the
+/// receiver with `Zrinit::CANOVIO`. A full buffer is handed to the storage,
+/// while the other one is being filled. The buffers are `'static`, e.g.
+/// taken from a `static` with `cortex_m::singleton!`, as the storage owns one
+/// of them while the hardware reads it. Dropping the sink waits for the write
+/// in progress.
+pub struct DoubleBuffer<W>
+where
+    W: DeferredWrite,
+{
+    storage: W,
+    active: DeferredBuf,
+    /// The other buffer, when it is not held by the storage
+    spare: Option<DeferredBuf>,
+    len: usize,
+}
+
+impl<W> DoubleBuffer<W>
+where
+    W: DeferredWrite,
+{
+    /// Creates a new instance
+    pub fn new(storage: W, buffers: [DeferredBuf; 2]) -> Self {
+        let [active, spare] = buffers;
+        Self {
+            storage,
+            active,
+            spare: Some(spare),
+            len: 0,
+        }
+    }
+
+    /// Returns a reference to the storage
+    pub fn get_ref(&self) -> &W {
+        &self.storage
+    }
+
+    /// Waits for the write in progress, and takes back its buffer
+    fn reclaim(&mut self) -> Result<(), Error> {
+        if let Some(buf) = self.storage.wait()? {
+            self.spare = Some(buf);
+        }
+        Ok(())
+    }
+
+    /// Hands the active buffer to the storage after the previous write has
+    /// completed, and continues with the other buffer
+    fn swap(&mut self) -> Result<(), Error> {
+        self.reclaim()?;
+        let spare = self.spare.take().ok_or(Error::Write)?;
+        let full = core::mem::replace(&mut self.active, spare);
+        let len = core::mem::take(&mut self.len);
+        self.storage.start(full, len)
+    }
+}
+
+impl<W> Write for DoubleBuffer<W>
+where
+    W: DeferredWrite,
+{
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let capacity = self.active.len();
+            if capacity == 0 {
+                return Err(Error::Write);
+            }
+            let len = (capacity - self.len).min(buf.len());
+            self.active[self.len..self.len + len].copy_from_slice(&buf[..len]);
+            self.len += len;
+            buf = &buf[len..];
+            if self.len == capacity {
+                self.swap()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.len > 0 {
+            self.swap()?;
+        }
+        self.reclaim()?;
+        self.storage.flush()
+    }
+}
+
+impl<W> Drop for DoubleBuffer<W>
+where
+    W: DeferredWrite,
+{
+    fn drop(&mut self) {
+        // The error cannot be reported, and the buffer is not used anymore:
+        let _ = self.storage.wait();
+    }
+}
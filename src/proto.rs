@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Low-level ZMODEM protocol definitions, which can be used by companion
+//! tools, such as sniffers and test generators. The frame, encoding and
+//! subpacket types are available as the byte values of `Frame`, `Encoding`
+//! and `Packet`, and the `ZRINIT` capabilities as `Zrinit`.
+
+pub use crate::{Encoding, Frame, Packet, Zrinit};
+
+/// Padding character, which starts a header
+pub const ZPAD: u8 = b'*';
+/// Escape character, which is also the ASCII CAN
+pub const ZDLE: u8 = 0x18;
+/// Flow control character, which resumes the transmission
+pub const XON: u8 = 0x11;
+/// Flow control character, which pauses the transmission
+pub const XOFF: u8 = 0x13;
+/// ASCII backspace
+pub const BS: u8 = 0x08;
+
+/// Escaped form of `0x7f` after `ZDLE`
+pub const ZRUB0: u8 = b'l';
+/// Escaped form of `0xff` after `ZDLE`
+pub const ZRUB1: u8 = b'm';
+/// Prefix after `ZDLE` of a byte with the 8th bit set, which is sent to a
+/// receiver advertising `Zrinit::ESC8`, when enabled with `State::set_zesc8`.
+/// The byte follows without the 8th bit, escaped as usual. The 1988
+/// specification does not define the escape, and this one is understood only
+/// by the receivers of this crate.
+pub const ZESC8: u8 = b'n';
+/// Bit flipped in a control character escaped after `ZDLE`
+pub const ZESCAPE_BIT: u8 = 0x40;
+
+/// Abort sequence of ten CAN characters followed by ten backspaces, which
+/// also clears the CAN characters from a terminal
+pub const ABORT: [u8; 20] = [
+    ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, BS, BS, BS, BS, BS, BS, BS, BS, BS,
+    BS,
+];
+
+/// `ZF0` conversion option of `ZFILE` for binary files
+pub const ZCBIN: u8 = 1;
+/// `ZF0` conversion option of `ZFILE` for text files
+pub const ZCNL: u8 = 2;
+/// `ZF0` conversion option of `ZFILE` for resuming an interrupted file
+pub const ZCRESUM: u8 = 3;
+
+/// `ZF1` management option of `ZFILE`: skip the file, if it does not exist
+pub const ZMSKNOLOC: u8 = 0x80;
+/// `ZF1` mask for the management options of `ZFILE`
+pub const ZMMASK: u8 = 0x1f;
+/// `ZF1` management option of `ZFILE`: transfer, if newer or longer
+pub const ZMNEWL: u8 = 1;
+/// `ZF1` management option of `ZFILE`: transfer, if the CRC or length differs
+pub const ZMCRC: u8 = 2;
+/// `ZF1` management option of `ZFILE`: append to the existing file
+pub const ZMAPND: u8 = 3;
+/// `ZF1` management option of `ZFILE`: replace the existing file
+pub const ZMCLOB: u8 = 4;
+/// `ZF1` management option of `ZFILE`: transfer, if newer
+pub const ZMNEW: u8 = 5;
+/// `ZF1` management option of `ZFILE`: transfer, if the dates or lengths
+/// differ
+pub const ZMDIFF: u8 = 6;
+/// `ZF1` management option of `ZFILE`: protect the existing file
+pub const ZMPROT: u8 = 7;
+/// `ZF1` management option of `ZFILE`: change the name, if the file exists
+pub const ZMCHNG: u8 = 8;
+
+/// `ZF2` transport option of `ZFILE` for compression
+pub const ZTLZW: u8 = 1;
+/// `ZF2` transport option of `ZFILE` for encryption
+pub const ZTCRYPT: u8 = 2;
+/// `ZF2` transport option of `ZFILE` for run-length encoding
+pub const ZTRLE: u8 = 3;
+
+/// `ZF3` extended option of `ZFILE` for sparse files
+pub const ZXSPARS: u8 = 0x40;
+
+/// `ZF0` flag of `ZSINIT` for escaping all control characters
+pub const TESCCTL: u8 = 0x40;
+/// `ZF0` flag of `ZSINIT` for escaping the 8th bit
+pub const TESC8: u8 = 0x80;
+
+/// `ZF0` flag of `ZCOMMAND` requesting `ZCOMPL` before running the command
+pub const ZCACK1: u8 = 1;
@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Quirks of the ZMODEM stacks built in terminal programs
+
+use crate::{Error, Read, XOFF, XON};
+use core::ops::BitOr;
+
+/// Deviations from lrzsz tolerated from the peer, which are typical for the
+/// ZMODEM stacks built in terminal programs. None are tolerated by default.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quirks(u8);
+
+#[allow(clippy::doc_markdown)]
+impl Quirks {
+    /// Skips the trailer of CR, LF and XON, which pads a `ZHEX` header before
+    /// its subpacket, and is not part of the header read in the lenient
+    /// conformance. A subpacket following a `ZHEX` header cannot start with
+    /// an unescaped CR or LF.
+    pub const PADDED_HEADERS: Self = Self(0x01);
+    /// Skips XON and XOFF sent late in the middle of a subpacket. The peer
+    /// escapes them in the data, and thus the unescaped ones are flow
+    /// control.
+    pub const LATE_XON: Self = Self(0x02);
+    /// Sends every subpacket with `ZCRCW`, and waits for `ZACK` before the
+    /// next one, regardless of the `ZRINIT` flags of the receiver, which
+    /// claims streaming but overruns.
+    pub const ZCRCW_ONLY: Self = Self(0x04);
+
+    /// HyperTerminal, which advertises full-duplex streaming but loses data
+    /// at the speed of the link, and sends XON late
+    pub const HYPERTERMINAL: Self = Self(Self::LATE_XON.0 | Self::ZCRCW_ONLY.0);
+    /// Tera Term, which sends `ZHEX` headers with the trailer before the
+    /// subpackets, and XON late
+    pub const TERA_TERM: Self = Self(Self::PADDED_HEADERS.0 | Self::LATE_XON.0);
+    /// SecureCRT, which sends `ZHEX` headers with the trailer before the
+    /// subpackets, and is overrun by a stream on a slow link
+    pub const SECURECRT: Self = Self(Self::PADDED_HEADERS.0 | Self::ZCRCW_ONLY.0);
+
+    /// Returns an instance with no quirks set
+    #[must_use]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns `true` if no quirks are set
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if all of the quirks in `other` are set
+    #[must_use]
+    pub const fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Quirks {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// Reader for a subpacket, which skips the bytes tolerated by the quirks. The
+/// padding is skipped only before the first byte of the subpacket.
+pub(crate) struct Tolerant<'a, P> {
+    port: &'a mut P,
+    quirks: Quirks,
+    padded: bool,
+}
+
+impl<'a, P> Tolerant<'a, P> {
+    pub(crate) fn new(port: &'a mut P, quirks: Quirks, padded: bool) -> Self {
+        Self {
+            port,
+            quirks,
+            padded: padded && quirks.contains(Quirks::PADDED_HEADERS),
+        }
+    }
+}
+
+impl<P> Read for Tolerant<'_, P>
+where
+    P: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        for b in buf.iter_mut() {
+            *b = self.read_byte()?;
+        }
+        u32::try_from(buf.len()).or(Err(Error::Data))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        loop {
+            let b = self.port.read_byte()?;
+            match b & 0x7f {
+                XON | XOFF if self.padded || self.quirks.contains(Quirks::LATE_XON) => (),
+                b'\r' | b'\n' if self.padded => (),
+                _ => {
+                    self.padded = false;
+                    return Ok(b);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Work quotas of the hardened mode
+
+use crate::filename::MAX_ALTERNATIVES;
+use crate::{Error, Read, HEADER_SIZE, MAX_GARBAGE};
+
+/// Bound on the work derived from the peer input, which has been exceeded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quota {
+    /// Garbage skipped while looking for a header in a single step
+    Garbage,
+    /// Escaped length of a header
+    Header,
+    /// Subpackets in a single `ZDATA` frame
+    Subpackets,
+    /// Duration of the session
+    Duration,
+    /// Alternative names tried for a received file, which already exists
+    Alternatives,
+}
+
+/// Bounds on the work derived from the peer input in the hardened mode set by
+/// `State::set_quotas`. Exceeding a bound fails the step with
+/// `Err(Error::Quota)` instead of being tolerated as a link condition.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quotas {
+    pub(crate) garbage: usize,
+    pub(crate) header: usize,
+    pub(crate) subpackets: Option<u32>,
+    pub(crate) duration: Option<u32>,
+    pub(crate) alternatives: u32,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Quotas {
+    /// Creates the quotas, which bound the garbage as in the normal mode, and
+    /// the length of a header to the longest valid header with a margin, and
+    /// the alternative names as in the normal mode. The number of subpackets
+    /// and the duration are not bound.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            garbage: MAX_GARBAGE,
+            header: HEADER_SIZE,
+            subpackets: None,
+            duration: None,
+            alternatives: MAX_ALTERNATIVES,
+        }
+    }
+
+    /// Sets the maximum number of bytes skipped in a step while looking for a
+    /// header
+    #[must_use]
+    pub const fn with_garbage(self, bytes: usize) -> Self {
+        Self {
+            garbage: bytes,
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of bytes in a header after the `ZPAD` and
+    /// `ZDLE` characters, including the escapes and the ignored characters
+    #[must_use]
+    pub const fn with_header_len(self, bytes: usize) -> Self {
+        Self {
+            header: bytes,
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of subpackets in a single `ZDATA` frame
+    #[must_use]
+    pub const fn with_subpackets(self, subpackets: u32) -> Self {
+        Self {
+            subpackets: Some(subpackets),
+            ..self
+        }
+    }
+
+    /// Sets the maximum duration of the session in milliseconds, which is
+    /// measured with the clock set by `State::set_clock`. The quotas are
+    /// rejected by `State::set_quotas` without a clock.
+    #[must_use]
+    pub const fn with_duration(self, ms: u32) -> Self {
+        Self {
+            duration: Some(ms),
+            ..self
+        }
+    }
+
+    /// Sets the maximum number of alternative names tried for a received
+    /// file, which already exists, with the policy set by
+    /// `State::set_name_policy`. Each one is checked with the storage.
+    #[must_use]
+    pub const fn with_alternatives(self, alternatives: u32) -> Self {
+        Self {
+            alternatives,
+            ..self
+        }
+    }
+}
+
+/// Reader, which fails with `quota` after `limit` bytes
+pub(crate) struct Bounded<'a, P> {
+    port: &'a mut P,
+    left: usize,
+    quota: Quota,
+}
+
+impl<'a, P> Bounded<'a, P> {
+    pub(crate) fn new(port: &'a mut P, limit: usize, quota: Quota) -> Self {
+        Self {
+            port,
+            left: limit,
+            quota,
+        }
+    }
+}
+
+impl<P> Read for Bounded<'_, P>
+where
+    P: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let len = buf.len().min(self.left);
+        if len == 0 && !buf.is_empty() {
+            return Err(Error::Quota(self.quota));
+        }
+        let count = self.port.read(&mut buf[..len])?;
+        self.left -= count as usize;
+        Ok(count)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        if self.left == 0 {
+            return Err(Error::Quota(self.quota));
+        }
+        let byte = self.port.read_byte()?;
+        self.left -= 1;
+        Ok(byte)
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Persistent resume storage
+
+use crate::{Error, CRC32};
+use core::str::FromStr;
+use heapless::String;
+
+/// Resume record of a file, which has been partially received
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResumeRecord {
+    pub(crate) name: String<256>,
+    pub(crate) size: u32,
+    pub(crate) offset: u32,
+    pub(crate) crc: Option<u32>,
+}
+
+impl ResumeRecord {
+    /// Creates a new record, e.g. when loading it from the storage
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the file name is too long
+    pub fn new(name: &str, size: u32, offset: u32, crc: Option<u32>) -> Result<Self, Error> {
+        Ok(Self {
+            name: String::from_str(name).or(Err(Error::Data))?,
+            size,
+            offset,
+            crc,
+        })
+    }
+
+    /// Returns the file name
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the file size declared in `ZFILE`
+    #[must_use]
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Returns the offset, up to which the file has been written and flushed
+    #[must_use]
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Returns the CRC-32 of the file data up to the offset, which can be used
+    /// to verify the file before resuming, or `None` if it is not known
+    #[must_use]
+    pub fn crc(&self) -> Option<u32> {
+        self.crc
+    }
+
+    /// Returns `true` when the file has been completely received
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.offset >= self.size
+    }
+}
+
+/// Storage for a single resume record, such as a flash sector or an EEPROM
+/// page. The receiver saves the record after flushing the file, and thus the
+/// stored offset never exceeds the data written to the file.
+pub trait ResumeStore {
+    /// Loads the stored record, or returns `None` if there is none
+    fn load(&mut self) -> Option<ResumeRecord>;
+
+    /// Saves the record, replacing the stored one
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Write)` when the record cannot be saved
+    fn save(&mut self, record: &ResumeRecord) -> Result<(), Error>;
+}
+
+/// Continues the CRC-32 `crc` with `data`
+pub(crate) fn update_crc(crc: u32, data: &[u8]) -> u32 {
+    let mut digest = CRC32.digest_with_initial((crc ^ 0xffff_ffff).reverse_bits());
+    digest.update(data);
+    digest.finalize()
+}
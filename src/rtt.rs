@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Port over an RTT channel pair
+
+use crate::{Error, Read, Write};
+
+/// Default number of polls of an empty or a full channel before giving up
+const POLLS: u32 = 10_000;
+
+/// Port over a SEGGER RTT up and down channel pair, which allows to transfer
+/// files through the debug probe, e.g. with probe-rs, without a UART. The
+/// channels are accessed with two closures, which usually forward to the
+/// channels of the RTT implementation:
+///
+/// * `up` writes as much of the buffer as fits to the up channel, and returns
+///   the number of bytes written, e.g. `rtt_target::UpChannel::write` in a
+///   non-blocking mode.
+/// * `down` reads the available bytes from the down channel, and returns the
+///   number of bytes read, e.g. `rtt_target::DownChannel::read`.
+///
+/// The channels are lossless, but the host moves the data in chunks, and
+/// neither closure blocks. Thus, a read is retried while the down channel is
+/// empty, and a write while the up channel is full, up to the number of polls
+/// set by `RttPort::with_polls`. A read, which runs out of polls, fails as a
+/// timed out read of a serial port.
+pub struct RttPort<U, D>
+where
+    U: FnMut(&[u8]) -> usize,
+    D: FnMut(&mut [u8]) -> usize,
+{
+    up: U,
+    down: D,
+    polls: u32,
+}
+
+impl<U, D> RttPort<U, D>
+where
+    U: FnMut(&[u8]) -> usize,
+    D: FnMut(&mut [u8]) -> usize,
+{
+    /// Creates a new instance
+    pub fn new(up: U, down: D) -> Self {
+        Self {
+            up,
+            down,
+            polls: POLLS,
+        }
+    }
+
+    /// Sets the number of polls of an empty down channel or a full up channel
+    /// before a read times out or a write fails
+    #[must_use]
+    pub fn with_polls(self, polls: u32) -> Self {
+        Self { polls, ..self }
+    }
+}
+
+impl<U, D> Read for RttPort<U, D>
+where
+    U: FnMut(&[u8]) -> usize,
+    D: FnMut(&mut [u8]) -> usize,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        for _ in 0..self.polls {
+            let len = (self.down)(buf).min(buf.len());
+            if len > 0 {
+                return u32::try_from(len).or(Err(Error::Data));
+            }
+        }
+        Err(Error::Read)
+    }
+}
+
+impl<U, D> Write for RttPort<U, D>
+where
+    U: FnMut(&[u8]) -> usize,
+    D: FnMut(&mut [u8]) -> usize,
+{
+    fn write_all(&mut self, mut buf: &[u8]) -> Result<(), Error> {
+        let mut polls = 0;
+        while !buf.is_empty() {
+            let len = (self.up)(buf).min(buf.len());
+            if len == 0 {
+                polls += 1;
+                if polls >= self.polls {
+                    return Err(Error::Write);
+                }
+                continue;
+            }
+            polls = 0;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+}
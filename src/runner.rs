@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Blocking runners with hooks
+
+use crate::{
+    receive, send, write_abort, BatchProgress, Decision, Error, FileInfo, Read, Seek, Stage, State,
+    TransferSummary, Write,
+};
+
+/// Callback invoked with the current file and its offset
+type Progress<'a> = &'a mut dyn FnMut(&FileInfo, u32);
+
+/// Callback invoked with the progress of the batch
+type Batch<'a> = &'a mut dyn FnMut(&BatchProgress);
+
+/// Hooks invoked by `zmodem2::run_send` and `zmodem2::run_receive` between
+/// the steps of the transfer
+#[derive(Default)]
+pub struct Hooks<'a> {
+    progress: Option<Progress<'a>>,
+    batch: Option<Batch<'a>>,
+    pre_accept: Option<&'a mut dyn FnMut(&FileInfo) -> Decision>,
+    cancel: Option<&'a mut dyn FnMut() -> bool>,
+}
+
+impl<'a> Hooks<'a> {
+    /// Creates a new instance without hooks
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a callback invoked with the current file and its offset, when the
+    /// offset has changed
+    pub fn set_progress(&mut self, callback: Progress<'a>) {
+        self.progress = Some(callback);
+    }
+
+    /// Sets a callback invoked with the progress of the batch along with the
+    /// progress callback, when the sender has offered a file
+    pub fn set_batch_progress(&mut self, callback: Batch<'a>) {
+        self.batch = Some(callback);
+    }
+
+    /// Sets a callback deciding on the files offered by the sender. See
+    /// `State::set_pre_accept`.
+    pub fn set_pre_accept(&mut self, callback: &'a mut dyn FnMut(&FileInfo) -> Decision) {
+        self.pre_accept = Some(callback);
+    }
+
+    /// Sets a callback polled before each step, which cancels the session
+    /// with the abort sequence when it returns `true`
+    pub fn set_cancel(&mut self, callback: &'a mut dyn FnMut() -> bool) {
+        self.cancel = Some(callback);
+    }
+}
+
+/// Sends files until the session is done, and returns the summary of the
+/// session
+///
+/// # Errors
+///
+/// * The first error returned by `zmodem2::send`
+/// * `Err(Error::Cancelled)` when the session has been cancelled by a hook
+pub fn run_send<'a, P, F>(
+    port: &mut P,
+    file: &mut F,
+    state: &mut State<'a>,
+    hooks: Hooks<'a>,
+) -> Result<TransferSummary, Error>
+where
+    P: Read + Write,
+    F: Read + Seek,
+{
+    run(port, file, state, hooks, send::<P, F>)
+}
+
+/// Receives files until the session is done, and returns the summary of the
+/// session. The pre-accept hook replaces the callback set to `state`, if any.
+///
+/// # Errors
+///
+/// * The first error returned by `zmodem2::receive`
+/// * `Err(Error::Cancelled)` when the session has been cancelled by a hook
+pub fn run_receive<'a, P, F>(
+    port: &mut P,
+    file: &mut F,
+    state: &mut State<'a>,
+    hooks: Hooks<'a>,
+) -> Result<TransferSummary, Error>
+where
+    P: Read + Write,
+    F: Write,
+{
+    run(port, file, state, hooks, receive::<P, F>)
+}
+
+fn run<'a, P, F>(
+    port: &mut P,
+    file: &mut F,
+    state: &mut State<'a>,
+    mut hooks: Hooks<'a>,
+    step: fn(&mut P, &mut F, &mut State<'a>) -> Result<(), Error>,
+) -> Result<TransferSummary, Error>
+where
+    P: Read + Write,
+{
+    if let Some(callback) = hooks.pre_accept.take() {
+        state.set_pre_accept(callback);
+    }
+    let mut count = None;
+    while state.stage() != Stage::Done {
+        if hooks.cancel.as_mut().is_some_and(|cancel| cancel()) {
+            let result = write_abort(port, state);
+            let flushed = port.flush();
+            return state
+                .record_error(result.and(flushed))
+                .map(|()| state.summary());
+        }
+        step(port, file, state)?;
+        if !state.file_open {
+            count = None;
+        } else if count != Some(state.count) {
+            count = Some(state.count);
+            if let Some(progress) = hooks.progress.as_mut() {
+                progress(&state.file, state.count);
+            }
+            if let (Some(callback), Some(batch)) = (hooks.batch.as_mut(), state.batch_progress()) {
+                callback(&batch);
+            }
+        }
+    }
+    Ok(state.summary())
+}
@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Adapter for the files of `embedded-sdmmc`
+
+use crate::{Error, Read, Seek, Write};
+use embedded_sdmmc::{BlockDevice, File, TimeSource};
+
+/// File on a FAT volume of an SD card, which can be used as the file sink of
+/// `zmodem2::receive`, and as the file source of `zmodem2::send`
+pub struct SdmmcFile<
+    'a,
+    D,
+    T,
+    const MAX_DIRS: usize,
+    const MAX_FILES: usize,
+    const MAX_VOLUMES: usize,
+> where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    file: File<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>,
+}
+
+impl<'a, D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize>
+    SdmmcFile<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    /// Creates a new instance
+    #[must_use]
+    pub fn new(file: File<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>) -> Self {
+        Self { file }
+    }
+
+    /// Returns a reference to the file
+    #[must_use]
+    pub fn get_ref(&self) -> &File<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES> {
+        &self.file
+    }
+
+    /// Returns the file, e.g. for closing it with `File::close`
+    #[must_use]
+    pub fn into_inner(self) -> File<'a, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES> {
+        self.file
+    }
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize> Read
+    for SdmmcFile<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        if buf.is_empty() || self.file.is_eof() {
+            return Ok(0);
+        }
+        let len = self.file.read(buf).or(Err(Error::Read))?;
+        u32::try_from(len).or(Err(Error::Data))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut buf = [0; 1];
+        match self.read(&mut buf)? {
+            1 => Ok(buf[0]),
+            _ => Err(Error::Read),
+        }
+    }
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize> Write
+    for SdmmcFile<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.file.write(buf).or(Err(Error::Write))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.file.flush().or(Err(Error::Write))
+    }
+}
+
+impl<D, T, const MAX_DIRS: usize, const MAX_FILES: usize, const MAX_VOLUMES: usize> Seek
+    for SdmmcFile<'_, D, T, MAX_DIRS, MAX_FILES, MAX_VOLUMES>
+where
+    D: BlockDevice,
+    T: TimeSource,
+{
+    fn seek(&mut self, offset: u32) -> Result<(), Error> {
+        self.file.seek_from_start(offset).or(Err(Error::Data))
+    }
+}
@@ -0,0 +1,157 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Event-iterator API
+
+use crate::{
+    receive, send, BatchProgress, Error, FileInfo, FileStatus, LinkEvent, Read, Seek, Stage, State,
+    TransferSummary, Write,
+};
+use heapless::Deque;
+
+/// Beginning or end of a file recorded by `State` for `Session`
+#[derive(Clone, Copy)]
+pub(crate) enum FileMark {
+    Begin,
+    End(FileStatus),
+}
+
+/// Event of the session
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A file has been offered and accepted
+    FileStarted(FileInfo),
+    /// The file offset of the current file has advanced
+    Progress { count: u32, size: u32 },
+    /// The batch has advanced along with the current file
+    Batch(BatchProgress),
+    /// The current file has ended with the given status
+    FileDone(FileStatus),
+    /// A step tolerated a condition of the link without receiving a header
+    Link(LinkEvent),
+    /// The session has finished
+    SessionDone(TransferSummary),
+}
+
+/// Step of `zmodem2::send` or `zmodem2::receive`
+type Step<'a, P, F> = fn(&mut P, &mut F, &mut State<'a>) -> Result<(), Error>;
+
+/// Session, which owns the port, the file and the state, and steps the
+/// transfer as it is iterated. Each item is either an event, or an error
+/// returned by a step. The session can be continued after an error, which is
+/// e.g. a timeout, and the iterator ends after `Event::SessionDone`.
+pub struct Session<'a, P, F> {
+    port: P,
+    file: F,
+    state: State<'a>,
+    step: Step<'a, P, F>,
+    events: Deque<Event, 8>,
+    count: Option<u32>,
+    done: bool,
+}
+
+impl<'a, P, F> Session<'a, P, F>
+where
+    P: Read + Write,
+{
+    /// Creates a new session, which sends `file` with `state`
+    pub fn send(port: P, file: F, state: State<'a>) -> Self
+    where
+        F: Read + Seek,
+    {
+        Self::new(port, file, state, send::<P, F>)
+    }
+
+    /// Creates a new session, which receives to `file` with `state`
+    pub fn receive(port: P, file: F, state: State<'a>) -> Self
+    where
+        F: Write,
+    {
+        Self::new(port, file, state, receive::<P, F>)
+    }
+
+    fn new(port: P, file: F, mut state: State<'a>, step: Step<'a, P, F>) -> Self {
+        state.file_marks = Some(Deque::new());
+        Self {
+            port,
+            file,
+            state,
+            step,
+            events: Deque::new(),
+            count: None,
+            done: false,
+        }
+    }
+}
+
+impl<'a, P, F> Session<'a, P, F> {
+    /// Returns the state
+    pub fn state(&self) -> &State<'a> {
+        &self.state
+    }
+
+    /// Returns the state for e.g. setting the next file to send
+    pub fn state_mut(&mut self) -> &mut State<'a> {
+        &mut self.state
+    }
+
+    /// Returns the file for e.g. replacing it with the next file to send
+    pub fn file_mut(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    /// Returns the port, the file and the state
+    pub fn into_inner(self) -> (P, F, State<'a>) {
+        (self.port, self.file, self.state)
+    }
+
+    /// Queues the events for the changes made by a step
+    fn collect(&mut self) {
+        if let Some(event) = self.state.link_event() {
+            self.events.push_back(Event::Link(event)).ok();
+        }
+        while let Some(mark) = self.state.file_marks.as_mut().and_then(Deque::pop_front) {
+            let event = match mark {
+                FileMark::Begin => {
+                    self.count = None;
+                    Event::FileStarted(self.state.file.clone())
+                }
+                FileMark::End(status) => Event::FileDone(status),
+            };
+            self.events.push_back(event).ok();
+        }
+        let count = self.state.count;
+        if self.state.file_open && self.count != Some(count) {
+            self.count = Some(count);
+            let size = self.state.file.size;
+            self.events.push_back(Event::Progress { count, size }).ok();
+            if let Some(batch) = self.state.batch_progress() {
+                self.events.push_back(Event::Batch(batch)).ok();
+            }
+        }
+        if self.state.stage == Stage::Done {
+            self.done = true;
+            let summary = self.state.summary();
+            self.events.push_back(Event::SessionDone(summary)).ok();
+        }
+    }
+}
+
+impl<P, F> Iterator for Session<'_, P, F> {
+    type Item = Result<Event, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Some(Ok(event));
+            }
+            if self.done {
+                return None;
+            }
+            let result = (self.step)(&mut self.port, &mut self.file, &mut self.state);
+            self.collect();
+            if let Err(err) = result {
+                return Some(Err(err));
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Sinks for receiving
+
+use crate::{source::Plain, Error, FileInfo, Write};
+
+/// File received with `zmodem2::receive_sink`, which is told when each file of
+/// the batch begins
+pub trait Sink: Write {
+    /// Called when the receiver has accepted `ZFILE`, before any data of the
+    /// file is written. The default does nothing.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the file cannot be written to the sink
+    fn begin(&mut self, _file: &FileInfo) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<F> Write for Plain<'_, F>
+where
+    F: Write,
+{
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.0.write_all(buf)
+    }
+
+    fn write_byte(&mut self, value: u8) -> Result<(), Error> {
+        self.0.write_byte(value)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.0.flush()
+    }
+}
+
+impl<F> Sink for Plain<'_, F> where F: Write {}
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Chunk-iterator and in-memory sources for sending
+
+use crate::{Error, EscapedSource, Read, Seek};
+
+/// File sent with `zmodem2::send_source`, which can provide its data faster
+/// than by reading it
+pub trait Source: Read + Seek {
+    /// Returns the contents of a file held in memory, which allows the sender
+    /// to send the data without copying it. The default is `None`.
+    fn contents(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Returns the source of the subpackets escaped ahead of writing them,
+    /// which takes the escaping off the path of the port writes. The default
+    /// is `None`.
+    fn escaped(&mut self) -> Option<&mut dyn EscapedSource> {
+        None
+    }
+}
+
+/// Source, which provides the data of a file only by reading it
+pub(crate) struct Plain<'f, F>(pub(crate) &'f mut F);
+
+impl<F> Read for Plain<'_, F>
+where
+    F: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        self.0.read(buf)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        self.0.read_byte()
+    }
+}
+
+impl<F> Seek for Plain<'_, F>
+where
+    F: Seek,
+{
+    fn seek(&mut self, offset: u32) -> Result<(), Error> {
+        self.0.seek(offset)
+    }
+}
+
+impl<F> Source for Plain<'_, F> where F: Read + Seek {}
+
+/// Source, which pulls the file data from an iterator of chunks, and thus
+/// allows to stream generated data without materializing a file first. The
+/// last `N` bytes are kept in a replay window, from which the data is re-sent
+/// when the receiver requests a position with `ZRPOS`. The window should be
+/// large enough to cover a full `ZDATA` burst, i.e. at least 10 KiB.
+pub struct ChunkSource<I, const N: usize>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    chunks: I,
+    chunk: Option<I::Item>,
+    chunk_offset: usize,
+    window: [u8; N],
+    end: u32,
+    offset: u32,
+}
+
+impl<I, const N: usize> ChunkSource<I, N>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    /// Creates a new instance
+    pub fn new<T>(chunks: T) -> Self
+    where
+        T: IntoIterator<IntoIter = I>,
+    {
+        Self {
+            chunks: chunks.into_iter(),
+            chunk: None,
+            chunk_offset: 0,
+            window: [0; N],
+            end: 0,
+            offset: 0,
+        }
+    }
+
+    /// Pulls new bytes from the iterator to `buf`, and records them to the
+    /// replay window. Returns the number of bytes pulled, which is zero only
+    /// when the iterator has been exhausted.
+    fn pull(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        loop {
+            if let Some(chunk) = &self.chunk {
+                let chunk = &chunk.as_ref()[self.chunk_offset..];
+                if !chunk.is_empty() {
+                    let len = chunk.len().min(buf.len());
+                    buf[..len].copy_from_slice(&chunk[..len]);
+                    self.chunk_offset += len;
+                    self.record(&buf[..len])?;
+                    return Ok(len);
+                }
+            }
+            self.chunk = self.chunks.next();
+            self.chunk_offset = 0;
+            if self.chunk.is_none() {
+                return Ok(0);
+            }
+        }
+    }
+
+    /// Records bytes to the replay window
+    fn record(&mut self, buf: &[u8]) -> Result<(), Error> {
+        for b in buf {
+            if N > 0 {
+                self.window[self.end as usize % N] = *b;
+            }
+            self.end = self.end.checked_add(1).ok_or(Error::Data)?;
+        }
+        Ok(())
+    }
+}
+
+impl<I, const N: usize> Read for ChunkSource<I, N>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let mut count = 0;
+        while count < buf.len() {
+            let len = if self.offset < self.end {
+                let index = self.offset as usize % N;
+                let len = ((self.end - self.offset) as usize)
+                    .min(N - index)
+                    .min(buf.len() - count);
+                buf[count..count + len].copy_from_slice(&self.window[index..index + len]);
+                len
+            } else {
+                self.pull(&mut buf[count..])?
+            };
+            if len == 0 {
+                break;
+            }
+            count += len;
+            self.offset += u32::try_from(len).or(Err(Error::Data))?;
+        }
+        u32::try_from(count).or(Err(Error::Data))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut buf = [0; 1];
+        match self.read(&mut buf)? {
+            0 => Err(Error::Read),
+            _ => Ok(buf[0]),
+        }
+    }
+}
+
+impl<I, const N: usize> Seek for ChunkSource<I, N>
+where
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    fn seek(&mut self, offset: u32) -> Result<(), Error> {
+        let mut buf = [0; 64];
+        while self.end < offset {
+            let len = ((offset - self.end) as usize).min(buf.len());
+            if self.pull(&mut buf[..len])? == 0 {
+                return Err(Error::Data);
+            }
+        }
+        if (self.end - offset) as usize > N {
+            return Err(Error::Data);
+        }
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+/// Source backed by the file contents in memory, such as a memory-mapped file
+/// (e.g. `memmap2::Mmap`), or a file in memory-mapped flash. The data is sent
+/// directly from the memory by `zmodem2::send_source` without copying it
+/// through the buffer of `State`, unless a codec is used, and seeking does not
+/// involve any I/O.
+pub struct MappedSource<T>
+where
+    T: AsRef<[u8]>,
+{
+    contents: T,
+    offset: usize,
+}
+
+impl<T> MappedSource<T>
+where
+    T: AsRef<[u8]>,
+{
+    /// Creates a new instance
+    pub fn new(contents: T) -> Self {
+        Self {
+            contents,
+            offset: 0,
+        }
+    }
+
+    /// Returns a reference to the contents
+    pub fn get_ref(&self) -> &T {
+        &self.contents
+    }
+}
+
+impl<T> Read for MappedSource<T>
+where
+    T: AsRef<[u8]>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let data = &self.contents.as_ref()[self.offset..];
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.offset += len;
+        u32::try_from(len).or(Err(Error::Data))
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let b = *self.contents.as_ref().get(self.offset).ok_or(Error::Read)?;
+        self.offset += 1;
+        Ok(b)
+    }
+}
+
+impl<T> Seek for MappedSource<T>
+where
+    T: AsRef<[u8]>,
+{
+    fn seek(&mut self, offset: u32) -> Result<(), Error> {
+        let offset = usize::try_from(offset).or(Err(Error::Data))?;
+        if offset > self.contents.as_ref().len() {
+            return Err(Error::Data);
+        }
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+impl<T> Source for MappedSource<T>
+where
+    T: AsRef<[u8]>,
+{
+    fn contents(&self) -> Option<&[u8]> {
+        Some(self.contents.as_ref())
+    }
+}
@@ -1,6 +1,28 @@
+#[cfg(feature = "futures")]
+mod blocking;
+mod fs;
+mod pipeline;
+mod port;
+mod tar;
+#[cfg(unix)]
+mod unix;
+#[cfg(feature = "nusb")]
+mod usb;
+
 use super::{Encoding, Error, Frame, Header, Packet, Read, Seek, Write};
 use std::{fmt, io::SeekFrom};
 
+#[cfg(feature = "futures")]
+pub use blocking::Blocking;
+pub use fs::{receive_dir, send_dir, NameRules};
+pub use pipeline::Pipelined;
+pub use port::BufferedPort;
+pub use tar::TarSink;
+#[cfg(all(unix, feature = "pty"))]
+pub use unix::{openpty, set_raw, PtyMaster, PtyPair};
+#[cfg(feature = "nusb")]
+pub use usb::UsbPort;
+
 impl<W> Write for W
 where
     W: std::io::Write,
@@ -8,6 +30,10 @@ where
     fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
         self.write_all(buf).or(Err(Error::Write))
     }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        std::io::Write::flush(self).or(Err(Error::Write))
+    }
 }
 
 impl<R> Read for R
@@ -24,6 +50,10 @@ where
             .map(|()| buf[0])
             .or(Err(Error::Read))
     }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        std::io::Read::read_exact(self, buf).or(Err(Error::Read))
+    }
 }
 
 impl<S> Seek for S
@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Blocking adapter for the `futures` I/O traits
+
+use core::{
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+use futures_lite::{
+    future::block_on, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt,
+};
+use std::{
+    io::{self, SeekFrom},
+    sync::Arc,
+    task::Wake,
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+/// Adapter, which drives an `AsyncRead`, `AsyncWrite` or `AsyncSeek` of the
+/// `futures` crate to completion, and thus allows to use e.g. a serial port or
+/// a file of `smol` or `async-std` as the port or the file of `zmodem2::send`
+/// and `zmodem2::receive`. As the calls block, the transfer should be run on a
+/// thread of its own, e.g. with `smol::unblock` or
+/// `async_std::task::spawn_blocking`, while the runtime drives the I/O.
+pub struct Blocking<T> {
+    inner: T,
+    timeout: Duration,
+}
+
+impl<T> Blocking<T> {
+    /// Creates a new instance. A read, which has not completed within
+    /// `timeout`, returns no data, which is a timeout for `zmodem2::send`
+    /// and `zmodem2::receive`.
+    pub fn new(inner: T, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+
+    /// Returns a reference to the wrapped I/O object
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the wrapped I/O object
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> io::Read for Blocking<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        block_on_timeout(self.inner.read(buf), self.timeout).unwrap_or(Ok(0))
+    }
+}
+
+impl<T> io::Write for Blocking<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        block_on(self.inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        block_on(self.inner.flush())
+    }
+}
+
+impl<T> io::Seek for Blocking<T>
+where
+    T: AsyncSeek + Unpin,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        block_on(self.inner.seek(pos))
+    }
+}
+
+/// Waker, which unparks the blocked thread
+struct Unparker(Thread);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Drives `future` to completion on the current thread, and returns `None`,
+/// when it has not completed within `timeout`. The future is dropped on
+/// timeout.
+fn block_on_timeout<F>(future: F, timeout: Duration) -> Option<F::Output>
+where
+    F: Future,
+{
+    let deadline = Instant::now() + timeout;
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        thread::park_timeout(deadline - now);
+    }
+}
@@ -0,0 +1,255 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! File system helpers
+
+use crate::{receive, send, Error, FileInfo, Read, Stage, State, Write};
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Suffix of the partially received files
+const PART_SUFFIX: &str = ".part";
+
+/// File being received
+struct PartFile {
+    file: File,
+    part: PathBuf,
+    path: PathBuf,
+}
+
+impl PartFile {
+    fn create(dir: &Path, name: &str) -> Result<Self, Error> {
+        let name = NameRules::default().sanitize(name)?;
+        let path = dir.join(name);
+        let mut part = path.clone().into_os_string();
+        part.push(PART_SUFFIX);
+        let part = PathBuf::from(part);
+        let file = File::create(&part).or(Err(Error::Write))?;
+        Ok(Self { file, part, path })
+    }
+
+    /// Moves the file to its final name, and removes it, when that fails
+    fn commit(self) -> Result<(), Error> {
+        let Self { file, part, path } = self;
+        let synced = file.sync_all();
+        drop(file);
+        let result = synced.and_then(|()| fs::rename(&part, &path));
+        if result.is_err() {
+            fs::remove_file(&part).unwrap_or_default();
+        }
+        result.or(Err(Error::Write))
+    }
+
+    /// Removes the partially received file
+    fn discard(self) {
+        drop(self.file);
+        fs::remove_file(&self.part).unwrap_or_default();
+    }
+}
+
+/// Receives a batch of files to the directory `dir`. Each file is written
+/// first to a temporary file with `.part` suffix, which is atomically renamed
+/// to the final name after the file has been successfully received, and
+/// removed on failure. A `.part` file left over by an interrupted earlier
+/// run is overwritten, as the transfers are not resumed from it.
+///
+/// # Errors
+///
+/// * `Err(Error::Read)` when the read I/O fails with the serial port
+/// * `Err(Error::Write)` when the write I/O fails with the serial port or file
+/// * `Err(Error::Data)` when corrupted data has been detected
+pub fn receive_dir<P>(port: &mut P, state: &mut State<'_>, dir: &Path) -> Result<(), Error>
+where
+    P: Read + Write,
+{
+    let mut file: Option<PartFile> = None;
+    while state.stage() != Stage::Done {
+        let result = match file.as_mut() {
+            Some(file) => receive(port, &mut file.file, state),
+            None => receive(port, &mut io::sink(), state),
+        };
+        if let Err(err) = result {
+            if let Some(file) = file.take() {
+                file.discard();
+            }
+            return Err(err);
+        }
+        match state.stage() {
+            Stage::Ready if file.is_none() => {
+                file = Some(PartFile::create(dir, state.file_name())?);
+            }
+            Stage::Waiting => {
+                if let Some(file) = file.take() {
+                    file.commit()?;
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Some(file) = file.take() {
+        file.discard();
+        return Err(Error::Data);
+    }
+    Ok(())
+}
+
+/// Sends the regular files in the directory `dir` as a batch in the
+/// alphabetical order. When `pattern` is given, only the files with a name
+/// matching it are sent, where `*` matches any sequence of characters, and `?`
+/// matches any single character. The `progress` callback is invoked after
+/// each step with the information of the current file, and the offset
+/// acknowledged by the receiver. Nothing is sent when no files are found.
+///
+/// # Errors
+///
+/// * `Err(Error::Read)` when the read I/O fails with the serial port or file
+/// * `Err(Error::Write)` when the write I/O fails with the serial port
+/// * `Err(Error::Data)` when corrupted data has been detected, or a file is
+///   too large
+pub fn send_dir<P>(
+    port: &mut P,
+    dir: &Path,
+    pattern: Option<&str>,
+    progress: &mut dyn FnMut(&FileInfo, u32),
+) -> Result<(), Error>
+where
+    P: Read + Write,
+{
+    let mut names = vec![];
+    for entry in fs::read_dir(dir).or(Err(Error::Read))? {
+        let path = entry.or(Err(Error::Read))?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if pattern.map_or(true, |pattern| glob_match(pattern, name)) && path.is_file() {
+            names.push(name.to_owned());
+        }
+    }
+    names.sort();
+    let mut names = names.into_iter();
+    let Some(name) = names.next() else {
+        return Ok(());
+    };
+    let (mut file, size) = open_file(&dir.join(&name))?;
+    let mut state = State::new_file(&name, size)?;
+    let mut next = None;
+    loop {
+        if next.is_none() {
+            if let Some(name) = names.next() {
+                let (file, size) = open_file(&dir.join(&name))?;
+                state.set_next_file(&name, size)?;
+                next = Some(file);
+            }
+        }
+        send(port, &mut file, &mut state)?;
+        if next.is_some() && !state.has_next_file() {
+            file = next.take().ok_or(Error::Read)?;
+        }
+        progress(state.file_info(), state.count());
+        if state.stage() == Stage::Done {
+            return Ok(());
+        }
+    }
+}
+
+/// Opens a file for sending, and returns it along with its size
+fn open_file(path: &Path) -> Result<(File, u32), Error> {
+    let file = File::open(path).or(Err(Error::Read))?;
+    let size = file.metadata().or(Err(Error::Read))?.len();
+    Ok((file, u32::try_from(size).or(Err(Error::Data))?))
+}
+
+/// Matches a file name against a pattern with `*` and `?` wildcards
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Device names reserved by Windows regardless of the extension
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Rules for the names of the files created by `zmodem2::receive_dir` from
+/// the names sent by the peer. The default are the rules of the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NameRules {
+    /// Strips the directory components separated by `/` or `\`
+    Posix,
+    /// Additionally replaces the characters not allowed by Windows with `_`,
+    /// strips the trailing dots and spaces, and prefixes the reserved device
+    /// names, such as `CON` and `COM1`, with `_`
+    Windows,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        if cfg!(windows) {
+            Self::Windows
+        } else {
+            Self::Posix
+        }
+    }
+}
+
+impl NameRules {
+    /// Maps a file name sent by the peer to a name safe to create in a
+    /// directory
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when no file name is left
+    pub fn sanitize(&self, name: &str) -> Result<String, Error> {
+        let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+        let mut out: String = match self {
+            Self::Posix => name.into(),
+            Self::Windows => name
+                .chars()
+                .map(|c| match c {
+                    '<' | '>' | ':' | '"' | '|' | '?' | '*' | '\0'..='\x1f' => '_',
+                    c => c,
+                })
+                .collect::<String>()
+                .trim_end_matches(['.', ' '])
+                .into(),
+        };
+        if matches!(out.as_str(), "" | "." | "..") {
+            return Err(Error::Data);
+        }
+        if *self == Self::Windows {
+            let stem = out.split('.').next().unwrap_or_default().trim_end();
+            if RESERVED
+                .iter()
+                .any(|device| device.eq_ignore_ascii_case(stem))
+            {
+                out.insert(0, '_');
+            }
+        }
+        Ok(out)
+    }
+}
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Subpackets escaped ahead on a worker thread
+
+use crate::{
+    read_full, write_slice_escaped, Encoding, Error, EscapeSet, EscapedSource, Read, Seek, Source,
+    CRC16, CRC32,
+};
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+/// Request to stream subpackets of `len` bytes from `offset`, escaped with
+/// `escape`, and protected with the CRC of `encoding`
+#[derive(Clone, Copy, PartialEq)]
+struct Request {
+    generation: u32,
+    offset: u32,
+    len: usize,
+    encoding: Encoding,
+    escape: EscapeSet,
+}
+
+/// Subpacket escaped by the worker
+struct Prepared {
+    generation: u32,
+    result: Result<(u32, Vec<u8>, u32), Error>,
+}
+
+/// File source, which reads, checksums and escapes the following subpackets
+/// on a worker thread, while the current one is being written to the port.
+/// At most `depth` subpackets are prepared ahead. The worker streams the file
+/// sequentially, and is restarted when the receiver requests another position
+/// with `ZRPOS`. The file is sent with `zmodem2::send_source`, and the
+/// subpackets encoded by a codec are read directly from the position of the
+/// source, which is independent of the worker.
+pub struct Pipelined<F>
+where
+    F: Read + Seek + Send + 'static,
+{
+    file: Arc<Mutex<F>>,
+    requests: Option<Sender<Request>>,
+    prepared: Option<Receiver<Prepared>>,
+    worker: Option<JoinHandle<()>>,
+    generation: u32,
+    /// The request, which the next subpacket prepared by the worker answers
+    next: Option<Request>,
+    current: Vec<u8>,
+    /// Position of the direct reads
+    offset: u32,
+}
+
+impl<F> Pipelined<F>
+where
+    F: Read + Seek + Send + 'static,
+{
+    /// Creates a new instance, and starts the worker thread
+    pub fn new(file: F, depth: usize) -> Self {
+        let file = Arc::new(Mutex::new(file));
+        let (requests, request_rx) = mpsc::channel();
+        let (prepared_tx, prepared) = mpsc::sync_channel(depth);
+        let worker_file = Arc::clone(&file);
+        let worker = thread::spawn(move || run(&worker_file, &request_rx, &prepared_tx));
+        Self {
+            file,
+            requests: Some(requests),
+            prepared: Some(prepared),
+            worker: Some(worker),
+            generation: 0,
+            next: None,
+            current: Vec::new(),
+            offset: 0,
+        }
+    }
+
+    /// Locks the file for direct access
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, F>, Error> {
+        self.file.lock().or(Err(Error::Read))
+    }
+}
+
+impl<F> Drop for Pipelined<F>
+where
+    F: Read + Seek + Send + 'static,
+{
+    fn drop(&mut self) {
+        // Disconnecting both channels stops the worker:
+        self.requests.take();
+        self.prepared.take();
+        if let Some(worker) = self.worker.take() {
+            worker.join().unwrap_or_default();
+        }
+    }
+}
+
+impl<F> Read for Pipelined<F>
+where
+    F: Read + Seek + Send + 'static,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, Error> {
+        let count = {
+            let mut file = self.lock()?;
+            file.seek(self.offset)?;
+            file.read(buf)?
+        };
+        self.offset = self.offset.checked_add(count).ok_or(Error::Data)?;
+        Ok(count)
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let byte = {
+            let mut file = self.lock()?;
+            file.seek(self.offset)?;
+            file.read_byte()?
+        };
+        self.offset = self.offset.checked_add(1).ok_or(Error::Data)?;
+        Ok(byte)
+    }
+}
+
+impl<F> Seek for Pipelined<F>
+where
+    F: Read + Seek + Send + 'static,
+{
+    fn seek(&mut self, offset: u32) -> Result<(), Error> {
+        self.lock()?.seek(offset)?;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+impl<F> Source for Pipelined<F>
+where
+    F: Read + Seek + Send + 'static,
+{
+    fn escaped(&mut self) -> Option<&mut dyn EscapedSource> {
+        Some(self)
+    }
+}
+
+impl<F> EscapedSource for Pipelined<F>
+where
+    F: Read + Seek + Send + 'static,
+{
+    fn next_escaped(
+        &mut self,
+        offset: u32,
+        len: usize,
+        encoding: Encoding,
+        escape: &EscapeSet,
+    ) -> Result<(u32, &[u8], u32), Error> {
+        let requests = self.requests.as_ref().ok_or(Error::Read)?;
+        let prepared = self.prepared.as_ref().ok_or(Error::Read)?;
+        let mut request = Request {
+            generation: self.generation,
+            offset,
+            len,
+            encoding,
+            escape: *escape,
+        };
+        // The subpackets prepared for the previous request are discarded:
+        if self.next != Some(request) {
+            self.generation = self.generation.wrapping_add(1);
+            request.generation = self.generation;
+            requests.send(request).or(Err(Error::Read))?;
+        }
+        self.next = None;
+        let (count, escaped, crc) = loop {
+            let subpacket = prepared.recv().or(Err(Error::Read))?;
+            if subpacket.generation == self.generation {
+                break subpacket.result?;
+            }
+        };
+        self.next = match count {
+            0 => None,
+            _ => Some(Request {
+                offset: offset.checked_add(count).ok_or(Error::Data)?,
+                ..request
+            }),
+        };
+        self.current = escaped;
+        Ok((count, &self.current, crc))
+    }
+}
+
+/// Streams the subpackets requested by `requests` from `file` to `prepared`,
+/// until either of the channels is disconnected
+fn run<F>(file: &Mutex<F>, requests: &Receiver<Request>, prepared: &SyncSender<Prepared>)
+where
+    F: Read + Seek,
+{
+    let mut pending = None;
+    loop {
+        let mut request = match pending.take() {
+            Some(request) => request,
+            None => match requests.recv() {
+                Ok(request) => request,
+                Err(_) => return,
+            },
+        };
+        loop {
+            // A new request preempts the current stream:
+            match requests.try_recv() {
+                Ok(next) => {
+                    pending = Some(next);
+                    break;
+                }
+                Err(TryRecvError::Disconnected) => return,
+                Err(TryRecvError::Empty) => (),
+            }
+            let result = prepare(file, &request);
+            let count = result.as_ref().map_or(0, |(count, _, _)| *count);
+            let subpacket = Prepared {
+                generation: request.generation,
+                result,
+            };
+            if prepared.send(subpacket).is_err() {
+                return;
+            }
+            // Wait for a new request at the end of file, or after an error:
+            match request.offset.checked_add(count) {
+                Some(offset) if count > 0 => request.offset = offset,
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Reads, checksums and escapes the subpacket at the beginning of `request`
+fn prepare<F>(file: &Mutex<F>, request: &Request) -> Result<(u32, Vec<u8>, u32), Error>
+where
+    F: Read + Seek,
+{
+    let mut data = vec![0; request.len];
+    let count = {
+        let mut file = file.lock().or(Err(Error::Read))?;
+        file.seek(request.offset)?;
+        read_full(&mut *file, &mut data)?
+    };
+    data.truncate(count as usize);
+    let mut escaped = Vec::with_capacity(data.len() * 2);
+    write_slice_escaped(&mut escaped, &data, &request.escape)?;
+    let crc = match request.encoding {
+        Encoding::ZBIN32 => CRC32.checksum(&data),
+        Encoding::ZBIN | Encoding::ZHEX => u32::from(CRC16.checksum(&data)),
+    };
+    Ok((count, escaped, crc))
+}
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Buffered serial port
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+/// Serial port, which buffers the reads and writes of the underlying handles,
+/// such as a raw `File` of a TTY, instead of issuing a system call per byte.
+/// The buffered output is flushed before blocking for more input, and at the
+/// end of each call to `zmodem2::send` or `zmodem2::receive`:
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// let tty = std::fs::File::options().read(true).write(true).open("/dev/ttyUSB0")?;
+/// let mut port = zmodem2::BufferedPort::new(tty.try_clone()?, tty);
+/// # Ok(())
+/// # }
+/// ```
+pub struct BufferedPort<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    reader: BufReader<R>,
+    writer: BufWriter<W>,
+}
+
+impl<R, W> BufferedPort<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    /// Creates a new instance with the default buffer capacity
+    pub fn new(reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        }
+    }
+
+    /// Creates a new instance with buffers of `capacity` bytes
+    pub fn with_capacity(capacity: usize, reader: R, writer: W) -> Self {
+        Self {
+            reader: BufReader::with_capacity(capacity, reader),
+            writer: BufWriter::with_capacity(capacity, writer),
+        }
+    }
+
+    /// Returns references to the underlying reader and writer
+    pub fn get_ref(&self) -> (&R, &W) {
+        (self.reader.get_ref(), self.writer.get_ref())
+    }
+}
+
+impl<R, W> Read for BufferedPort<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The peer cannot answer the output, which has not been sent:
+        if self.reader.buffer().is_empty() {
+            self.writer.flush()?;
+        }
+        self.reader.read(buf)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if self.reader.buffer().len() < buf.len() {
+            self.writer.flush()?;
+        }
+        self.reader.read_exact(buf)
+    }
+}
+
+impl<R, W> BufRead for BufferedPort<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.reader.buffer().is_empty() {
+            self.writer.flush()?;
+        }
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+    }
+}
+
+impl<R, W> Write for BufferedPort<R, W>
+where
+    R: Read,
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Tar archive sink for batch receives
+
+use crate::{Error, FileInfo, Sink, Write};
+
+/// Size of a tar header and data block
+const BLOCK_SIZE: usize = 512;
+
+/// Default mode for the files, which were sent without one
+const DEFAULT_MODE: u32 = 0o644;
+
+/// Sink, which writes each received file of a batch as an entry into a ustar
+/// archive. An entry is started by `zmodem2::receive_sink` when the receiver
+/// has accepted `ZFILE`, and it is closed when the declared number of bytes
+/// has been written:
+///
+/// ```no_run
+/// # fn main() -> Result<(), zmodem2::Error> {
+/// # let mut port = std::io::Cursor::new(vec![]);
+/// let mut sink = zmodem2::TarSink::new(std::fs::File::create("batch.tar").unwrap());
+/// let mut state = zmodem2::State::new();
+/// while state.stage() != zmodem2::Stage::Done {
+///     zmodem2::receive_sink(&mut port, &mut sink, &mut state)?;
+/// }
+/// sink.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TarSink<W> {
+    inner: W,
+    pending: Option<FileInfo>,
+    size: u32,
+    count: u32,
+}
+
+impl<W> TarSink<W>
+where
+    W: std::io::Write,
+{
+    /// Creates a new instance writing the archive to `inner`
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pending: None,
+            size: 0,
+            count: 0,
+        }
+    }
+
+    /// Writes the end-of-archive marker, and returns the inner writer
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Write)` when the write I/O fails
+    /// * `Err(Error::Data)` when the last entry is incomplete
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.write_header()?;
+        if self.count != self.size {
+            return Err(Error::Data);
+        }
+        self.inner
+            .write_all(&[0; 2 * BLOCK_SIZE])
+            .or(Err(Error::Write))?;
+        std::io::Write::flush(&mut self.inner).or(Err(Error::Write))?;
+        Ok(self.inner)
+    }
+
+    /// Writes the header of the pending entry, if any
+    fn write_header(&mut self) -> Result<(), Error> {
+        let Some(file) = self.pending.take() else {
+            return Ok(());
+        };
+        let mut header = [0u8; BLOCK_SIZE];
+        let path = sanitize(file.name());
+        let (prefix, name) = split_name(&path)?;
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let mode = match file.mode() & 0o7777 {
+            0 => DEFAULT_MODE,
+            mode => mode,
+        };
+        write_octal(&mut header[100..108], mode);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], file.size());
+        write_octal(&mut header[136..148], file.mtime());
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header[148..156].fill(b' ');
+        let checksum = header.iter().map(|b| u32::from(*b)).sum();
+        write_octal(&mut header[148..155], checksum);
+        self.inner.write_all(&header).or(Err(Error::Write))?;
+        self.size = file.size();
+        self.count = 0;
+        self.close()
+    }
+
+    /// Pads the entry to the block boundary, when all of its data has been
+    /// written
+    fn close(&mut self) -> Result<(), Error> {
+        if self.count != self.size {
+            return Ok(());
+        }
+        let tail = self.size as usize % BLOCK_SIZE;
+        if tail != 0 {
+            self.inner
+                .write_all(&[0; BLOCK_SIZE][tail..])
+                .or(Err(Error::Write))?;
+        }
+        Ok(())
+    }
+}
+
+impl<W> Write for TarSink<W>
+where
+    W: std::io::Write,
+{
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.write_header()?;
+        let len = u32::try_from(buf.len()).or(Err(Error::Data))?;
+        if len > self.size - self.count {
+            return Err(Error::Data);
+        }
+        self.inner.write_all(buf).or(Err(Error::Write))?;
+        self.count += len;
+        self.close()
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.write_header()?;
+        std::io::Write::flush(&mut self.inner).or(Err(Error::Write))
+    }
+}
+
+impl<W> Sink for TarSink<W>
+where
+    W: std::io::Write,
+{
+    /// Starts a new entry. The header is written along with the first data,
+    /// and thus beginning again before that replaces the pending entry.
+    ///
+    /// # Errors
+    ///
+    /// * `Err(Error::Data)` when the previous entry is incomplete
+    fn begin(&mut self, file: &FileInfo) -> Result<(), Error> {
+        if self.count != self.size {
+            return Err(Error::Data);
+        }
+        self.pending = Some(file.clone());
+        Ok(())
+    }
+}
+
+/// Maps a path sent by the peer to a relative path, which cannot escape the
+/// directory the archive is extracted to. The components are separated by `/`
+/// or `\`, and the empty, `.` and `..` components are dropped.
+fn sanitize(path: &str) -> String {
+    path.split(['/', '\\'])
+        .filter(|component| !matches!(*component, "" | "." | ".."))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Splits a path into the ustar prefix and name fields
+fn split_name(path: &str) -> Result<(&str, &str), Error> {
+    if path.is_empty() {
+        return Err(Error::Data);
+    }
+    if path.len() <= 100 {
+        return Ok(("", path));
+    }
+    path.match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && !name.is_empty() && name.len() <= 100)
+        .ok_or(Error::Data)
+}
+
+/// Writes a NUL-terminated octal number, filling the field with leading zeros
+fn write_octal(field: &mut [u8], value: u32) {
+    let (digits, nul) = field.split_at_mut(field.len() - 1);
+    let mut value = value;
+    for digit in digits.iter_mut().rev() {
+        // The remainder is always smaller than eight:
+        #[allow(clippy::cast_possible_truncation)]
+        let remainder = (value % 8) as u8;
+        *digit = b'0' + remainder;
+        value /= 8;
+    }
+    nul[0] = 0;
+}
@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Unix domain socket and pseudoterminal ports
+
+use super::BufferedPort;
+#[cfg(feature = "pty")]
+use std::{
+    fs::File,
+    io::Read,
+    os::unix::io::{AsRawFd, FromRawFd},
+};
+use std::{io, os::unix::net::UnixStream, path::Path, time::Duration};
+
+impl BufferedPort<UnixStream, UnixStream> {
+    /// Creates a port for a connected Unix domain socket. A read times out
+    /// after `timeout` without data, which `zmodem2::send` and
+    /// `zmodem2::receive` handle as a timeout of the peer.
+    ///
+    /// # Errors
+    ///
+    /// * The error of setting the timeout or cloning the socket
+    pub fn from_unix_stream(stream: UnixStream, timeout: Duration) -> io::Result<Self> {
+        stream.set_read_timeout(Some(timeout))?;
+        Ok(Self::new(stream.try_clone()?, stream))
+    }
+
+    /// Connects to the Unix domain socket at `path`, and creates a port for it
+    /// as `BufferedPort::from_unix_stream`
+    ///
+    /// # Errors
+    ///
+    /// * The error of connecting, setting the timeout or cloning the socket
+    pub fn connect_unix<P>(path: P, timeout: Duration) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::from_unix_stream(UnixStream::connect(path)?, timeout)
+    }
+}
+
+/// Sets the TTY to the raw mode required by ZMODEM, in which a read times out
+/// after `timeout` without data, rounded to the tenths of a second up to 25.5
+/// seconds. The pitfalls corrupting transfers in the cooked mode are
+/// disabled: the line editing and the signal characters, the translation of
+/// CR and NL, the stripping of the 8th bit, and the XON/XOFF flow control,
+/// which would consume the escaped control characters.
+///
+/// # Errors
+///
+/// * The error of `tcgetattr` or `tcsetattr`, e.g. when `tty` is not a TTY
+#[cfg(feature = "pty")]
+pub fn set_raw<T>(tty: &T, timeout: Duration) -> io::Result<()>
+where
+    T: AsRawFd,
+{
+    let fd = tty.as_raw_fd();
+    // SAFETY: `termios` is plain data, which `tcgetattr` initializes.
+    let mut termios = unsafe { core::mem::zeroed::<libc::termios>() };
+    // SAFETY: `fd` is open for the lifetime of `tty`, and `termios` is valid.
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `termios` has been initialized by `tcgetattr`.
+    unsafe { libc::cfmakeraw(&mut termios) };
+    termios.c_iflag &= !(libc::IXON | libc::IXOFF | libc::IXANY | libc::ISTRIP);
+    termios.c_cflag |= libc::CREAD | libc::CLOCAL;
+    let tenths = (timeout.as_millis() / 100).clamp(1, 255);
+    termios.c_cc[libc::VMIN] = 0;
+    termios.c_cc[libc::VTIME] = u8::try_from(tenths).unwrap_or(u8::MAX);
+    // SAFETY: `fd` is open for the lifetime of `tty`, and `termios` is valid.
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Master side of a pseudoterminal, whose reads time out similarly to the
+/// slave side in the raw mode. A terminal emulator or a test talks through
+/// the master to the program using the slave side.
+#[cfg(feature = "pty")]
+pub struct PtyMaster {
+    file: File,
+    timeout: Duration,
+}
+
+#[cfg(feature = "pty")]
+impl Read for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = libc::c_int::try_from(self.timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+        // SAFETY: `fds` is a valid array of one element.
+        match unsafe { libc::poll(&mut fds, 1, ms) } {
+            -1 => Err(io::Error::last_os_error()),
+            // No data within the timeout, as with `VTIME` on the slave:
+            0 => Ok(0),
+            _ => self.file.read(buf),
+        }
+    }
+}
+
+/// Pseudoterminal pair as ports
+#[cfg(feature = "pty")]
+pub type PtyPair = (BufferedPort<PtyMaster, File>, BufferedPort<File, File>);
+
+/// Opens a pseudoterminal pair with `openpty`, sets the slave side to the raw
+/// mode with `set_raw`, and returns the ports for the master and the slave
+/// sides. A read from either side times out after `timeout` without data.
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// let timeout = std::time::Duration::from_secs(1);
+/// let (mut master, mut slave) = zmodem2::openpty(timeout)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// * The error of `openpty`, `set_raw` or cloning the file descriptors
+#[cfg(feature = "pty")]
+pub fn openpty(timeout: Duration) -> io::Result<PtyPair> {
+    let mut master = -1;
+    let mut slave = -1;
+    // SAFETY: The descriptors are valid pointers, and the optional arguments
+    // are null.
+    let result = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+            core::ptr::null_mut(),
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `openpty` succeeded, and the descriptors are owned only here.
+    let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+    set_raw(&slave, timeout)?;
+    let reader = PtyMaster {
+        file: master.try_clone()?,
+        timeout,
+    };
+    Ok((
+        BufferedPort::new(reader, master),
+        BufferedPort::new(slave.try_clone()?, slave),
+    ))
+}
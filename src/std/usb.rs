@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! USB CDC-ACM port
+
+use nusb::{
+    descriptors::TransferType,
+    io::{EndpointRead, EndpointWrite},
+    transfer::{Bulk, Direction, In, Out},
+    Endpoint, Interface,
+};
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+/// Size of a single bulk transfer, which is a multiple of the maximum packet
+/// size of both full-speed and high-speed endpoints
+const TRANSFER_SIZE: usize = 4096;
+
+/// Number of transfers kept in flight for each direction
+const NUM_TRANSFERS: usize = 4;
+
+/// Port, which talks to the bulk endpoints of a USB CDC-ACM data interface
+/// directly, bypassing the serial driver of the operating system.
+///
+/// The differences to a TTY device are handled as follows:
+///
+/// * The output is buffered into bulk transfers, and a flush terminates the
+///   transfer with a short or zero-length packet, as a device will not pass a
+///   transfer ending at a packet boundary to its application before that.
+/// * A read, which times out, returns zero bytes similarly to a TTY with
+///   `VTIME` set, which `zmodem2::send` and `zmodem2::receive` handle as a
+///   timeout. The pending transfer is not cancelled, and its data is returned
+///   by the following read.
+///
+/// ```no_run
+/// use nusb::MaybeFuture;
+/// # fn main() -> std::io::Result<()> {
+/// let info = nusb::list_devices()
+///     .wait()?
+///     .find(|info| info.vendor_id() == 0x1209)
+///     .ok_or(std::io::ErrorKind::NotFound)?;
+/// let device = info.open().wait()?;
+/// let interface = device.detach_and_claim_interface(1).wait()?;
+/// let timeout = std::time::Duration::from_secs(1);
+/// let mut port = zmodem2::UsbPort::new(&interface, timeout)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct UsbPort {
+    reader: EndpointRead<Bulk>,
+    writer: EndpointWrite<Bulk>,
+}
+
+impl UsbPort {
+    /// Creates a new instance for the bulk endpoints of `interface`, which
+    /// must be the claimed data interface of a CDC-ACM function
+    ///
+    /// # Errors
+    ///
+    /// * `ErrorKind::NotFound` when the interface lacks a bulk endpoint
+    /// * The error of opening the endpoints
+    pub fn new(interface: &Interface, timeout: Duration) -> io::Result<Self> {
+        let descriptor = interface.descriptor().ok_or(io::ErrorKind::NotFound)?;
+        let address = |direction| {
+            descriptor
+                .endpoints()
+                .find(|ep| ep.transfer_type() == TransferType::Bulk && ep.direction() == direction)
+                .map(|ep| ep.address())
+                .ok_or(io::ErrorKind::NotFound)
+        };
+        let input = interface.endpoint::<Bulk, In>(address(Direction::In)?)?;
+        let output = interface.endpoint::<Bulk, Out>(address(Direction::Out)?)?;
+        Ok(Self::from_endpoints(input, output, timeout))
+    }
+
+    /// Creates a new instance for already opened bulk endpoints, where
+    /// `timeout` applies to both reads and writes
+    #[must_use]
+    pub fn from_endpoints(
+        input: Endpoint<Bulk, In>,
+        output: Endpoint<Bulk, Out>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            reader: input
+                .reader(TRANSFER_SIZE)
+                .with_num_transfers(NUM_TRANSFERS)
+                .with_read_timeout(timeout),
+            writer: output
+                .writer(TRANSFER_SIZE)
+                .with_num_transfers(NUM_TRANSFERS)
+                .with_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for UsbPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reader.read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Write for UsbPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush_end()
+    }
+}
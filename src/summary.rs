@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Transfer summary
+
+use heapless::String;
+
+/// Status of a file in the session
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum FileStatus {
+    /// The file has not been completely transferred
+    #[default]
+    Incomplete,
+    /// The file has been transferred
+    Transferred,
+    /// The file was skipped by the receiver
+    Skipped,
+}
+
+/// Summary of a single file in the session
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileSummary {
+    pub(crate) name: String<256>,
+    pub(crate) size: u32,
+    pub(crate) count: u32,
+    pub(crate) status: FileStatus,
+}
+
+impl FileSummary {
+    /// Returns the file name
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the file size declared in `ZFILE`
+    #[must_use]
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Returns the number of bytes transferred
+    #[must_use]
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// Returns the status of the file
+    #[must_use]
+    pub fn status(&self) -> FileStatus {
+        self.status
+    }
+}
+
+/// Condition of the link, which a step tolerated without failing
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkEvent {
+    /// The port timed out before a header arrived
+    Timeout,
+    /// No header was found in the bytes read from the port
+    Garbage,
+    /// A header was rejected with `ZNAK`
+    BadHeader,
+}
+
+/// Progress of the batch of files in the session, when the sender has
+/// declared the files and bytes remaining in `ZFILE`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BatchProgress {
+    pub(crate) file: u32,
+    pub(crate) files: Option<u32>,
+    pub(crate) bytes: u64,
+    pub(crate) total: Option<u64>,
+}
+
+impl BatchProgress {
+    /// Returns the number of the current file starting from one
+    #[must_use]
+    pub fn file(&self) -> u32 {
+        self.file
+    }
+
+    /// Returns the number of files in the batch, when declared by the sender
+    #[must_use]
+    pub fn files(&self) -> Option<u32> {
+        self.files
+    }
+
+    /// Returns the number of bytes in the batch up to the offset of the
+    /// current file, including the skipped files and the parts of the files
+    /// not transferred
+    #[must_use]
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the number of bytes in the batch, when declared by the sender
+    #[must_use]
+    pub fn total_bytes(&self) -> Option<u64> {
+        self.total
+    }
+}
+
+/// Summary of the session
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransferSummary {
+    pub(crate) files_transferred: u32,
+    pub(crate) files_skipped: u32,
+    pub(crate) bytes: u64,
+    pub(crate) retries: u32,
+    pub(crate) timeouts: u32,
+    pub(crate) garbage: u32,
+    pub(crate) bad_headers: u32,
+    pub(crate) crc_width_mismatches: u32,
+    pub(crate) suppressed_repositions: u32,
+    pub(crate) subpacket_bytes: u64,
+    pub(crate) escapes: u64,
+    pub(crate) duration: Option<u32>,
+}
+
+impl TransferSummary {
+    /// Returns the number of files transferred
+    #[must_use]
+    pub fn files_transferred(&self) -> u32 {
+        self.files_transferred
+    }
+
+    /// Returns the number of files skipped by the receiver
+    #[must_use]
+    pub fn files_skipped(&self) -> u32 {
+        self.files_skipped
+    }
+
+    /// Returns the number of file bytes transferred
+    #[must_use]
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the number of retransmissions requested with `ZNAK` or
+    /// `ZRPOS`, or caused by a `ZACK` disagreeing with the sent data or by
+    /// an unanswered `ZFILE`
+    #[must_use]
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Returns the number of steps, in which the port timed out before a
+    /// header arrived
+    #[must_use]
+    pub fn timeouts(&self) -> u32 {
+        self.timeouts
+    }
+
+    /// Returns the number of steps, in which no header was found in the bytes
+    /// read from the port
+    #[must_use]
+    pub fn garbage(&self) -> u32 {
+        self.garbage
+    }
+
+    /// Returns the number of headers rejected with `ZNAK`
+    #[must_use]
+    pub fn bad_headers(&self) -> u32 {
+        self.bad_headers
+    }
+
+    /// Returns the number of binary headers accepted with the CRC of the
+    /// other binary encoding. See `State::set_crc_width_tolerant`.
+    #[must_use]
+    pub fn crc_width_mismatches(&self) -> u32 {
+        self.crc_width_mismatches
+    }
+
+    /// Returns the number of `ZRPOS` not sent by the receiver, as they would
+    /// have repeated the previous one before the line drained
+    #[must_use]
+    pub fn suppressed_repositions(&self) -> u32 {
+        self.suppressed_repositions
+    }
+
+    /// Returns the number of bytes added by escaping the data subpackets sent
+    #[must_use]
+    pub fn escapes(&self) -> u64 {
+        self.escapes
+    }
+
+    /// Returns the bytes added by escaping the data subpackets sent as a
+    /// percentage of their bytes before escaping, i.e. 100 when escaping has
+    /// doubled the bytes on the wire. This is `None`, when no data has been
+    /// sent.
+    #[must_use]
+    pub fn escape_overhead(&self) -> Option<u64> {
+        (self.subpacket_bytes > 0).then(|| self.escapes * 100 / self.subpacket_bytes)
+    }
+
+    /// Returns the duration of the session in milliseconds, when a clock has
+    /// been set with `State::set_clock`
+    #[must_use]
+    pub fn duration(&self) -> Option<u32> {
+        self.duration
+    }
+}
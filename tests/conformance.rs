@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Table-driven conformance suite for the frame layouts, the escaping rules
+//! and the state transitions of the 1988 ZMODEM specification, and the
+//! deviations of lrzsz, which are accepted as errata.
+
+use std::collections::VecDeque;
+use std::io::Cursor;
+use zmodem2::proto::{XON, ZDLE, ZPAD};
+use zmodem2::{
+    receive, send, Conformance, DequePort, Encoding, Frame, Header, Stage, State, Zrinit,
+};
+
+/// `ZRINIT` written by the receiver in the default state, which carries the
+/// capabilities in `ZF0` instead of a count
+const ZRINIT: (Frame, u32) = (
+    Frame::ZRINIT,
+    u32::from_le_bytes([
+        0,
+        0,
+        0,
+        Zrinit::CANFDX.bits() | Zrinit::CANOVIO.bits() | Zrinit::CANFC32.bits(),
+    ]),
+);
+
+const fn hex(frame: Frame, count: u32) -> Header {
+    Header::new(Encoding::ZHEX, frame, &count.to_le_bytes())
+}
+
+fn encode(header: &Header) -> Vec<u8> {
+    let mut out = vec![];
+    header.write(&mut out).unwrap();
+    out
+}
+
+/// Returns the frame types and counts of the headers in `bytes`
+fn frames(mut bytes: &[u8]) -> Vec<(Frame, u32)> {
+    let mut frames = vec![];
+    while let Some(at) = bytes.windows(2).position(|w| w == [ZPAD, ZDLE]) {
+        bytes = &bytes[at + 2..];
+        if let Ok(header) = Header::read(&mut bytes) {
+            frames.push((header.frame(), header.count()));
+        }
+    }
+    frames
+}
+
+/// Runs a step for each of the slices of `steps` after queueing the slice to
+/// the port, and returns the headers written in the last step, and the state
+fn run(sender: bool, steps: &[Vec<u8>]) -> (Vec<(Frame, u32)>, State<'static>) {
+    let mut state = if sender {
+        State::new_file("foo", 3).unwrap()
+    } else {
+        State::new()
+    };
+    let mut rx = VecDeque::new();
+    let mut tx = VecDeque::new();
+    let mut sink = vec![];
+    for input in steps {
+        rx.extend(input);
+        tx.clear();
+        let mut port = DequePort::new(&mut rx, &mut tx);
+        let _ = if sender {
+            send(&mut port, &mut Cursor::new(b"foo"), &mut state)
+        } else {
+            receive(&mut port, &mut sink, &mut state)
+        };
+    }
+    let written: Vec<u8> = tx.into_iter().collect();
+    (frames(&written), state)
+}
+
+/// Header layouts, where the `ZHEX` vectors are the ones written by lrzsz
+#[rstest::rstest]
+#[case(hex(Frame::ZRQINIT, 0), b"**\x18B00000000000000\r\n\x11")]
+#[case(Header::new(Encoding::ZHEX, Frame::ZRINIT, &[0, 0, 0, 0x23]), b"**\x18B0100000023be50\r\n\x11")]
+#[case(hex(Frame::ZFIN, 0), b"**\x18B0800000000022d\r\n")]
+pub fn test_hex_layout(#[case] header: Header, #[case] expected: &[u8]) {
+    assert_eq!(encode(&header), expected);
+    assert!(Header::read(&mut &expected[3..]).unwrap() == header);
+}
+
+/// Binary headers begin with a single `ZPAD`, and end with CRC-16 in the
+/// big-endian or CRC-32 in the little-endian byte order
+#[rstest::rstest]
+#[case(Encoding::ZBIN, b'A', 2)]
+#[case(Encoding::ZBIN32, b'C', 4)]
+pub fn test_binary_layout(#[case] encoding: Encoding, #[case] id: u8, #[case] crc: usize) {
+    let header = Header::new(encoding, Frame::ZDATA, &[0x20; 4]);
+    let out = encode(&header);
+    assert_eq!(out[..5], [ZPAD, ZDLE, id, Frame::ZDATA as u8, 0x20]);
+    assert_eq!(out.len(), 3 + 5 + crc);
+    assert!(Header::read(&mut &out[2..]).unwrap() == header);
+}
+
+/// `ZDLE`, DLE, XON, XOFF, CR, DEL and their parity-marked versions are
+/// escaped, and the other bytes are written as they are
+#[rstest::rstest]
+#[case(ZDLE, &[ZDLE, 0x58])]
+#[case(0x10, &[ZDLE, 0x50])]
+#[case(0x90, &[ZDLE, 0xd0])]
+#[case(XON, &[ZDLE, 0x51])]
+#[case(0x91, &[ZDLE, 0xd1])]
+#[case(0x13, &[ZDLE, 0x53])]
+#[case(0x93, &[ZDLE, 0xd3])]
+#[case(0x0d, &[ZDLE, 0x4d])]
+#[case(0x8d, &[ZDLE, 0xcd])]
+#[case(0x7f, &[ZDLE, b'l'])]
+#[case(0xff, &[ZDLE, b'm'])]
+#[case(0x00, &[0x00])]
+#[case(b'A', b"A")]
+#[case(0x80, &[0x80])]
+pub fn test_escape(#[case] byte: u8, #[case] expected: &[u8]) {
+    let header = Header::new(Encoding::ZBIN, Frame::ZRPOS, &[byte, 0, 0, 0]);
+    let out = encode(&header);
+    assert_eq!(&out[4..4 + expected.len()], expected);
+    assert!(Header::read(&mut &out[2..]).unwrap() == header);
+}
+
+/// State transitions of the sender, which sends "foo"
+#[rstest::rstest]
+#[case::timeout(vec![vec![]], &[(Frame::ZRQINIT, 0)], Stage::Waiting)]
+#[case::zrinit(vec![encode(&hex(Frame::ZRINIT, 0))], &[(Frame::ZRQINIT, 0), (Frame::ZFILE, 0)], Stage::Ready)]
+#[case::znak(vec![encode(&hex(Frame::ZNAK, 0))], &[(Frame::ZRQINIT, 0), (Frame::ZRQINIT, 0)], Stage::Waiting)]
+#[case::zchallenge(vec![encode(&hex(Frame::ZCHALLENGE, 1234))], &[(Frame::ZRQINIT, 0), (Frame::ZACK, 1234)], Stage::Waiting)]
+#[case::zrpos(vec![encode(&hex(Frame::ZRINIT, 0)), encode(&hex(Frame::ZRPOS, 0))], &[(Frame::ZDATA, 0)], Stage::InProgress)]
+#[case::zrpos_resume(vec![encode(&hex(Frame::ZRINIT, 0)), encode(&hex(Frame::ZRPOS, 2))], &[(Frame::ZDATA, 2)], Stage::InProgress)]
+#[case::zack_eof(vec![encode(&hex(Frame::ZRINIT, 0)), encode(&hex(Frame::ZRPOS, 0)), encode(&hex(Frame::ZACK, 3))], &[(Frame::ZEOF, 3)], Stage::InProgress)]
+#[case::zskip(vec![encode(&hex(Frame::ZRINIT, 0)), encode(&hex(Frame::ZSKIP, 0))], &[(Frame::ZFIN, 0)], Stage::Ready)]
+#[case::zfin(vec![encode(&hex(Frame::ZRINIT, 0)), encode(&hex(Frame::ZFIN, 0))], &[], Stage::Done)]
+pub fn test_sender_transitions(
+    #[case] steps: Vec<Vec<u8>>,
+    #[case] expected: &[(Frame, u32)],
+    #[case] stage: Stage,
+) {
+    let (written, state) = run(true, &steps);
+    assert_eq!(written, expected);
+    assert_eq!(state.stage(), stage);
+}
+
+/// State transitions of the receiver
+#[rstest::rstest]
+#[case::timeout(vec![vec![]], &[ZRINIT], Stage::Waiting)]
+#[case::zrqinit(vec![encode(&hex(Frame::ZRQINIT, 0))], &[ZRINIT], Stage::Waiting)]
+#[case::zrqinit_repeated(vec![vec![], encode(&hex(Frame::ZRQINIT, 0))], &[ZRINIT], Stage::Waiting)]
+#[case::zdata(vec![encode(&hex(Frame::ZDATA, 0))], &[ZRINIT, ZRINIT], Stage::Waiting)]
+#[case::zeof(vec![encode(&hex(Frame::ZEOF, 0))], &[ZRINIT], Stage::Waiting)]
+#[case::zfin(vec![encode(&hex(Frame::ZFIN, 0))], &[ZRINIT, (Frame::ZFIN, 0)], Stage::Done)]
+pub fn test_receiver_transitions(
+    #[case] steps: Vec<Vec<u8>>,
+    #[case] expected: &[(Frame, u32)],
+    #[case] stage: Stage,
+) {
+    let (written, state) = run(false, &steps);
+    assert_eq!(written, expected);
+    assert_eq!(state.stage(), stage);
+}
+
+/// Deviations of lrzsz accepted by the lenient conformance, as opposed to a
+/// corrupted header, which is rejected in either mode
+#[rstest::rstest]
+#[case::parity(|b: &mut Vec<u8>| b[4..18].iter_mut().for_each(|c| *c |= 0x80), true, false)]
+#[case::xon(|b: &mut Vec<u8>| b.insert(10, XON), true, false)]
+#[case::single_zpad(|b: &mut Vec<u8>| { b.remove(0); }, true, false)]
+#[case::upper_case(|b: &mut Vec<u8>| b.make_ascii_uppercase(), true, true)]
+#[case::corrupted(|b: &mut Vec<u8>| b[6] = b'7', false, false)]
+pub fn test_errata(#[case] deviate: fn(&mut Vec<u8>), #[case] lenient: bool, #[case] strict: bool) {
+    let mut header = encode(&Header::new(Encoding::ZHEX, Frame::ZRQINIT, &[0xa5; 4]));
+    deviate(&mut header);
+    for (conformance, expected) in [
+        (Conformance::Lenient, lenient),
+        (Conformance::Strict, strict),
+    ] {
+        let mut state = State::new();
+        state.set_conformance(conformance);
+        let mut rx: VecDeque<u8> = header.iter().copied().collect();
+        let mut tx = VecDeque::new();
+        let _ = receive(
+            &mut DequePort::new(&mut rx, &mut tx),
+            &mut vec![],
+            &mut state,
+        );
+        assert_eq!(state.last_nak().is_none(), expected, "{conformance:?}");
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg_attr(not(host_has_rzsz), allow(dead_code, unused_imports))]
+
 extern crate zmodem2;
 
 use std::fs::{remove_file, File};